@@ -12,19 +12,55 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use criterion::{criterion_group, criterion_main, Criterion};
-use sequence_map;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 
 const ENTRIES: usize = 50000;
 
-fn run_one(lookup: &sequence_map::Map, bits: usize, entries: usize, c: &mut Criterion) {
-    c.bench_function(&format!("lookup bits={} entries={}", bits, entries), move |b| {
-        b.iter(|| {
-            for key in 0..entries {
-                lookup.get(key as u64).expect(&format!("entry exists: {}", key));
-            }
+// The number of entries in the maps used to compare layouts.  These need to be
+// large enough not to fit into the CPU caches for the layout to matter.
+const LAYOUT_ENTRIES: usize = 500000;
+
+// Returns `count` keys spread over the entire key space.
+fn random_keys(count: usize) -> Vec<u64> {
+    let mut key: u64 = 0x1234_5678;
+    (0..count)
+        .map(|_| {
+            // A xorshift generator.
+            key ^= key << 13;
+            key ^= key >> 7;
+            key ^= key << 17;
+            key
         })
-    });
+        .collect()
+}
+
+fn run_one(lookup: &sequence_map::Map, bits: usize, entries: usize, c: &mut Criterion) {
+    c.bench_function(
+        &format!("lookup bits={} entries={}", bits, entries),
+        move |b| {
+            b.iter(|| {
+                for key in 0..entries {
+                    lookup
+                        .get(key as u64)
+                        .unwrap_or_else(|| panic!("entry exists: {}", key));
+                }
+            })
+        },
+    );
+}
+
+fn run_many(lookup: &sequence_map::Map, bits: usize, entries: usize, c: &mut Criterion) {
+    let keys: Vec<u64> = (0..entries as u64).collect();
+    c.bench_function(
+        &format!("lookup many bits={} entries={}", bits, entries),
+        move |b| {
+            let mut values = vec![None; entries];
+            b.iter(|| {
+                lookup.get_many(&keys, &mut values);
+                assert!(values.iter().all(|value| value.is_some()));
+            })
+        },
+    );
 }
 
 fn run_bit_size(bits: usize, entries: usize, c: &mut Criterion) {
@@ -40,6 +76,169 @@ fn run_bit_size(bits: usize, entries: usize, c: &mut Criterion) {
     run_one(&lookup, bits, 10, c);
     run_one(&lookup, bits, 100, c);
     run_one(&lookup, bits, 1000, c);
+    run_many(&lookup, bits, 1000, c);
+}
+
+fn run_layout(layout: sequence_map::Layout, bits: usize, c: &mut Criterion) {
+    let keys = random_keys(LAYOUT_ENTRIES);
+    let mut builder = sequence_map::Builder::new(bits);
+    builder.set_layout(layout);
+    for key in &keys {
+        let string = format!("entry_{}", key);
+        builder.insert(*key, &string);
+    }
+    let bytes = builder.build();
+    let lookup = sequence_map::Map::new(&bytes);
+
+    // Look up keys in a different order than they were inserted in.
+    let keys: Vec<u64> = keys.iter().rev().step_by(97).take(1000).cloned().collect();
+    let many_keys = keys.clone();
+    let lookup = &lookup;
+    c.bench_function(
+        &format!("lookup layout={:?} bits={}", layout, bits),
+        move |b| {
+            b.iter(|| {
+                for key in &keys {
+                    lookup
+                        .get(*key)
+                        .unwrap_or_else(|| panic!("entry exists: {}", key));
+                }
+            })
+        },
+    );
+    // The same, with the lookups interleaved.
+    c.bench_function(
+        &format!("lookup many layout={:?} bits={}", layout, bits),
+        move |b| {
+            let mut values = vec![None; many_keys.len()];
+            b.iter(|| {
+                lookup.get_many(&many_keys, &mut values);
+                assert!(values.iter().all(|value| value.is_some()));
+            })
+        },
+    );
+}
+
+// Compares looking up keys in a trie with looking them up in a perfect hash
+// index, in a map which does not fit into the CPU caches.
+fn run_index_kind(index_kind: sequence_map::IndexKind, c: &mut Criterion) {
+    let keys = random_keys(LAYOUT_ENTRIES);
+    let mut builder = sequence_map::Builder::new(4);
+    builder.set_index_kind(index_kind);
+    for key in &keys {
+        let string = format!("entry_{}", key);
+        builder.insert(*key, &string);
+    }
+    let bytes = builder.build();
+    let lookup = sequence_map::Map::new(&bytes);
+
+    let keys: Vec<u64> = keys.iter().rev().step_by(97).take(1000).cloned().collect();
+    let lookup = &lookup;
+    c.bench_function(&format!("lookup index_kind={:?}", index_kind), move |b| {
+        b.iter(|| {
+            for key in &keys {
+                lookup
+                    .get(*key)
+                    .unwrap_or_else(|| panic!("entry exists: {}", key));
+            }
+        })
+    });
+}
+
+// Compares looking up keys in a trie with looking them up in a dense range,
+// for a map of consecutive keys.
+fn run_dense_range(dense_range: bool, c: &mut Criterion) {
+    let mut builder = sequence_map::Builder::new(4);
+    builder.set_dense_range(dense_range);
+    for key in 0..LAYOUT_ENTRIES {
+        let string = format!("entry_{}", key);
+        builder.insert(key as u64, &string);
+    }
+    let bytes = builder.build();
+    let lookup = sequence_map::Map::new(&bytes);
+
+    let keys: Vec<u64> = random_keys(1000)
+        .into_iter()
+        .map(|key| key % LAYOUT_ENTRIES as u64)
+        .collect();
+    let lookup = &lookup;
+    c.bench_function(&format!("lookup dense_range={}", dense_range), move |b| {
+        b.iter(|| {
+            for key in &keys {
+                lookup
+                    .get(*key)
+                    .unwrap_or_else(|| panic!("entry exists: {}", key));
+            }
+        })
+    });
+}
+
+fn run_compression(compression: sequence_map::Compression, c: &mut Criterion) {
+    let mut builder = sequence_map::Builder::new(8);
+    builder.set_compression(compression);
+    for key in 0..ENTRIES {
+        let string = format!("entry_{}", key);
+        builder.insert(key as u64, &string);
+    }
+    let bytes = builder.build();
+    let lookup = sequence_map::CompressedMap::new(&bytes);
+    let lookup = &lookup;
+
+    // Keys next to each other, which share compressed blocks, and keys far
+    // apart, which do not.
+    for (order, step) in &[("sequential", 1), ("strided", 997)] {
+        let keys: Vec<u64> = (0..ENTRIES as u64).step_by(*step).take(1000).collect();
+        c.bench_function(
+            &format!("lookup compression={:?} order={}", compression, order),
+            move |b| {
+                b.iter(|| {
+                    for key in &keys {
+                        lookup
+                            .get(*key)
+                            .unwrap_or_else(|| panic!("entry exists: {}", key));
+                    }
+                })
+            },
+        );
+    }
+}
+
+// Measures inserting `entries` keys into a builder and building the map.
+// Every `repeat`-th value is a repeat of an earlier one.
+fn run_build(entries: usize, repeat: usize, c: &mut Criterion) {
+    let keys = random_keys(entries);
+    let values: Vec<String> = (0..entries)
+        .map(|index| format!("entry_{}", index - index / repeat))
+        .collect();
+    let mut group = c.benchmark_group("build");
+    group.throughput(Throughput::Elements(entries as u64));
+    group.sample_size(10);
+    group.bench_function(
+        format!("build entries={} repeat={}", entries, repeat),
+        |b| {
+            b.iter(|| {
+                let mut builder = sequence_map::Builder::new(8);
+                for (key, value) in keys.iter().zip(&values) {
+                    builder.insert(*key, value);
+                }
+                builder.build()
+            })
+        },
+    );
+    // The same, spread over multiple threads.
+    #[cfg(feature = "rayon")]
+    group.bench_function(
+        format!("build parallel entries={} repeat={}", entries, repeat),
+        |b| {
+            let entries: Vec<(u64, &str)> = keys
+                .iter()
+                .zip(&values)
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect();
+            b.iter(|| sequence_map::Builder::new(8).build_parallel(&entries))
+        },
+    );
+    group.finish();
 }
 
 pub fn criterion_benchmark(c: &mut Criterion) {
@@ -47,6 +246,23 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     run_bit_size(4, ENTRIES, c);
     run_bit_size(8, ENTRIES, c);
     run_bit_size(16, ENTRIES, c);
+
+    run_layout(sequence_map::Layout::Insertion, 4, c);
+    run_layout(sequence_map::Layout::BreadthFirst, 4, c);
+    run_layout(sequence_map::Layout::VanEmdeBoas, 4, c);
+
+    run_index_kind(sequence_map::IndexKind::Trie, c);
+    run_index_kind(sequence_map::IndexKind::PerfectHash, c);
+
+    run_dense_range(false, c);
+    run_dense_range(true, c);
+
+    run_compression(sequence_map::Compression::None, c);
+    run_compression(sequence_map::Compression::Symbols, c);
+    run_compression(sequence_map::Compression::Blocks, c);
+
+    run_build(LAYOUT_ENTRIES, 2, c);
+    run_build(LAYOUT_ENTRIES, LAYOUT_ENTRIES, c);
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,40 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iterates over arbitrary buffers.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sequence_map::{CompressedMap, Map};
+
+fuzz_target!(|data: &[u8]| {
+    let bytes = data.to_vec();
+    let compressed = match CompressedMap::try_new(&bytes) {
+        Ok(map) => map,
+        Err(_) => return,
+    };
+    for (key, value) in compressed.iter() {
+        // Corrupt maps may hold keys in places where lookups do not find
+        // them, so only make sure that looking them up does not crash.
+        compressed.get(key);
+        compressed.keys_for(&value).count();
+    }
+    // Compressed values can not be borrowed.
+    if let Ok(map) = Map::try_new(&bytes) {
+        for (key, _) in map.iter() {
+            map.get(key);
+        }
+    }
+});
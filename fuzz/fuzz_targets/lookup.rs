@@ -0,0 +1,57 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Looks up keys in arbitrary buffers.  The input is a key of 8 bytes,
+//! followed by the buffer.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sequence_map::{CompressedMap, Compression, Error, Map};
+use std::convert::TryInto;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 8 {
+        return;
+    }
+    let key = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let bytes = data[8..].to_vec();
+    let compressed = match CompressedMap::try_new(&bytes) {
+        Ok(map) => map,
+        Err(_) => return,
+    };
+    let keys = [key, key ^ 1, key >> 8, !key, 0];
+    for key in &keys {
+        compressed.get(*key);
+    }
+    // Only maps whose values are not compressed can borrow them.
+    let map = match Map::try_new(&bytes) {
+        Ok(map) => map,
+        Err(err) => {
+            assert_eq!(Error::Compressed, err);
+            assert_ne!(Compression::None, compressed.compression());
+            return;
+        }
+    };
+    let mut values = [None; 5];
+    map.get_many(&keys, &mut values);
+    for (key, value) in keys.iter().zip(&values) {
+        assert_eq!(map.get(*key), *value);
+        assert_eq!(
+            map.get_cstr(*key).and_then(|cstr| cstr.to_str().ok()),
+            *value
+        );
+        assert_eq!(compressed.get(*key).as_deref(), *value);
+    }
+});
@@ -0,0 +1,139 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builds maps from arbitrary inserts and removes, and checks that the maps
+//! hold the same as a BTreeMap which the same changes are applied to.  Also
+//! checks that builders are only created from damaged copies of the maps if
+//! they build valid maps.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use sequence_map::{reference, Builder, CompressedMap, Compression, Layout, Map};
+use std::collections::BTreeMap;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(u64, String),
+    Remove(u64),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    schedule: Vec<u8>,
+    layout: u8,
+    compression: u8,
+    dense_range: bool,
+    ops: Vec<Op>,
+    // Whether to rebuild the map with Builder::from_bytes half way through.
+    rebuild: bool,
+    // The positions and bits of the bytes to flip in the final map.
+    damage: Vec<(u32, u8)>,
+}
+
+fn build(builder: Builder) -> (Vec<u8>, Builder) {
+    let bytes = builder.build();
+    let builder = Builder::try_from_bytes(bytes.clone()).expect("valid map");
+    (bytes, builder)
+}
+
+fn check(bytes: &[u8], expected: &BTreeMap<u64, String>, keys: &[u64]) {
+    let map = CompressedMap::new(bytes);
+    for key in keys {
+        let value = expected.get(key).map(|value| value.as_str());
+        assert_eq!(value, map.get(*key).as_deref(), "key: {}", key);
+        assert_eq!(
+            value,
+            reference::lookup(bytes, *key).as_deref(),
+            "key: {}",
+            key
+        );
+    }
+    let found: BTreeMap<u64, String> = map
+        .iter()
+        .map(|(key, value)| (key, value.into_owned()))
+        .collect();
+    assert_eq!(*expected, found);
+}
+
+fuzz_target!(|input: Input| {
+    let schedule: Vec<usize> = input
+        .schedule
+        .iter()
+        .take(4)
+        .map(|bits| 2 + *bits as usize % 15)
+        .collect();
+    let mut builder = if schedule.is_empty() {
+        Builder::new(4)
+    } else {
+        Builder::with_schedule(&schedule)
+    };
+    let layouts = [
+        Layout::Insertion,
+        Layout::DepthFirst,
+        Layout::BreadthFirst,
+        Layout::VanEmdeBoas,
+    ];
+    let layout = layouts[input.layout as usize % layouts.len()];
+    builder.set_layout(layout);
+    let compressions = [
+        Compression::None,
+        Compression::Symbols,
+        Compression::Blocks,
+    ];
+    builder.set_compression(compressions[input.compression as usize % compressions.len()]);
+    builder.set_dense_range(input.dense_range);
+
+    let mut expected = BTreeMap::new();
+    let mut keys = vec![];
+    for (count, op) in input.ops.iter().enumerate() {
+        if input.rebuild && count == input.ops.len() / 2 {
+            let (bytes, rebuilt) = build(builder);
+            check(&bytes, &expected, &keys);
+            builder = rebuilt;
+            builder.set_layout(layout);
+        }
+        match op {
+            Op::Insert(key, value) => {
+                // Values are C strings.
+                let value = value.replace('\0', "");
+                builder.insert(*key, &value);
+                // Inserting does not replace existing values.
+                expected.entry(*key).or_insert(value);
+                keys.push(*key);
+            }
+            Op::Remove(key) => {
+                assert_eq!(expected.remove(key).is_some(), builder.remove(*key));
+                keys.push(*key);
+            }
+        }
+    }
+    let (bytes, _) = build(builder);
+    check(&bytes, &expected, &keys);
+
+    let mut damaged = bytes;
+    let len = damaged.len();
+    for (position, bits) in &input.damage {
+        damaged[*position as usize % len] ^= bits;
+    }
+    if let Ok(map) = Map::try_new(&damaged) {
+        if let Ok(builder) = Builder::try_from_map(&map) {
+            Map::new(&builder.build());
+        }
+    }
+    if let Ok(builder) = Builder::try_from_bytes(damaged) {
+        CompressedMap::new(&builder.build());
+    }
+});
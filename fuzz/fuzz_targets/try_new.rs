@@ -0,0 +1,28 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks that the validating constructors accept and reject the same
+//! buffers, and do not crash on any.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sequence_map::{Map, MapBuf};
+
+fuzz_target!(|data: &[u8]| {
+    // Copies the input, so that it is aligned like any heap allocation.
+    let bytes = data.to_vec();
+    let valid = Map::try_new(&bytes).is_ok();
+    assert_eq!(valid, MapBuf::new(bytes).is_ok());
+});
@@ -0,0 +1,160 @@
+/*
+ * Copyright 2020 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#ifndef SEQUENCE_MAP_H
+#define SEQUENCE_MAP_H
+
+/* Generated by cbindgen from src/capi.rs.  Do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A read-only map.
+ */
+typedef struct SeqMap SeqMap;
+
+/**
+ * A map builder.
+ */
+typedef struct SeqMapBuilder SeqMapBuilder;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Loads the map stored in the file at `path`.  Returns NULL if the file can
+ * not be read, or does not hold a valid map.
+ *
+ * # Safety
+ *
+ * `path` must be NULL or a NUL-terminated string.
+ */
+struct SeqMap *seqmap_open(const char *path);
+
+/**
+ * Creates a map from a copy of the `len` bytes at `bytes`, as returned by
+ * `seqmap_bytes`.  Returns NULL if the bytes do not hold a valid map.
+ *
+ * # Safety
+ *
+ * `bytes` must be NULL or point to `len` readable bytes.
+ */
+struct SeqMap *seqmap_from_bytes(const uint8_t *bytes, size_t len);
+
+/**
+ * Looks up `key` in `map`.  Returns the found NUL-terminated UTF-8 string,
+ * which is valid until `map` is freed, or NULL if the key is not in the map.
+ * The values of maps with compressed values are decoded when they are first
+ * looked up, and kept until `map` is freed, so that the memory used by
+ * `map` grows by the size of each distinct value looked up, up to the size
+ * of all its values.  `seqmap_get_copy` does not keep the values.
+ *
+ * # Safety
+ *
+ * `map` must be NULL or a map which has not been freed.
+ */
+const char *seqmap_get(const struct SeqMap *map, uint64_t key);
+
+/**
+ * Looks up `key` in `map`, and copies the found value, with its terminating
+ * NUL, into the `len` bytes at `buf`.  Returns the number of bytes that the
+ * value needs, including the NUL, or zero if the key is not in the map.  If
+ * that is more than `len`, nothing is copied, so that the lookup can be
+ * repeated with a larger buffer.  Unlike `seqmap_get`, this does not keep
+ * the values of maps with compressed values.
+ *
+ * # Safety
+ *
+ * `map` must be NULL or a map which has not been freed.  `buf` must point to
+ * `len` writable bytes, or be NULL if `len` is zero.
+ */
+size_t seqmap_get_copy(const struct SeqMap *map, uint64_t key, char *buf, size_t len);
+
+/**
+ * Returns the bytes of `map`, and stores their number into `len`.  These can
+ * be written to a file for `seqmap_open`.  The bytes are valid until `map`
+ * is freed.
+ *
+ * # Safety
+ *
+ * `map` must be NULL or a map which has not been freed.  `len` must point to
+ * a writable `size_t`.
+ */
+const uint8_t *seqmap_bytes(const struct SeqMap *map, size_t *len);
+
+/**
+ * Frees `map`.
+ *
+ * # Safety
+ *
+ * `map` must be NULL or a map which has not been freed.
+ */
+void seqmap_free(struct SeqMap *map);
+
+/**
+ * Creates a builder which uses `bits` bits of the key for each trie level.
+ * Returns NULL if `bits` is not between 2 and 16.
+ */
+struct SeqMapBuilder *seqmap_builder_new(size_t bits);
+
+/**
+ * Inserts `key` with the NUL-terminated `value` into the builder, unless
+ * `key` is already present.  Returns false if `value` is not valid UTF-8.
+ *
+ * # Safety
+ *
+ * `builder` must be NULL or a builder which has not been freed or built.
+ * `value` must be NULL or a NUL-terminated string.
+ */
+bool seqmap_builder_insert(struct SeqMapBuilder *builder, uint64_t key, const char *value);
+
+/**
+ * Removes `key` from the builder.  Returns true if the key was present.
+ *
+ * # Safety
+ *
+ * `builder` must be NULL or a builder which has not been freed or built.
+ */
+bool seqmap_builder_remove(struct SeqMapBuilder *builder, uint64_t key);
+
+/**
+ * Builds the map, and frees the builder.
+ *
+ * # Safety
+ *
+ * `builder` must be NULL or a builder which has not been freed or built.
+ */
+struct SeqMap *seqmap_builder_build(struct SeqMapBuilder *builder);
+
+/**
+ * Frees the builder without building the map.
+ *
+ * # Safety
+ *
+ * `builder` must be NULL or a builder which has not been freed or built.
+ */
+void seqmap_builder_free(struct SeqMapBuilder *builder);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SEQUENCE_MAP_H */
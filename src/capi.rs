@@ -0,0 +1,270 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A C API for building maps and looking up keys in them.  The declarations
+//! for C are in `include/sequence_map.h`, which is generated from this file
+//! by `build.rs`.
+//!
+//! Functions which create an object return a pointer to it, or NULL on
+//! failure.  Each object must be freed with the matching `free` function.
+//! Passing NULL to any function which expects an object is allowed; it is
+//! treated as a failure, or ignored by the `free` functions.
+
+use crate::{Builder, Compression, MapBuf};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use std::slice;
+use std::sync::{Mutex, PoisonError};
+
+/// A read-only map.
+pub struct SeqMap {
+    // The values of the map may be compressed, so they are only looked up
+    // with Map::value, or with Map::get_cstr if they are not compressed.
+    map: MapBuf,
+    // The values which were decoded, if the values of the map are compressed,
+    // by their string index.  They are kept until the map is freed.
+    decoded: Mutex<HashMap<usize, CString>>,
+}
+
+/// A map builder.
+pub struct SeqMapBuilder {
+    builder: Builder,
+}
+
+fn into_raw(map: MapBuf) -> *mut SeqMap {
+    Box::into_raw(Box::new(SeqMap {
+        map,
+        decoded: Mutex::new(HashMap::new()),
+    }))
+}
+
+/// Loads the map stored in the file at `path`.  Returns NULL if the file can
+/// not be read, or does not hold a valid map.
+///
+/// # Safety
+///
+/// `path` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_open(path: *const c_char) -> *mut SeqMap {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ptr::null_mut(),
+    };
+    match std::fs::read(path)
+        .ok()
+        .and_then(|bytes| MapBuf::new_compressed(bytes).ok())
+    {
+        Some(map) => into_raw(map),
+        None => ptr::null_mut(),
+    }
+}
+
+/// Creates a map from a copy of the `len` bytes at `bytes`, as returned by
+/// `seqmap_bytes`.  Returns NULL if the bytes do not hold a valid map.
+///
+/// # Safety
+///
+/// `bytes` must be NULL or point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_from_bytes(bytes: *const u8, len: usize) -> *mut SeqMap {
+    if bytes.is_null() {
+        return ptr::null_mut();
+    }
+    // Copying also makes sure that the map is aligned.
+    let bytes = slice::from_raw_parts(bytes, len).to_vec();
+    match MapBuf::new_compressed(bytes) {
+        Ok(map) => into_raw(map),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Looks up `key` in `map`.  Returns the found NUL-terminated UTF-8 string,
+/// which is valid until `map` is freed, or NULL if the key is not in the map.
+/// The values of maps with compressed values are decoded when they are first
+/// looked up, and kept until `map` is freed, so that the memory used by
+/// `map` grows by the size of each distinct value looked up, up to the size
+/// of all its values.  `seqmap_get_copy` does not keep the values.
+///
+/// # Safety
+///
+/// `map` must be NULL or a map which has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_get(map: *const SeqMap, key: u64) -> *const c_char {
+    match map.as_ref().and_then(|map| map.get(key)) {
+        Some(value) => value,
+        None => ptr::null(),
+    }
+}
+
+/// Looks up `key` in `map`, and copies the found value, with its terminating
+/// NUL, into the `len` bytes at `buf`.  Returns the number of bytes that the
+/// value needs, including the NUL, or zero if the key is not in the map.  If
+/// that is more than `len`, nothing is copied, so that the lookup can be
+/// repeated with a larger buffer.  Unlike `seqmap_get`, this does not keep
+/// the values of maps with compressed values.
+///
+/// # Safety
+///
+/// `map` must be NULL or a map which has not been freed.  `buf` must point to
+/// `len` writable bytes, or be NULL if `len` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_get_copy(
+    map: *const SeqMap,
+    key: u64,
+    buf: *mut c_char,
+    len: usize,
+) -> usize {
+    let value = match map
+        .as_ref()
+        .and_then(|map| map.map.value(map.map.find(key)?))
+    {
+        Some(value) => value,
+        None => return 0,
+    };
+    let needed = value.len() + 1;
+    if needed <= len {
+        ptr::copy_nonoverlapping(value.as_ptr(), buf as *mut u8, value.len());
+        *buf.add(value.len()) = 0;
+    }
+    needed
+}
+
+impl SeqMap {
+    // Returns the value of `key`, which lives as long as the map does.
+    fn get(&self, key: u64) -> Option<*const c_char> {
+        if self.map.compression() == Compression::None {
+            return self.map.get_cstr(key).map(CStr::as_ptr);
+        }
+        let string_index = self.map.find(key)?;
+        let mut decoded = self.decoded.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(value) = decoded.get(&string_index) {
+            return Some(value.as_ptr());
+        }
+        let value = CString::new(self.map.value(string_index)?.into_owned()).ok()?;
+        // The bytes of the string do not move when it is moved into the map.
+        let pointer = value.as_ptr();
+        decoded.insert(string_index, value);
+        Some(pointer)
+    }
+}
+
+/// Returns the bytes of `map`, and stores their number into `len`.  These can
+/// be written to a file for `seqmap_open`.  The bytes are valid until `map`
+/// is freed.
+///
+/// # Safety
+///
+/// `map` must be NULL or a map which has not been freed.  `len` must point to
+/// a writable `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_bytes(map: *const SeqMap, len: *mut usize) -> *const u8 {
+    let bytes = match map.as_ref() {
+        Some(map) => map.map.as_bytes(),
+        None => &[],
+    };
+    *len = bytes.len();
+    bytes.as_ptr()
+}
+
+/// Frees `map`.
+///
+/// # Safety
+///
+/// `map` must be NULL or a map which has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_free(map: *mut SeqMap) {
+    if !map.is_null() {
+        drop(Box::from_raw(map));
+    }
+}
+
+/// Creates a builder which uses `bits` bits of the key for each trie level.
+/// Returns NULL if `bits` is not between 2 and 16.
+#[no_mangle]
+pub extern "C" fn seqmap_builder_new(bits: usize) -> *mut SeqMapBuilder {
+    if !(2..=16).contains(&bits) {
+        return ptr::null_mut();
+    }
+    let builder = Builder::new(bits);
+    Box::into_raw(Box::new(SeqMapBuilder { builder }))
+}
+
+/// Inserts `key` with the NUL-terminated `value` into the builder, unless
+/// `key` is already present.  Returns false if `value` is not valid UTF-8.
+///
+/// # Safety
+///
+/// `builder` must be NULL or a builder which has not been freed or built.
+/// `value` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_builder_insert(
+    builder: *mut SeqMapBuilder,
+    key: u64,
+    value: *const c_char,
+) -> bool {
+    if builder.is_null() || value.is_null() {
+        return false;
+    }
+    match CStr::from_ptr(value).to_str() {
+        Ok(value) => {
+            (*builder).builder.insert(key, value);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Removes `key` from the builder.  Returns true if the key was present.
+///
+/// # Safety
+///
+/// `builder` must be NULL or a builder which has not been freed or built.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_builder_remove(builder: *mut SeqMapBuilder, key: u64) -> bool {
+    match builder.as_mut() {
+        Some(builder) => builder.builder.remove(key),
+        None => false,
+    }
+}
+
+/// Builds the map, and frees the builder.
+///
+/// # Safety
+///
+/// `builder` must be NULL or a builder which has not been freed or built.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_builder_build(builder: *mut SeqMapBuilder) -> *mut SeqMap {
+    if builder.is_null() {
+        return ptr::null_mut();
+    }
+    let builder = Box::from_raw(builder).builder;
+    into_raw(MapBuf::new(builder.build()).expect("built maps are valid"))
+}
+
+/// Frees the builder without building the map.
+///
+/// # Safety
+///
+/// `builder` must be NULL or a builder which has not been freed or built.
+#[no_mangle]
+pub unsafe extern "C" fn seqmap_builder_free(builder: *mut SeqMapBuilder) {
+    if !builder.is_null() {
+        drop(Box::from_raw(builder));
+    }
+}
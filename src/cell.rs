@@ -21,6 +21,9 @@ pub enum Type {
     Empty = 0,
     StringPtr = 1,
     TablePtr = 2,
+    // A pointer to a table which is more than one level below, along with the
+    // key bits expected on the levels in between.
+    Skip = 3,
     Unknown = 255,
 }
 
@@ -35,46 +38,65 @@ impl From<u8> for Type {
         if t == Type::TablePtr as u8 {
             return Type::TablePtr;
         }
+        if t == Type::Skip as u8 {
+            return Type::Skip;
+        }
         Type::Unknown
     }
 }
 
-#[derive(AsBytes, FromBytes)]
-#[repr(packed)]
+/// The number of low bits of [Instance::tag_and_index] which hold the type.
+const TAG_BITS: u32 = 3;
+
+/// An empty cell.
+pub const EMPTY: Instance = Instance {
+    tag_and_index: Type::Empty as u64,
+    string_key: 0,
+};
+
+/// A single cell of a table.  Cells are 16 bytes long and aligned to 8 bytes,
+/// so that they can be read without unaligned accesses.
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C)]
 pub struct Instance {
-    c_type: u8,
-    /// Byte pointer index.  For strings, it's relative to the string offset
-    /// index as specified in the table root.  For tables, it is relative to
-    /// the start of the buffer.
-    index: usize,
-    /// For StringPtr, contains the actual key of the stored string.  Should be
-    /// zero for all other types.
+    /// The type of the cell in the lowest [TAG_BITS] bits, and the byte
+    /// pointer index in the remaining bits.  For strings, the index is relative
+    /// to the string offset index as specified in the table root.  For tables,
+    /// it is relative to the start of the buffer.
+    tag_and_index: u64,
+    /// For StringPtr, contains the actual key of the stored string.  For Skip,
+    /// contains the key bits to skip over, followed by a single set bit which
+    /// marks the number of bits to skip.  Should be zero for all other types.
     string_key: u64,
 }
 
 impl Instance {
     pub fn string_index_and_key(&self) -> (usize, u64) {
         assert!(self.get_type() == Type::StringPtr);
-        (self.index, self.string_key)
+        (self.index(), self.string_key)
     }
 
     pub fn table_index(&self) -> usize {
         assert!(self.get_type() == Type::TablePtr);
-        self.index
+        self.index()
+    }
+
+    fn index(&self) -> usize {
+        (self.tag_and_index >> TAG_BITS) as usize
+    }
+
+    /// Returns the table index, the number of key bits to skip, and the
+    /// expected value of the skipped bits.
+    pub fn skip(&self) -> (usize, u8, u64) {
+        assert!(self.get_type() == Type::Skip);
+        let marker = self.string_key;
+        // A marker of zero is not valid, and is treated as skipping no bits.
+        let bits = 63 - marker.leading_zeros().min(63) as u8;
+        (self.index(), bits, marker & !(1 << bits))
     }
 
     pub fn get_type(&self) -> Type {
-        let t = self.c_type;
-        if t == Type::Empty as u8 {
-            return Type::Empty;
-        }
-        if t == Type::StringPtr as u8 {
-            return Type::StringPtr;
-        }
-        if t == Type::TablePtr as u8 {
-            return Type::TablePtr;
-        }
-        return Type::Unknown;
+        Type::from((self.tag_and_index & ((1 << TAG_BITS) - 1)) as u8)
     }
 
     pub fn become_string_ptr(&mut self, index: usize, key: u64) {
@@ -86,9 +108,149 @@ impl Instance {
         self.become_type(Type::TablePtr, index);
     }
 
+    pub fn become_skip(&mut self, index: usize, bits: u8, pattern: u64) {
+        assert!(bits > 0 && bits < 64, "bits: {}", bits);
+        assert_eq!(pattern >> bits, 0, "pattern: {}, bits: {}", pattern, bits);
+        self.become_type(Type::Skip, index);
+        self.string_key = pattern | (1 << bits);
+    }
+
+    pub fn become_empty(&mut self) {
+        self.become_type(Type::Empty, 0);
+    }
+
     fn become_type(&mut self, t: Type, index: usize) {
-        self.c_type = t as u8;
-        self.index = index;
+        let index = index as u64;
+        assert_eq!(index << TAG_BITS >> TAG_BITS, index, "index: {}", index);
+        self.tag_and_index = index << TAG_BITS | t as u64;
         self.string_key = 0;
     }
 }
+
+/// The largest index that a [Compact] cell can hold.
+pub const COMPACT_INDEX_LIMIT: usize = 1 << (32 - TAG_BITS);
+
+/// A table cell which is half the size of an [Instance].  Its index is 32 bits
+/// wide, so it can only be used in buffers smaller than
+/// [COMPACT_INDEX_LIMIT].  Instead of the complete key of a string, it stores
+/// only the key bits which are not used to index the tables on the way to the
+/// cell.
+#[derive(AsBytes, FromBytes, Clone, Copy)]
+#[repr(C)]
+pub struct Compact {
+    /// Same as [Instance::tag_and_index].
+    tag_and_index: u32,
+    /// For StringPtr, the key of the stored string shifted right by the number
+    /// of key bits used by the tables up to and including the one this cell is
+    /// in.  For Skip, same as [Instance::string_key].  Zero for all other types.
+    residual: u32,
+}
+
+impl Compact {
+    /// Creates a compact cell with the same content as `cell`, which is in a
+    /// table below which `remaining_bits` of the key are not used for indexing.
+    /// Returns `None` if the content does not fit.
+    pub fn from_instance(cell: &Instance, remaining_bits: usize) -> Option<Compact> {
+        let residual = match cell.get_type() {
+            Type::StringPtr => match remaining_bits {
+                0 => 0,
+                bits => cell.string_key >> (64 - bits),
+            },
+            _ => cell.string_key,
+        };
+        let index = cell.index();
+        if index >= COMPACT_INDEX_LIMIT || residual > u32::MAX as u64 {
+            return None;
+        }
+        Some(Compact {
+            tag_and_index: (index as u32) << TAG_BITS | cell.get_type() as u32,
+            residual: residual as u32,
+        })
+    }
+
+    pub fn get_type(&self) -> Type {
+        Type::from((self.tag_and_index & ((1 << TAG_BITS) - 1)) as u8)
+    }
+
+    fn index(&self) -> usize {
+        (self.tag_and_index >> TAG_BITS) as usize
+    }
+
+    /// Returns the cell content as an [Instance].  The complete key of a
+    /// string is reconstructed from `path`, which holds the lowest
+    /// `64 - remaining_bits` bits of the key.
+    pub fn to_instance(self, remaining_bits: usize, path: u64) -> Instance {
+        let string_key = match self.get_type() {
+            Type::StringPtr => match remaining_bits {
+                0 => path,
+                bits => (self.residual as u64) << (64 - bits) | path,
+            },
+            _ => self.residual as u64,
+        };
+        Instance {
+            tag_and_index: self.tag_and_index as u64,
+            string_key,
+        }
+    }
+}
+
+/// A reference to a table cell in either of the cell formats.
+#[derive(Clone, Copy)]
+pub enum Ref<'a> {
+    Wide(&'a Instance),
+    Compact(&'a Compact),
+}
+
+impl<'a> Ref<'a> {
+    pub fn get_type(&self) -> Type {
+        match self {
+            Ref::Wide(cell) => cell.get_type(),
+            Ref::Compact(cell) => cell.get_type(),
+        }
+    }
+
+    /// Returns true if this cell holds the string for `key`.  `next_key` is
+    /// the part of the key which is not used by the tables up to and including
+    /// the one this cell is in.
+    pub fn is_string_for(&self, key: u64, next_key: u64) -> bool {
+        assert!(self.get_type() == Type::StringPtr);
+        match self {
+            Ref::Wide(cell) => cell.string_key == key,
+            Ref::Compact(cell) => cell.residual as u64 == next_key,
+        }
+    }
+
+    pub fn string_index(&self) -> usize {
+        assert!(self.get_type() == Type::StringPtr);
+        self.index()
+    }
+
+    pub fn table_index(&self) -> usize {
+        assert!(self.get_type() == Type::TablePtr);
+        self.index()
+    }
+
+    /// Same as [Instance::skip].
+    pub fn skip(&self) -> (usize, u8, u64) {
+        match self {
+            Ref::Wide(cell) => cell.skip(),
+            Ref::Compact(cell) => cell.to_instance(0, 0).skip(),
+        }
+    }
+
+    /// Returns the cell content as an [Instance].  See [Compact::to_instance]
+    /// for the meaning of the arguments, which are only used for compact cells.
+    pub fn to_instance(self, remaining_bits: usize, path: u64) -> Instance {
+        match self {
+            Ref::Wide(cell) => *cell,
+            Ref::Compact(cell) => cell.to_instance(remaining_bits, path),
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Ref::Wide(cell) => cell.index(),
+            Ref::Compact(cell) => cell.index(),
+        }
+    }
+}
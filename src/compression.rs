@@ -0,0 +1,617 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compression of the string section.
+//!
+//! With [Compression::Symbols], the values are encoded with a table of
+//! frequent byte sequences, in the manner of FSST ("Fast Static Symbol
+//! Table").  Each value is encoded on its own, so that a single value can be
+//! decoded without touching any of the others.
+//!
+//! With [Compression::Blocks], the values are grouped into blocks, which are
+//! compressed with [lz](crate::lz).  A value is decoded by decompressing its
+//! whole block.  The map keeps the most recently decompressed blocks, so that
+//! values which are looked up together are cheap to decode.
+
+use crate::lz;
+use crate::string_slice::Intern;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// How the values of a map are stored.  See [Builder::set_compression](crate::Builder::set_compression).
+///
+/// The values of compressed maps can not be borrowed from the map, so
+/// [Map::try_new](crate::Map::try_new) rejects such maps.  They are looked up
+/// with a [CompressedMap](crate::CompressedMap), which decodes the values.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum Compression {
+    /// Values are stored as they are, each followed by a NUL byte.  This is
+    /// the default.
+    #[default]
+    None = 0,
+    /// Values are encoded with up to 255 symbols of up to 8 bytes each, which
+    /// are chosen based on the values of the map.  This works well for values
+    /// which share many words or fragments, such as text in a natural
+    /// language.
+    Symbols = 1,
+    /// Values are grouped into blocks of about 4 KiB, which are compressed
+    /// with an LZ77 codec.  This makes maps smaller than [Compression::Symbols]
+    /// does, in particular if values repeat longer parts of other values, but
+    /// each lookup decompresses a whole block.  Each [Map](crate::Map) keeps
+    /// the 8 blocks that it decompressed last, so lookups of values which are
+    /// stored close together are faster.
+    Blocks = 2,
+}
+
+// The code which is followed by a byte that stands for itself.
+const ESCAPE: u8 = 255;
+const MAX_SYMBOLS: usize = ESCAPE as usize;
+const MAX_SYMBOL_LEN: usize = 8;
+// The number of value bytes that the symbols are chosen from.
+const SAMPLE_LEN: usize = 1 << 16;
+// The number of times that the symbols are refined.
+const ROUNDS: usize = 5;
+
+/// A table of symbols, as used by the builder to encode values.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Vec<u8>>,
+    // The codes of the symbols which start with each byte, longest first.
+    by_first: Vec<Vec<u8>>,
+}
+
+impl SymbolTable {
+    /// Chooses the symbols which encode `values` best.  Starting from no
+    /// symbols at all, each round counts how often each symbol, and each
+    /// concatenation of two symbols, is used when encoding the values with the
+    /// symbols of the previous round.  The symbols which save the most bytes
+    /// are kept for the next round.
+    pub fn train<'a, I>(values: I) -> SymbolTable
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut sample = vec![];
+        let mut sample_len = 0;
+        for value in values {
+            if sample_len >= SAMPLE_LEN {
+                break;
+            }
+            sample_len += value.len();
+            sample.push(value.as_bytes());
+        }
+        let mut table = SymbolTable::new(vec![]);
+        for _ in 0..ROUNDS {
+            let mut counts: HashMap<&[u8], usize> = HashMap::new();
+            for value in &sample {
+                let mut position = 0;
+                let mut previous: Option<usize> = None;
+                while position < value.len() {
+                    let len = match table.longest_match(&value[position..]) {
+                        Some(code) => table.symbols[code as usize].len(),
+                        None => 1,
+                    };
+                    *counts.entry(&value[position..position + len]).or_default() += 1;
+                    if len > 1 {
+                        *counts.entry(&value[position..position + 1]).or_default() += 1;
+                    }
+                    if let Some(start) = previous {
+                        if position + len - start <= MAX_SYMBOL_LEN {
+                            *counts.entry(&value[start..position + len]).or_default() += 1;
+                        }
+                    }
+                    previous = Some(position);
+                    position += len;
+                }
+            }
+            let mut candidates: Vec<(&[u8], usize)> = counts.into_iter().collect();
+            // Sorting by the symbol as well keeps the result deterministic.
+            candidates.sort_by_key(|(symbol, count)| (Reverse(count * symbol.len()), *symbol));
+            candidates.truncate(MAX_SYMBOLS);
+            table = SymbolTable::new(
+                candidates
+                    .into_iter()
+                    .map(|(symbol, _)| symbol.to_vec())
+                    .collect(),
+            );
+        }
+        table
+    }
+
+    fn new(symbols: Vec<Vec<u8>>) -> SymbolTable {
+        let mut by_first = vec![vec![]; 256];
+        for (code, symbol) in symbols.iter().enumerate() {
+            by_first[symbol[0] as usize].push(code as u8);
+        }
+        for codes in &mut by_first {
+            codes.sort_by_key(|code| Reverse(symbols[*code as usize].len()));
+        }
+        SymbolTable { symbols, by_first }
+    }
+
+    // Returns the code of the longest symbol that `bytes` starts with.
+    fn longest_match(&self, bytes: &[u8]) -> Option<u8> {
+        self.by_first[bytes[0] as usize]
+            .iter()
+            .copied()
+            .find(|code| bytes.starts_with(&self.symbols[*code as usize]))
+    }
+
+    /// Appends the table to `out`: the number of symbols, then the length of
+    /// each symbol, then the bytes of all symbols.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.symbols.len() as u8);
+        out.extend(self.symbols.iter().map(|symbol| symbol.len() as u8));
+        for symbol in &self.symbols {
+            out.extend_from_slice(symbol);
+        }
+    }
+
+    /// Appends the encoded `value` to `out`: the number of bytes of codes, as
+    /// a LEB128 number, followed by the codes.
+    pub fn encode(&self, value: &str, out: &mut Vec<u8>) {
+        let value = value.as_bytes();
+        let mut codes = vec![];
+        let mut position = 0;
+        while position < value.len() {
+            match self.longest_match(&value[position..]) {
+                Some(code) => {
+                    codes.push(code);
+                    position += self.symbols[code as usize].len();
+                }
+                None => {
+                    codes.push(ESCAPE);
+                    codes.push(value[position]);
+                    position += 1;
+                }
+            }
+        }
+        let mut len = codes.len();
+        while len >= 0x80 {
+            out.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        out.push(len as u8);
+        out.extend_from_slice(&codes);
+    }
+}
+
+/// A symbol table within a map, which decodes values.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbols<'a> {
+    // The whole string section, which starts with the table.
+    section: &'a [u8],
+    // Symbol `i` is at `starts[i]..starts[i + 1]` of `section`.
+    starts: [u16; MAX_SYMBOLS + 1],
+    count: usize,
+}
+
+impl<'a> Symbols<'a> {
+    /// Reads the table at the start of the string section `section`.  Returns
+    /// `None` if it does not fit, or has symbols that are empty or too long.
+    pub fn read(section: &'a [u8]) -> Option<Symbols<'a>> {
+        let count = *section.first()? as usize;
+        let lens = section.get(1..1 + count)?;
+        let mut starts = [0; MAX_SYMBOLS + 1];
+        let mut start = 1 + count;
+        for (code, len) in lens.iter().enumerate() {
+            if *len == 0 || *len as usize > MAX_SYMBOL_LEN {
+                return None;
+            }
+            starts[code] = start as u16;
+            start += *len as usize;
+        }
+        starts[count] = start as u16;
+        if start > section.len() {
+            return None;
+        }
+        Some(Symbols {
+            section,
+            starts,
+            count,
+        })
+    }
+
+    /// Returns the offset of the first value, just past the table.
+    pub fn len(&self) -> usize {
+        self.starts[self.count] as usize
+    }
+
+    /// Decodes the value at `index` of the string section.  Returns the value
+    /// and the offset just past it, or `None` if the value is not valid.
+    pub fn decode(&self, index: usize) -> Option<(String, usize)> {
+        let mut position = index;
+        let mut len: usize = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.section.get(position)?;
+            position += 1;
+            len |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if shift + 7 >= 64 {
+                return None;
+            }
+        }
+        let end = position.checked_add(len)?;
+        let mut codes = self.section.get(position..end)?.iter();
+        let mut value = vec![];
+        while let Some(code) = codes.next() {
+            match *code {
+                ESCAPE => value.push(*codes.next()?),
+                code if (code as usize) < self.count => {
+                    let code = code as usize;
+                    let symbol = self.starts[code] as usize..self.starts[code + 1] as usize;
+                    value.extend_from_slice(&self.section[symbol]);
+                }
+                _ => return None,
+            }
+        }
+        let value = String::from_utf8(value).ok()?;
+        Some((value, end))
+    }
+}
+
+// The number of uncompressed bytes after which a block is complete.
+const BLOCK_SIZE: usize = 1 << BLOCK_OFFSET_BITS;
+// The number of bits of a string index which are the offset in the block.
+// Strings start at offsets below BLOCK_SIZE.
+const BLOCK_OFFSET_BITS: u32 = 12;
+// The number of decompressed blocks that a map keeps.
+const CACHED_BLOCKS: usize = 8;
+
+/// The string section of a map, as used for lookups.
+#[derive(Debug, Clone)]
+pub enum Strings<'a> {
+    Plain,
+    // Boxed, as the table of symbol offsets is large.
+    Symbols(Box<Symbols<'a>>),
+    Blocks(Blocks<'a>),
+}
+
+impl<'a> Strings<'a> {
+    /// Reads the string section `section`, with the format `format` from the
+    /// map header.  Returns `None` if the start of the section is not valid.
+    /// The values themselves are only checked when they are decoded.
+    pub fn read(format: u8, section: &'a [u8]) -> Option<Strings<'a>> {
+        if format == Compression::None as u8 {
+            // The last string must be terminated within the buffer.
+            return match section.last() {
+                None | Some(0) => Some(Strings::Plain),
+                Some(_) => None,
+            };
+        }
+        if format == Compression::Symbols as u8 {
+            return Symbols::read(section).map(|symbols| Strings::Symbols(Box::new(symbols)));
+        }
+        if format == Compression::Blocks as u8 {
+            return Blocks::read(section).map(Strings::Blocks);
+        }
+        None
+    }
+}
+
+/// Encodes the strings of `intern` into a string section with `compression`.
+/// Returns the section, and, if the strings have moved, the index in it of
+/// each string, by the string's index in `intern`.
+pub fn compress(
+    intern: Intern,
+    compression: Compression,
+) -> (Vec<u8>, Option<HashMap<usize, usize>>) {
+    match compression {
+        Compression::None => (intern.into(), None),
+        Compression::Symbols => {
+            let (section, indexes) = compress_symbols(&intern);
+            (section, Some(indexes))
+        }
+        Compression::Blocks => {
+            let (section, indexes) = compress_blocks(&intern);
+            (section, Some(indexes))
+        }
+    }
+}
+
+// The compression, the strings, and the indexes of the strings of a decoded
+// string section.
+type Decompressed = (Compression, Intern, Option<HashMap<usize, usize>>);
+
+/// Decodes the string section `section` with the format `format` from the
+/// map header.  Returns the compression that was used, the strings, and, if
+/// they have moved, the index of each string in them, by the string's index
+/// in `section`.  Returns `None` if `section` is not valid.
+pub fn decompress(format: u8, section: Vec<u8>) -> Option<Decompressed> {
+    Some(match Strings::read(format, &section)? {
+        Strings::Plain => {
+            // Strings are separated by '\0', which is not part of any other
+            // UTF-8 sequence, so the strings are valid if the section is.
+            std::str::from_utf8(&section).ok()?;
+            (Compression::None, Intern::from(section), None)
+        }
+        Strings::Symbols(symbols) => {
+            let (intern, indexes) = decompress_symbols(&symbols)?;
+            (Compression::Symbols, intern, Some(indexes))
+        }
+        Strings::Blocks(blocks) => {
+            let (intern, indexes) = decompress_blocks(&blocks)?;
+            (Compression::Blocks, intern, Some(indexes))
+        }
+    })
+}
+
+fn compress_symbols(intern: &Intern) -> (Vec<u8>, HashMap<usize, usize>) {
+    let table = SymbolTable::train(intern.iter().map(|(_, value)| value));
+    let mut section = vec![];
+    table.write(&mut section);
+    let mut indexes = HashMap::new();
+    for (index, value) in intern.iter() {
+        indexes.insert(index, section.len());
+        table.encode(value, &mut section);
+    }
+    (section, indexes)
+}
+
+fn decompress_symbols(symbols: &Symbols) -> Option<(Intern, HashMap<usize, usize>)> {
+    let mut intern = Intern::new();
+    let mut indexes = HashMap::new();
+    let mut index = symbols.len();
+    while index < symbols.section.len() {
+        let (value, end) = symbols.decode(index)?;
+        indexes.insert(index, intern.add(&value));
+        index = end;
+    }
+    Some((intern, indexes))
+}
+
+/// A block compressed string section within a map, which decodes values.
+#[derive(Debug)]
+pub struct Blocks<'a> {
+    section: &'a [u8],
+    count: usize,
+    offset_bits: u32,
+    // Recently decompressed blocks, by block number, the most recently used
+    // first.
+    cache: Mutex<Vec<(usize, Vec<u8>)>>,
+}
+
+// Each clone has a cache of its own, so that threads with their own clone do
+// not contend for it.
+impl Clone for Blocks<'_> {
+    fn clone(&self) -> Self {
+        Blocks {
+            section: self.section,
+            count: self.count,
+            offset_bits: self.offset_bits,
+            cache: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl<'a> Blocks<'a> {
+    // The size of the number of blocks, of the number of offset bits, and of
+    // each block offset.
+    const COUNT_SIZE: usize = 4;
+    const OFFSET_BITS_SIZE: usize = 4;
+    const OFFSET_SIZE: usize = 8;
+
+    /// Reads the header at the start of the string section `section`.
+    /// Returns `None` if it does not fit, or has an invalid number of offset
+    /// bits.
+    pub fn read(section: &'a [u8]) -> Option<Blocks<'a>> {
+        let count = read_u32(section, 0)? as usize;
+        let offset_bits = read_u32(section, Blocks::COUNT_SIZE)?;
+        if offset_bits == 0 || offset_bits > 32 {
+            return None;
+        }
+        let header_len = count
+            .checked_add(1)?
+            .checked_mul(Blocks::OFFSET_SIZE)?
+            .checked_add(Blocks::COUNT_SIZE + Blocks::OFFSET_BITS_SIZE)?;
+        if header_len > section.len() {
+            return None;
+        }
+        Some(Blocks {
+            section,
+            count,
+            offset_bits,
+            cache: Mutex::new(vec![]),
+        })
+    }
+
+    // Returns the compressed bytes of block `block`.
+    fn compressed(&self, block: usize) -> Option<&'a [u8]> {
+        let offset = |block: usize| {
+            let at = Blocks::COUNT_SIZE + Blocks::OFFSET_BITS_SIZE + block * Blocks::OFFSET_SIZE;
+            let field = self.section.get(at..at + Blocks::OFFSET_SIZE)?;
+            u64::from_ne_bytes(field.try_into().unwrap())
+                .try_into()
+                .ok()
+        };
+        if block >= self.count {
+            return None;
+        }
+        let start: usize = offset(block)?;
+        let end: usize = offset(block + 1)?;
+        self.section.get(start..end)
+    }
+
+    /// Decodes the value at `index` of the string section.  Returns `None` if
+    /// the value is not valid.
+    pub fn decode(&self, index: usize) -> Option<String> {
+        let block = index.checked_shr(self.offset_bits).unwrap_or(0);
+        let offset = index
+            & 1_usize
+                .checked_shl(self.offset_bits)
+                .map_or(usize::MAX, |bit| bit - 1);
+        let value = |bytes: &[u8]| {
+            let value = ffi::CStr::from_bytes_until_nul(bytes.get(offset..)?).ok()?;
+            Some(value.to_str().ok()?.to_string())
+        };
+        {
+            let mut cache = self.lock_cache();
+            if let Some(position) = cache.iter().position(|(cached, _)| *cached == block) {
+                let entry = cache.remove(position);
+                cache.insert(0, entry);
+                return value(&cache[0].1);
+            }
+        }
+        // The cache is not locked while the block is decompressed, so that
+        // other threads which use the same map are not held up.  Another
+        // thread may decompress the same block in the meantime, in which
+        // case it is only cached once.
+        let bytes = lz::decompress(self.compressed(block)?)?;
+        let found = value(&bytes);
+        let mut cache = self.lock_cache();
+        if !cache.iter().any(|(cached, _)| *cached == block) {
+            cache.insert(0, (block, bytes));
+            cache.truncate(CACHED_BLOCKS);
+        }
+        found
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, Vec<(usize, Vec<u8>)>> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let field = bytes.get(at..at + 4)?;
+    Some(u32::from_ne_bytes(field.try_into().unwrap()))
+}
+
+fn compress_blocks(intern: &Intern) -> (Vec<u8>, HashMap<usize, usize>) {
+    let mut blocks = vec![];
+    let mut block = vec![];
+    let mut indexes = HashMap::new();
+    for (index, value) in intern.iter() {
+        if block.len() >= BLOCK_SIZE {
+            blocks.push(std::mem::take(&mut block));
+        }
+        indexes.insert(index, blocks.len() << BLOCK_OFFSET_BITS | block.len());
+        block.extend_from_slice(value.as_bytes());
+        block.push(0);
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+
+    let blocks: Vec<Vec<u8>> = blocks.iter().map(|block| lz::compress(block)).collect();
+    let mut section = vec![];
+    section.extend_from_slice(&(blocks.len() as u32).to_ne_bytes());
+    section.extend_from_slice(&BLOCK_OFFSET_BITS.to_ne_bytes());
+    let mut offset =
+        Blocks::COUNT_SIZE + Blocks::OFFSET_BITS_SIZE + (blocks.len() + 1) * Blocks::OFFSET_SIZE;
+    for block in blocks.iter().map(|block| block.len()).chain(Some(0)) {
+        section.extend_from_slice(&(offset as u64).to_ne_bytes());
+        offset += block;
+    }
+    for block in &blocks {
+        section.extend_from_slice(block);
+    }
+    (section, indexes)
+}
+
+fn decompress_blocks(blocks: &Blocks) -> Option<(Intern, HashMap<usize, usize>)> {
+    let mut intern = Intern::new();
+    let mut indexes = HashMap::new();
+    for block in 0..blocks.count {
+        let bytes = lz::decompress(blocks.compressed(block)?)?;
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let value = ffi::CStr::from_bytes_until_nul(&bytes[offset..]).ok()?;
+            let value = value.to_str().ok()?;
+            let index = block.checked_shl(blocks.offset_bits)? | offset;
+            indexes.insert(index, intern.add(value));
+            offset += value.len() + 1;
+        }
+    }
+    Some((intern, indexes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(values: &[&str]) -> Vec<u8> {
+        let table = SymbolTable::train(values.iter().copied());
+        let mut section = vec![];
+        table.write(&mut section);
+        let mut indexes = vec![];
+        for value in values {
+            indexes.push(section.len());
+            table.encode(value, &mut section);
+        }
+        let symbols = Symbols::read(&section).unwrap();
+        assert_eq!(
+            indexes.first().copied().unwrap_or(section.len()),
+            symbols.len()
+        );
+        for (value, index) in values.iter().zip(&indexes) {
+            let (decoded, end) = symbols.decode(*index).unwrap();
+            assert_eq!(*value, decoded);
+            assert!(end == section.len() || indexes.contains(&end));
+        }
+        section
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        round_trip(&[]);
+        round_trip(&["", "a", "", "Hello!"]);
+        round_trip(&["Grüße", "日本語のテキスト", "\u{10ffff}"]);
+        let long = "The quick brown fox jumps over the lazy dog. ".repeat(100);
+        round_trip(&[&long, "quick", "lazy dog"]);
+    }
+
+    #[test]
+    fn compresses_repetitive_text() {
+        let values: Vec<String> = (0..1000)
+            .map(|index| format!("The file number {} could not be opened.", index))
+            .collect();
+        let values: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+        let section = round_trip(&values);
+        let raw: usize = values.iter().map(|value| value.len() + 1).sum();
+        assert!(section.len() * 2 < raw, "{} of {}", section.len(), raw);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut section = vec![];
+        SymbolTable::train(vec!["abc"]).write(&mut section);
+        let start = section.len();
+        let symbols = Symbols::read(&section).unwrap();
+        // Past the end, and a length past the end.
+        assert!(symbols.decode(start).is_none());
+        section.push(2);
+        assert!(Symbols::read(&section).unwrap().decode(start).is_none());
+        // A code without a symbol, and an escape without a byte.
+        section.extend_from_slice(&[254, 0]);
+        assert!(Symbols::read(&section).unwrap().decode(start).is_none());
+        section[start..].copy_from_slice(&[1, ESCAPE, 0]);
+        assert!(Symbols::read(&section).unwrap().decode(start).is_none());
+        // Not UTF-8.
+        section[start..].copy_from_slice(&[2, ESCAPE, 0xff]);
+        assert!(Symbols::read(&section).unwrap().decode(start).is_none());
+        // A length which does not end.
+        section.truncate(start);
+        section.extend_from_slice(&[0xff; 11]);
+        assert!(Symbols::read(&section).unwrap().decode(start).is_none());
+
+        assert!(Symbols::read(&[]).is_none());
+        assert!(Symbols::read(&[1, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(Symbols::read(&[1, 0]).is_none());
+        assert!(Symbols::read(&[2, 1, 1, b'a']).is_none());
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The dense segment of a map, which holds the values of a range of keys in
+//! an array, so that looking up a key in the range takes a single load.  See
+//! [format](crate::format) for its layout.
+
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+use zerocopy::LayoutVerified;
+
+/// The string index of the keys in the range which are not in the map.
+pub const ABSENT: u64 = u64::MAX;
+
+// The fewest keys that a dense segment holds.  A few keys are found quickly
+// in the trie as well.
+const MIN_KEYS: usize = 64;
+
+// The largest difference between consecutive keys in a dense segment, so that
+// at most about half of its keys are absent.
+const MAX_GAP: u64 = 2;
+
+/// Returns the range of keys which the dense segment of a map with `keys`
+/// holds, or `None` if the map is better off without one.  `keys` must be
+/// sorted.  The range is that of the longest run of keys in which consecutive
+/// keys are at most a few apart.
+pub fn find_range(keys: &[u64]) -> Option<RangeInclusive<u64>> {
+    let mut finder = RangeFinder::default();
+    for key in keys {
+        finder.push(*key);
+    }
+    finder.finish()
+}
+
+/// Finds the same range as [find_range], given the keys one at a time.
+#[derive(Debug, Default)]
+pub struct RangeFinder {
+    // The first and last key, and the number of keys, of the longest run so
+    // far, and of the run that the last key is in.
+    longest: Option<(u64, u64, usize)>,
+    current: Option<(u64, u64, usize)>,
+}
+
+impl RangeFinder {
+    /// Takes the next `key` into account, which must be greater than the ones
+    /// before.
+    pub fn push(&mut self, key: u64) {
+        match &mut self.current {
+            Some((_, last, len)) if key - *last <= MAX_GAP => {
+                *last = key;
+                *len += 1;
+            }
+            _ => {
+                self.end_run();
+                self.current = Some((key, key, 1));
+            }
+        }
+    }
+
+    fn end_run(&mut self) {
+        if let Some(run) = self.current.take() {
+            if self.longest.map_or(true, |longest| run.2 > longest.2) {
+                self.longest = Some(run);
+            }
+        }
+    }
+
+    /// Returns the range, as [find_range] does for all keys pushed.
+    pub fn finish(mut self) -> Option<RangeInclusive<u64>> {
+        self.end_run();
+        self.longest
+            .filter(|(_, _, len)| *len >= MIN_KEYS)
+            .map(|(first, last, _)| first..=last)
+    }
+}
+
+/// Appends the dense segment of the keys from `base` on to `out`.  Each key
+/// is given by the string index of its value, or [ABSENT].
+pub fn write(base: u64, indexes: &[u64], out: &mut Vec<u8>) {
+    out.extend_from_slice(&base.to_ne_bytes());
+    out.extend_from_slice(&(indexes.len() as u64).to_ne_bytes());
+    for index in indexes {
+        out.extend_from_slice(&index.to_ne_bytes());
+    }
+}
+
+/// A dense segment, read from the bytes of a map.
+#[derive(Clone, Default)]
+pub struct Segment<'a> {
+    base: u64,
+    // The string index of each key, or ABSENT.
+    indexes: &'a [u64],
+}
+
+impl<'a> Segment<'a> {
+    /// Reads the dense segment which starts at the start of `bytes`.  Returns
+    /// `None` if it does not fit into `bytes`, if they are not aligned, or if
+    /// it has no keys.
+    pub fn read(bytes: &'a [u8]) -> Option<Segment<'a>> {
+        let words: &[u64] = LayoutVerified::new_slice_from_prefix(bytes, bytes.len() / 8)?
+            .0
+            .into_slice();
+        let base = *words.first()?;
+        let len = usize::try_from(*words.get(1)?).ok()?;
+        if len == 0 {
+            return None;
+        }
+        Some(Segment {
+            base,
+            indexes: words.get(2..len.checked_add(2)?)?,
+        })
+    }
+
+    /// Looks up `key`.  Returns `None` if it is outside of the range of the
+    /// segment, and otherwise the string index of its value, if it is in the
+    /// map.
+    #[inline]
+    pub fn get(&self, key: u64) -> Option<Option<usize>> {
+        let position = usize::try_from(key.wrapping_sub(self.base)).ok()?;
+        let index = *self.indexes.get(position)?;
+        Some((index != ABSENT).then_some(index as usize))
+    }
+
+    /// Returns the keys in the segment, and the string indexes of their
+    /// values.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            base: self.base,
+            indexes: self.indexes.iter().enumerate(),
+        }
+    }
+}
+
+/// An iterator over the keys in a dense segment.  See [Segment::entries].
+pub struct Entries<'a> {
+    base: u64,
+    indexes: std::iter::Enumerate<std::slice::Iter<'a, u64>>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = (u64, usize);
+
+    fn next(&mut self) -> Option<(u64, usize)> {
+        let base = self.base;
+        self.indexes.find_map(|(position, index)| {
+            (*index != ABSENT).then(|| (base.wrapping_add(position as u64), *index as usize))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(None, find_range(&[]));
+        let keys: Vec<u64> = (0..MIN_KEYS as u64 - 1).collect();
+        assert_eq!(None, find_range(&keys));
+        let keys: Vec<u64> = (0..MIN_KEYS as u64).collect();
+        assert_eq!(Some(0..=MIN_KEYS as u64 - 1), find_range(&keys));
+        // Every other key, after a shorter run, and before a gap.
+        let mut keys: Vec<u64> = (0..MIN_KEYS as u64).collect();
+        keys.extend((0..MIN_KEYS as u64 + 1).map(|key| 1000 + 2 * key));
+        keys.extend(&[2000, u64::MAX]);
+        assert_eq!(Some(1000..=1000 + 2 * MIN_KEYS as u64), find_range(&keys));
+    }
+
+    #[test]
+    fn write_and_read() {
+        let indexes = [5, ABSENT, 0, 7];
+        let mut bytes = vec![];
+        write(u64::MAX - 1, &indexes, &mut bytes);
+        // Copied into words, so that they are aligned.
+        let mut words = vec![0_u64; bytes.len() / 8];
+        words.as_bytes_mut().copy_from_slice(&bytes);
+        let bytes = words.as_bytes();
+        let segment = Segment::read(bytes).unwrap();
+        assert_eq!(None, segment.get(u64::MAX - 2));
+        assert_eq!(Some(Some(5)), segment.get(u64::MAX - 1));
+        assert_eq!(Some(None), segment.get(u64::MAX));
+        assert_eq!(Some(Some(0)), segment.get(0));
+        assert_eq!(Some(Some(7)), segment.get(1));
+        assert_eq!(None, segment.get(2));
+        assert_eq!(
+            vec![(u64::MAX - 1, 5), (0, 0), (1, 7)],
+            segment.entries().collect::<Vec<_>>()
+        );
+        for len in 0..bytes.len() {
+            assert!(Segment::read(&bytes[..len]).is_none(), "len: {}", len);
+        }
+    }
+}
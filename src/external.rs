@@ -0,0 +1,1247 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builds maps whose key-value pairs do not fit into memory.  See
+//! [ExternalBuilder].
+//!
+//! The key-value pairs are sorted with external merge sorts: records are
+//! sorted in memory in runs of a limited size, each run is written out to a
+//! temporary file, and the runs are merged while they are read back.  The
+//! map is then made in these steps:
+//!
+//! 1. The pairs are sorted by key, and only the first pair inserted under
+//!    each key is kept.
+//! 2. The pairs are sorted by value, to find the first insert of each
+//!    distinct value.  The values are written out in the order of those, as
+//!    [Builder] interns them, and each key is given the index of its value.
+//! 3. The keys are sorted in the order of a depth first traversal of the
+//!    trie, and the tables are reconstructed from them with the tables on the
+//!    path to the last key in memory.  Each table is complete when the keys
+//!    below it are, which is before the tables above it are.  So the tables
+//!    are sorted once more, into the order of the layout, and then written
+//!    out one after another.
+//!
+//! If the keys have a dense range, those in it are sorted by key once more,
+//! and written out in an array after the tables instead of into the trie.
+//!
+//! With [Layout::Insertion], a table is created by the insert of the second
+//! key below it, as in [Builder], including keys in the dense range.  The tables below a table come after it,
+//! but not right after it, so their offsets are found in a pass over the
+//! tables before they are written out, and joined back in with one more sort.
+
+use crate::cell;
+use crate::dense;
+use crate::header;
+use crate::layout;
+use crate::Builder;
+use crate::Layout;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The default number of bytes of records which are sorted in memory at a
+// time.
+const DEFAULT_RUN_SIZE: usize = 64 << 20;
+
+/// A map builder for maps whose key-value pairs do not fit into memory.  The
+/// pairs are kept in temporary files instead, and the map is written out to
+/// a [Write] as it is made.  The result is the same, byte for byte, as that of
+/// a [Builder] with the same bits per level, [Layout] and dense range setting,
+/// into which the same pairs were inserted in the same order.  As by default
+/// in a [Builder], values are stored without compression and without suffix
+/// sharing.
+///
+/// Example:
+///
+/// ```rust
+/// use sequence_map::{ExternalBuilder, Map};
+///
+/// let mut builder = ExternalBuilder::new(4, &std::env::temp_dir());
+/// builder.insert(42, "Hello!").unwrap();
+/// builder.insert(84, "World!").unwrap();
+/// let mut bytes = vec![];
+/// builder.build(&mut bytes).unwrap();
+///
+/// let lookup = Map::new(&bytes);
+/// assert_eq!("Hello!", lookup.get(42).unwrap());
+/// ```
+#[derive(Debug)]
+pub struct ExternalBuilder {
+    levels: Levels,
+    layout: Layout,
+    dense_range: bool,
+    // The directory in which temporary files are created.
+    dir: PathBuf,
+    run_size: usize,
+    // The inserted pairs, as the key in trie order, the number of inserts
+    // before, and the value.
+    entries: Sorter<(u64, u64, String)>,
+    inserts: u64,
+}
+
+impl ExternalBuilder {
+    /// Creates a new map builder, which uses `bits` bits for each level of the
+    /// trie, as [Builder::new] does.  Temporary files are created in `dir`.
+    pub fn new(bits: usize, dir: &Path) -> ExternalBuilder {
+        ExternalBuilder::with_schedule(&[bits], dir)
+    }
+
+    /// Creates a new map builder, which uses a different number of bits for
+    /// each level of the trie, as [Builder::with_schedule] does.  Temporary
+    /// files are created in `dir`.
+    pub fn with_schedule(schedule: &[usize], dir: &Path) -> ExternalBuilder {
+        ExternalBuilder {
+            levels: Levels::new(Builder::with_schedule(schedule).schedule),
+            layout: Layout::default(),
+            dense_range: true,
+            dir: dir.to_path_buf(),
+            run_size: DEFAULT_RUN_SIZE,
+            entries: Sorter::new(dir, DEFAULT_RUN_SIZE),
+            inserts: 0,
+        }
+    }
+
+    /// Sets about how many bytes of records are sorted in memory at a time,
+    /// before they are written out to a temporary file.  The default is 64
+    /// MiB.  Building a map takes about as much memory, and one open file for
+    /// each run of records.
+    pub fn set_run_size(&mut self, run_size: usize) {
+        assert!(run_size > 0, "run_size: {}", run_size);
+        self.run_size = run_size;
+        self.entries.run_size = run_size;
+    }
+
+    /// Sets the order in which the tables of the map are laid out, as
+    /// [Builder::set_layout] does.  Only [Layout::Insertion], the default, and
+    /// [Layout::DepthFirst] are supported.
+    pub fn set_layout(&mut self, layout: Layout) {
+        assert!(
+            matches!(layout, Layout::Insertion | Layout::DepthFirst),
+            "layout: {:?}",
+            layout
+        );
+        self.layout = layout;
+    }
+
+    /// Sets whether the keys of a dense range are stored in an array, as
+    /// [Builder::set_dense_range] does.  This is on by default.  The keys are
+    /// sorted by key once more to find the range.
+    pub fn set_dense_range(&mut self, dense_range: bool) {
+        self.dense_range = dense_range;
+    }
+
+    /// Inserts this `key`-`value` pair into the map.  As with
+    /// [Builder::insert], a second insert under the same key does not replace
+    /// the value, and `value` must not contain NUL characters.
+    pub fn insert(&mut self, key: u64, value: &str) -> io::Result<()> {
+        assert!(!value.contains('\0'), "value contains NUL: {:?}", value);
+        let order = self.levels.order(key);
+        self.entries
+            .push((order, self.inserts, value.to_string()))?;
+        self.inserts += 1;
+        Ok(())
+    }
+
+    /// Writes the resulting map to `out`.
+    pub fn build<W: Write>(self, mut out: W) -> io::Result<()> {
+        let dir = &self.dir;
+        let run_size = self.run_size;
+
+        // Only the first value inserted under each key is kept.
+        let mut entries = self.entries.finish()?;
+        let mut by_value = Sorter::new(dir, run_size);
+        let mut last = None;
+        while let Some((order, insert, value)) = entries.next()? {
+            if last != Some(order) {
+                by_value.push((value, insert, order))?;
+            }
+            last = Some(order);
+        }
+        drop(entries);
+
+        // Each value is stored where the first insert of it puts it.
+        let mut by_value = by_value.finish()?;
+        let mut values = Sorter::new(dir, run_size);
+        let mut keys = Sorter::new(dir, run_size);
+        let mut first: Option<(String, u64)> = None;
+        while let Some((value, insert, order)) = by_value.next()? {
+            let first_insert = match &first {
+                Some((first_value, first_insert)) if *first_value == value => *first_insert,
+                _ => {
+                    values.push((insert, value.clone()))?;
+                    first = Some((value, insert));
+                    insert
+                }
+            };
+            keys.push((first_insert, order, insert))?;
+        }
+        drop(by_value);
+
+        let mut values = values.finish()?;
+        let mut keys = keys.finish()?;
+        let (strings_file, strings) = TempFile::create(dir)?;
+        let mut strings = BufWriter::new(strings);
+        let mut strings_len = 0;
+        let mut indexes = Sorter::new(dir, run_size);
+        let mut by_key = Sorter::new(dir, run_size);
+        let mut value: Option<(u64, usize)> = None;
+        while let Some((first_insert, order, insert)) = keys.next()? {
+            // Each value has at least one key, so none are skipped.
+            while value.map(|(insert, _)| insert) != Some(first_insert) {
+                let (insert, content) = values.next()?.expect("value for key");
+                strings.write_all(content.as_bytes())?;
+                strings.write_all(&[0])?;
+                value = Some((insert, strings_len));
+                strings_len += content.len() + 1;
+            }
+            let index = value.unwrap().1 as u64;
+            indexes.push((order, index, insert))?;
+            if self.dense_range {
+                by_key.push((self.levels.key(order), index))?;
+            }
+        }
+        drop(keys);
+        drop(values);
+
+        // The keys in the dense range, which are read once more to write them
+        // out.
+        let mut dense = None;
+        let mut by_key = by_key.finish()?;
+        let mut finder = dense::RangeFinder::default();
+        let mut in_range = Sorter::new(dir, run_size);
+        while let Some(record) = by_key.next()? {
+            finder.push(record.0);
+            in_range.push(record)?;
+        }
+        drop(by_key);
+        if let Some(range) = finder.finish() {
+            dense = Some((range, in_range));
+        }
+
+        // The keys in the dense range only count for when tables are created.
+        let mut indexes = indexes.finish()?;
+        let mut tables = Tables::new(&self.levels, self.layout, Sorter::new(dir, run_size));
+        while let Some((order, index, insert)) = indexes.next()? {
+            let key = self.levels.key(order);
+            let index = match &dense {
+                Some((range, _)) if range.contains(&key) => None,
+                _ => Some(index),
+            };
+            tables.add(order, index, insert)?;
+        }
+        drop(indexes);
+        let wide_size = tables.wide_size;
+        let (root_sizes, tables) = tables.finish()?;
+
+        // The same choice of cell formats as in `layout::Trie::write`, where
+        // the offsets of tables and strings are checked for whether they all
+        // fit into compact cells.  The size of each table is a multiple of
+        // the alignment.
+        let mut builder = Builder::with_level_bits(self.levels.bits.clone());
+        builder.clear_tables(dense.is_some());
+        let header_size = builder.index.len();
+        let compact = align(header_size + strings_len) + wide_size < cell::COMPACT_INDEX_LIMIT;
+        let tables_size = root_sizes.map_or(0, |sizes| sizes[compact as usize] as usize);
+        let mut string_offset = header_size + tables_size;
+        if let Some((range, _)) = &dense {
+            builder.extension().dense_offset = string_offset;
+            builder.header().required_features |= header::REQUIRE_DENSE;
+            string_offset += (2 + (range.end() - range.start()) as usize + 1) * size_of::<u64>();
+        }
+        {
+            let root = builder.header();
+            if root_sizes.is_some() {
+                root.set_table_offset(header_size);
+            }
+            root.set_string_offset(string_offset);
+        }
+        out.write_all(&builder.index)?;
+
+        let tables = tables.finish()?;
+        let end = match self.layout {
+            Layout::Insertion => write_insertion(
+                &self.levels,
+                dir,
+                run_size,
+                tables,
+                header_size,
+                compact,
+                &mut out,
+            )?,
+            _ => write_depth_first(&self.levels, tables, header_size, compact, &mut out)?,
+        };
+        assert_eq!(header_size + tables_size, end, "tables out of sync");
+
+        if let Some((range, in_range)) = dense {
+            let mut in_range = in_range.finish()?;
+            let mut words = BufWriter::new(&mut out);
+            let base = *range.start();
+            words.write_all(&base.to_ne_bytes())?;
+            words.write_all(&(range.end() - base + 1).to_ne_bytes())?;
+            // As in `dense::write`, the keys in the range which are not in
+            // the map are absent.
+            let mut next = base;
+            while let Some((key, index)) = in_range.next()? {
+                if range.contains(&key) {
+                    for _ in next..key {
+                        words.write_all(&dense::ABSENT.to_ne_bytes())?;
+                    }
+                    words.write_all(&index.to_ne_bytes())?;
+                    next = key.wrapping_add(1);
+                }
+            }
+            words.flush()?;
+        }
+
+        let mut strings = strings.into_inner().map_err(|error| error.into_error())?;
+        strings.seek(SeekFrom::Start(0))?;
+        io::copy(&mut strings, &mut out)?;
+        drop(strings_file);
+        Ok(())
+    }
+}
+
+// Writes out `tables`, which are in depth first order, to `out` after the
+// header, which takes `header_size` bytes.  Returns the offset after them.
+fn write_depth_first(
+    levels: &Levels,
+    mut tables: Merge<TableRecord>,
+    header_size: usize,
+    compact: bool,
+    out: &mut impl Write,
+) -> io::Result<usize> {
+    let sizes = compact as usize;
+    let mut offset = header_size;
+    let mut table_bytes = vec![];
+    while let Some(table) = tables.next()? {
+        let level = table.level as usize;
+        let node = node(levels.bits[level], &table.cells);
+        let remaining_bits = levels.remaining_bits(level);
+        // The subtries below the table follow it, one after another.
+        let size = node.size_alone(compact, remaining_bits);
+        let mut next = offset + size;
+        let mut offsets = vec![];
+        for (_, cell) in &table.cells {
+            if let CellRecord::Table { sizes: subtrie, .. } = cell {
+                offsets.push(next);
+                next += subtrie[sizes] as usize;
+            }
+        }
+        table_bytes.clear();
+        node.write_alone(&mut table_bytes, &offsets, compact, remaining_bits);
+        out.write_all(&table_bytes)?;
+        offset += size;
+    }
+    Ok(offset)
+}
+
+// Writes out `tables`, which are in the order in which they were created, to
+// `out` after the header, which takes `header_size` bytes.  Returns the offset
+// after them.  The offset of
+// each table is known from the sizes of the tables before it, so a first pass
+// finds the offsets, which are then sorted into the order of the cells that
+// point to the tables, and a second pass writes the tables out.
+fn write_insertion(
+    levels: &Levels,
+    dir: &Path,
+    run_size: usize,
+    mut tables: Merge<TableRecord>,
+    header_size: usize,
+    compact: bool,
+    out: &mut impl Write,
+) -> io::Result<usize> {
+    // The cells which point to tables, as the id of the table pointed to,
+    // the id of the table with the cell, and the number of the cell among
+    // the ones that point to tables.
+    let mut pointers = Sorter::new(dir, run_size);
+    let mut offsets = Sorter::new(dir, run_size);
+    let mut again = Sorter::new(dir, run_size);
+    let mut offset = header_size;
+    while let Some(table) = tables.next()? {
+        let level = table.level as usize;
+        let children = table.cells.iter().filter_map(|(_, cell)| match cell {
+            CellRecord::Table { id, .. } => Some(*id),
+            CellRecord::String { .. } => None,
+        });
+        for (number, child) in children.enumerate() {
+            pointers.push((child, table.rank, number as u64))?;
+        }
+        offsets.push((table.rank, offset as u64))?;
+        offset += node(levels.bits[level], &table.cells)
+            .size_alone(compact, levels.remaining_bits(level));
+        again.push(table)?;
+    }
+    drop(tables);
+
+    // Every table but the root is pointed to by exactly one cell.
+    let mut pointers = pointers.finish()?;
+    let mut offsets = offsets.finish()?;
+    let mut resolved = Sorter::new(dir, run_size);
+    while let Some((child, id, number)) = pointers.next()? {
+        loop {
+            let (table, offset) = offsets.next()?.expect("table pointed to");
+            if table == child {
+                resolved.push((id, number, offset))?;
+                break;
+            }
+        }
+    }
+    drop(pointers);
+    drop(offsets);
+
+    let mut tables = again.finish()?;
+    let mut resolved = resolved.finish()?;
+    let mut offset = header_size;
+    let mut table_bytes = vec![];
+    while let Some(table) = tables.next()? {
+        let level = table.level as usize;
+        let node = node(levels.bits[level], &table.cells);
+        let remaining_bits = levels.remaining_bits(level);
+        let mut offsets = vec![];
+        for (_, cell) in &table.cells {
+            if let CellRecord::Table { .. } = cell {
+                let (id, _, child) = resolved.next()?.expect("offset of table below");
+                assert_eq!(table.rank, id, "tables out of sync");
+                offsets.push(child as usize);
+            }
+        }
+        table_bytes.clear();
+        node.write_alone(&mut table_bytes, &offsets, compact, remaining_bits);
+        out.write_all(&table_bytes)?;
+        offset += node.size_alone(compact, remaining_bits);
+    }
+    Ok(offset)
+}
+
+// Returns the id of the table on `level` which the insert numbered `created`
+// creates.  Ids are in the order in which [Builder] creates tables: by insert,
+// and from the top down within one.  There are far fewer than 2^56 inserts.
+fn table_id(created: u64, level: usize) -> u64 {
+    created << 8 | level as u64
+}
+
+fn align(offset: usize) -> usize {
+    (offset + header::ALIGNMENT - 1) / header::ALIGNMENT * header::ALIGNMENT
+}
+
+// The key bits that each level of the trie uses.
+#[derive(Debug, Clone)]
+struct Levels {
+    bits: Vec<u8>,
+    // The number of key bits used by the levels above each level, and in
+    // total.
+    starts: Vec<u32>,
+}
+
+impl Levels {
+    fn new(bits: Vec<u8>) -> Levels {
+        let mut starts = vec![0];
+        for level_bits in &bits {
+            starts.push(starts.last().unwrap() + *level_bits as u32);
+        }
+        assert_eq!(Some(&64), starts.last(), "bits: {:?}", bits);
+        Levels { bits, starts }
+    }
+
+    fn mask(bits: u32) -> u64 {
+        1_u64.checked_shl(bits).unwrap_or(0).wrapping_sub(1)
+    }
+
+    // Returns the cell of the table on `level` that `key` falls into.
+    fn slot(&self, key: u64, level: usize) -> usize {
+        ((key >> self.starts[level]) & Levels::mask(self.bits[level] as u32)) as usize
+    }
+
+    // Returns `key` with the bits of the levels in reverse order, the root
+    // level in the top bits.  Keys in this order form are ordered as in a
+    // depth first traversal of the trie.
+    fn order(&self, key: u64) -> u64 {
+        (0..self.bits.len()).fold(0, |order, level| {
+            order << self.bits[level] | self.slot(key, level) as u64
+        })
+    }
+
+    // Returns the key of `order`, which is in order form.
+    fn key(&self, mut order: u64) -> u64 {
+        let mut key = 0;
+        for level in (0..self.bits.len()).rev() {
+            key |= (order & Levels::mask(self.bits[level] as u32)) << self.starts[level];
+            order >>= self.bits[level];
+        }
+        key
+    }
+
+    // Returns the first level on which the distinct keys `a` and `b`, in order
+    // form, fall into different cells.
+    fn split_level(&self, a: u64, b: u64) -> usize {
+        let same = (a ^ b).leading_zeros();
+        self.starts[1..]
+            .iter()
+            .position(|end| *end > same)
+            .expect("distinct keys")
+    }
+
+    // Returns the bits of `order` which the levels above `level` use, and
+    // zeros in place of the others.
+    fn prefix(&self, order: u64, level: usize) -> u64 {
+        order & !(u64::MAX.checked_shr(self.starts[level]).unwrap_or(0))
+    }
+
+    // Returns the number of key bits not used by the levels up to and
+    // including `level`.
+    fn remaining_bits(&self, level: usize) -> usize {
+        64 - self.starts[level + 1] as usize
+    }
+}
+
+// A complete table of the trie.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TableRecord {
+    // The table's place in the layout, and its level.  With
+    // [Layout::DepthFirst], this is the key bits of the levels above it, in
+    // order form, and with [Layout::Insertion], the id of the table.
+    rank: u64,
+    level: u8,
+    // The occupied cells, ordered by cell index.
+    cells: Vec<(usize, CellRecord)>,
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum CellRecord {
+    String {
+        index: u64,
+        key: u64,
+    },
+    // A table below, reached by skipping over the next `bits` key bits,
+    // which must be equal to `pattern`.  `sizes` holds the number of bytes
+    // that the subtrie rooted at the table takes up, without and with
+    // compact cells, and `id` is the table's id.
+    Table {
+        bits: u8,
+        pattern: u64,
+        sizes: [u64; 2],
+        id: u64,
+    },
+}
+
+// Returns the table with `bits` bits and `cells`, in which the tables that
+// the cells point to are numbered in cell order.
+fn node(bits: u8, cells: &[(usize, CellRecord)]) -> layout::Node {
+    let mut tables = 0;
+    let cells = cells
+        .iter()
+        .map(|(slot, cell)| {
+            let child = match *cell {
+                CellRecord::String { index, key } => layout::Child::String {
+                    index: index as usize,
+                    key,
+                },
+                CellRecord::Table { bits, pattern, .. } => {
+                    tables += 1;
+                    match bits {
+                        0 => layout::Child::Table(tables - 1),
+                        _ => layout::Child::Skip {
+                            bits,
+                            pattern,
+                            table: tables - 1,
+                        },
+                    }
+                }
+            };
+            (*slot, child)
+        })
+        .collect();
+    layout::Node::new(bits, cells)
+}
+
+// What goes into the cell for the last key in the deepest open table.
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    // The index of the key's value.
+    String(u64),
+    // A complete table on the given level, with the sizes of its subtrie,
+    // and its id.
+    Table(usize, [u64; 2], u64),
+}
+
+// A table which may still get more cells.
+#[derive(Debug)]
+struct Open {
+    level: usize,
+    cells: Vec<(usize, CellRecord)>,
+    // The first two inserts of keys below the table, once all keys below it
+    // are known.
+    inserts: Option<[u64; 2]>,
+}
+
+impl Open {
+    fn new(level: usize) -> Open {
+        Open {
+            level,
+            cells: vec![],
+            inserts: None,
+        }
+    }
+}
+
+// Reconstructs the tables of the trie from its keys, in order form, given in
+// order.  These are the tables that [Builder] creates, with chains of tables
+// that have a single occupied cell each replaced by skip cells: a table is on
+// the first level at which the keys below its cell in the table above fall
+// into different cells, and the root table is on level zero.  Keys without a
+// cell are only taken into account for when tables are created.
+struct Tables<'a> {
+    levels: &'a Levels,
+    layout: Layout,
+    // The tables on the path to the last key with a cell, from the root.
+    open: Vec<Open>,
+    // The last key with a cell and what goes into its cell.
+    last: Option<(u64, Pending)>,
+    // For each level, the first two inserts of the keys so far which share
+    // the key bits of the levels above it with the last key, or `u64::MAX`
+    // while there are fewer.
+    inserts: Vec<[u64; 2]>,
+    // The last key.
+    last_key: Option<u64>,
+    complete: Sorter<TableRecord>,
+    // The total size of the complete tables, with wide cells.
+    wide_size: usize,
+}
+
+impl<'a> Tables<'a> {
+    fn new(levels: &'a Levels, layout: Layout, complete: Sorter<TableRecord>) -> Tables<'a> {
+        Tables {
+            levels,
+            layout,
+            open: vec![],
+            last: None,
+            inserts: vec![[u64::MAX; 2]; levels.bits.len()],
+            last_key: None,
+            complete,
+            wide_size: 0,
+        }
+    }
+
+    // Adds the key `order`, which was first inserted by the insert numbered
+    // `insert`, with the index of its value, or without a cell.
+    fn add(&mut self, order: u64, index: Option<u64>, insert: u64) -> io::Result<()> {
+        // The keys which share the bits of the levels above the level on
+        // which the keys split up, and below, are all known.
+        if let Some(last_key) = self.last_key {
+            let level = self.levels.split_level(last_key, order);
+            for level in level + 1..self.inserts.len() {
+                self.end_inserts(level);
+            }
+        }
+        self.last_key = Some(order);
+        for inserts in &mut self.inserts {
+            if insert < inserts[0] {
+                *inserts = [insert, inserts[0]];
+            } else if insert < inserts[1] {
+                inserts[1] = insert;
+            }
+        }
+        let index = match index {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        match self.last {
+            None => self.open.push(Open::new(0)),
+            Some((last_order, mut pending)) => {
+                // The tables below the level on which the keys split up are
+                // complete.  If there is no table on that level yet, the keys
+                // so far did not split up there, and it goes in between.
+                let level = self.levels.split_level(last_order, order);
+                loop {
+                    let mut open = self.open.pop().expect("root table");
+                    if open.level < level {
+                        self.open.push(open);
+                        self.open.push(Open::new(level));
+                        continue;
+                    }
+                    self.set_cell(&mut open, last_order, pending);
+                    if open.level == level {
+                        self.open.push(open);
+                        break;
+                    }
+                    pending = self.complete(open, last_order)?;
+                }
+            }
+        }
+        self.last = Some((order, Pending::String(index)));
+        Ok(())
+    }
+
+    // Gives the open table on `level`, if there is one, the inserts of the
+    // keys that share the bits of the levels above it, unless it already has
+    // them, and starts over for the next keys.
+    fn end_inserts(&mut self, level: usize) {
+        let inserts = std::mem::replace(&mut self.inserts[level], [u64::MAX; 2]);
+        if let Some(open) = self.open.iter_mut().find(|open| open.level == level) {
+            open.inserts.get_or_insert(inserts);
+        }
+    }
+
+    // Completes all tables.  Returns the sizes of the whole trie, unless it
+    // is empty, and the complete tables.
+    fn finish(mut self) -> io::Result<(Option<[u64; 2]>, Sorter<TableRecord>)> {
+        let mut sizes = None;
+        for level in 0..self.inserts.len() {
+            self.end_inserts(level);
+        }
+        if let Some((order, mut pending)) = self.last.take() {
+            while let Some(mut open) = self.open.pop() {
+                self.set_cell(&mut open, order, pending);
+                pending = self.complete(open, order)?;
+            }
+            if let Pending::Table(_, root_sizes, ..) = pending {
+                sizes = Some(root_sizes);
+            }
+        }
+        Ok((sizes, self.complete))
+    }
+
+    // Sets the cell for the key `order` in `open` to `pending`.
+    fn set_cell(&self, open: &mut Open, order: u64, pending: Pending) {
+        let key = self.levels.key(order);
+        let cell = match pending {
+            Pending::String(index) => CellRecord::String { index, key },
+            Pending::Table(level, sizes, id) => {
+                let start = self.levels.starts[open.level + 1];
+                let bits = self.levels.starts[level] - start;
+                CellRecord::Table {
+                    bits: bits as u8,
+                    pattern: (key >> start) & Levels::mask(bits),
+                    sizes,
+                    id,
+                }
+            }
+        };
+        open.cells.push((self.levels.slot(key, open.level), cell));
+    }
+
+    // Completes the table `open`, which the key `order` is below.
+    fn complete(&mut self, open: Open, order: u64) -> io::Result<Pending> {
+        let node = node(self.levels.bits[open.level], &open.cells);
+        let remaining_bits = self.levels.remaining_bits(open.level);
+        let wide = node.size_alone(false, remaining_bits);
+        let mut sizes = [wide as u64, node.size_alone(true, remaining_bits) as u64];
+        for (_, cell) in &open.cells {
+            if let CellRecord::Table { sizes: subtrie, .. } = cell {
+                sizes[0] += subtrie[0];
+                sizes[1] += subtrie[1];
+            }
+        }
+        self.wide_size += wide;
+        // The root table is created by the first insert, and any other table
+        // by the insert of the second key below it.
+        let inserts = open.inserts.expect("all keys below known");
+        let created = inserts[(open.level > 0) as usize];
+        let id = table_id(created, open.level);
+        let rank = match self.layout {
+            Layout::Insertion => id,
+            _ => self.levels.prefix(order, open.level),
+        };
+        self.complete.push(TableRecord {
+            rank,
+            level: open.level as u8,
+            cells: open.cells,
+        })?;
+        Ok(Pending::Table(open.level, sizes, id))
+    }
+}
+
+// A temporary file, which is removed once this is dropped.
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    // Creates a new temporary file in `dir`, opened for reading and writing.
+    fn create(dir: &Path) -> io::Result<(TempFile, fs::File)> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let path = dir.join(format!(
+                "sequence-map-{}-{}.tmp",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => return Ok((TempFile { path }, file)),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// A record which can be written to a temporary file, and read back.
+trait Record: Ord + Sized {
+    // Returns about the number of bytes of memory that the record takes up.
+    fn size(&self) -> usize;
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()>;
+    // Returns `None` at the end of `input`.
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>>;
+}
+
+fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// Reads the first field of a record, or returns `None` at the end of `input`.
+fn read_first_u64(input: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut bytes = [0; 8];
+    let mut len = 0;
+    while len < bytes.len() {
+        match input.read(&mut bytes[len..])? {
+            0 if len == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => len += read,
+        }
+    }
+    Ok(Some(u64::from_le_bytes(bytes)))
+}
+
+fn write_string(out: &mut impl Write, value: &str) -> io::Result<()> {
+    write_u64(out, value.len() as u64)?;
+    out.write_all(value.as_bytes())
+}
+
+fn read_string(input: &mut impl Read, len: u64) -> io::Result<String> {
+    let mut bytes = vec![0; len as usize];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+impl Record for (u64, u64) {
+    fn size(&self) -> usize {
+        size_of::<Self>()
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u64(out, self.0)?;
+        write_u64(out, self.1)
+    }
+
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        Ok(match read_first_u64(input)? {
+            None => None,
+            Some(first) => Some((first, read_u64(input)?)),
+        })
+    }
+}
+
+impl Record for (u64, u64, u64) {
+    fn size(&self) -> usize {
+        size_of::<Self>()
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u64(out, self.0)?;
+        write_u64(out, self.1)?;
+        write_u64(out, self.2)
+    }
+
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        Ok(match read_first_u64(input)? {
+            None => None,
+            Some(first) => Some((first, read_u64(input)?, read_u64(input)?)),
+        })
+    }
+}
+
+impl Record for (u64, String) {
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.1.len()
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u64(out, self.0)?;
+        write_string(out, &self.1)
+    }
+
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        Ok(match read_first_u64(input)? {
+            None => None,
+            Some(first) => {
+                let len = read_u64(input)?;
+                Some((first, read_string(input, len)?))
+            }
+        })
+    }
+}
+
+impl Record for (u64, u64, String) {
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.2.len()
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u64(out, self.0)?;
+        write_u64(out, self.1)?;
+        write_string(out, &self.2)
+    }
+
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        Ok(match read_first_u64(input)? {
+            None => None,
+            Some(first) => {
+                let second = read_u64(input)?;
+                let len = read_u64(input)?;
+                Some((first, second, read_string(input, len)?))
+            }
+        })
+    }
+}
+
+impl Record for (String, u64, u64) {
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.0.len()
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        write_string(out, &self.0)?;
+        write_u64(out, self.1)?;
+        write_u64(out, self.2)
+    }
+
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        Ok(match read_first_u64(input)? {
+            None => None,
+            Some(len) => Some((read_string(input, len)?, read_u64(input)?, read_u64(input)?)),
+        })
+    }
+}
+
+impl Record for TableRecord {
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.cells.len() * size_of::<(usize, CellRecord)>()
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u64(out, self.rank)?;
+        write_u64(out, self.level as u64)?;
+        write_u64(out, self.cells.len() as u64)?;
+        for (slot, cell) in &self.cells {
+            write_u64(out, *slot as u64)?;
+            match *cell {
+                CellRecord::String { index, key } => {
+                    write_u64(out, u64::MAX)?;
+                    write_u64(out, index)?;
+                    write_u64(out, key)?;
+                }
+                CellRecord::Table {
+                    bits,
+                    pattern,
+                    sizes,
+                    id,
+                } => {
+                    write_u64(out, bits as u64)?;
+                    write_u64(out, pattern)?;
+                    write_u64(out, sizes[0])?;
+                    write_u64(out, sizes[1])?;
+                    write_u64(out, id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_from(input: &mut impl Read) -> io::Result<Option<Self>> {
+        let rank = match read_first_u64(input)? {
+            None => return Ok(None),
+            Some(rank) => rank,
+        };
+        let level = read_u64(input)? as u8;
+        let len = read_u64(input)?;
+        let mut cells = vec![];
+        for _ in 0..len {
+            let slot = read_u64(input)? as usize;
+            let cell = match read_u64(input)? {
+                u64::MAX => CellRecord::String {
+                    index: read_u64(input)?,
+                    key: read_u64(input)?,
+                },
+                bits => CellRecord::Table {
+                    bits: bits as u8,
+                    pattern: read_u64(input)?,
+                    sizes: [read_u64(input)?, read_u64(input)?],
+                    id: read_u64(input)?,
+                },
+            };
+            cells.push((slot, cell));
+        }
+        Ok(Some(TableRecord { rank, level, cells }))
+    }
+}
+
+// Sorts records, with about `run_size` bytes of them in memory at a time.
+// Whenever there are more, they are sorted and written out to a temporary
+// file as a run.
+#[derive(Debug)]
+struct Sorter<T> {
+    dir: PathBuf,
+    run_size: usize,
+    records: Vec<T>,
+    size: usize,
+    runs: Vec<(TempFile, fs::File)>,
+}
+
+impl<T: Record> Sorter<T> {
+    fn new(dir: &Path, run_size: usize) -> Sorter<T> {
+        Sorter {
+            dir: dir.to_path_buf(),
+            run_size,
+            records: vec![],
+            size: 0,
+            runs: vec![],
+        }
+    }
+
+    fn push(&mut self, record: T) -> io::Result<()> {
+        self.size += record.size();
+        self.records.push(record);
+        if self.size >= self.run_size {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self) -> io::Result<()> {
+        self.records.sort_unstable();
+        let (temp_file, file) = TempFile::create(&self.dir)?;
+        let mut out = BufWriter::new(file);
+        for record in self.records.drain(..) {
+            record.write_to(&mut out)?;
+        }
+        let mut file = out.into_inner().map_err(|error| error.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        self.runs.push((temp_file, file));
+        self.size = 0;
+        Ok(())
+    }
+
+    // Returns all records pushed, in order.
+    fn finish(mut self) -> io::Result<Merge<T>> {
+        self.records.sort_unstable();
+        let mut merge = Merge {
+            records: self.records.into_iter(),
+            runs: self
+                .runs
+                .into_iter()
+                .map(|(temp_file, file)| (temp_file, BufReader::new(file)))
+                .collect(),
+            heads: BinaryHeap::new(),
+        };
+        for source in 0..=merge.runs.len() {
+            merge.read(source)?;
+        }
+        Ok(merge)
+    }
+}
+
+// Merges the runs of a [Sorter], and the records which remained in memory.
+struct Merge<T> {
+    records: std::vec::IntoIter<T>,
+    runs: Vec<(TempFile, BufReader<fs::File>)>,
+    // The next record of each source which has any left: the records in
+    // memory are source zero, and each run is the source after the one
+    // before it.
+    heads: BinaryHeap<Reverse<(T, usize)>>,
+}
+
+impl<T: Record> Merge<T> {
+    fn next(&mut self) -> io::Result<Option<T>> {
+        match self.heads.pop() {
+            None => Ok(None),
+            Some(Reverse((record, source))) => {
+                self.read(source)?;
+                Ok(Some(record))
+            }
+        }
+    }
+
+    // Reads the next record of `source`, if it has any left.
+    fn read(&mut self, source: usize) -> io::Result<()> {
+        let record = match source {
+            0 => self.records.next(),
+            _ => T::read_from(&mut self.runs[source - 1].1)?,
+        };
+        if let Some(record) = record {
+            self.heads.push(Reverse((record, source)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Map;
+
+    // Returns an empty directory for temporary files, for the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sequence-map-external-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries() -> Vec<(u64, String)> {
+        let mut entries = vec![];
+        let mut key: u64 = 0x1234_5678;
+        for entry in 0..2000_u64 {
+            // A xorshift generator, for keys all over the key space.
+            key ^= key << 13;
+            key ^= key >> 7;
+            key ^= key << 17;
+            entries.push((key, format!("entry_{}", entry % 300)));
+            entries.push((entry, format!("entry_{}", entry)));
+            // Keys which share all but their top bits.
+            entries.push((entry << 52 | 7, format!("top_{}", entry % 7)));
+        }
+        // Only the first value inserted under a key is kept.
+        entries.push((42, "Again!".to_string()));
+        entries.push((1, "entry_0".to_string()));
+        entries
+    }
+
+    #[test]
+    fn same_as_builder() {
+        let dir = temp_dir("same_as_builder");
+        let entries = entries();
+        // Both builders as configured by default, with the other layout, and
+        // without dense ranges.
+        let configs = [
+            (None, true),
+            (Some(Layout::DepthFirst), true),
+            (None, false),
+        ];
+        for schedule in &[&[4][..], &[16, 4], &[2], &[3, 5, 7]] {
+            for (layout, dense_range) in &configs {
+                for len in &[0, 1, 2, entries.len()] {
+                    // With many small runs, and with a single one in memory.
+                    for run_size in &[1000, DEFAULT_RUN_SIZE] {
+                        let mut builder = Builder::with_schedule(schedule);
+                        let mut external = ExternalBuilder::with_schedule(schedule, &dir);
+                        if let Some(layout) = layout {
+                            builder.set_layout(*layout);
+                            external.set_layout(*layout);
+                        }
+                        if !dense_range {
+                            builder.set_dense_range(false);
+                            external.set_dense_range(false);
+                        }
+                        external.set_run_size(*run_size);
+                        for (key, value) in &entries[..*len] {
+                            builder.insert(*key, value);
+                            external.insert(*key, value).unwrap();
+                        }
+                        let mut bytes = vec![];
+                        external.build(&mut bytes).unwrap();
+                        assert!(
+                            builder.build() == bytes,
+                            "schedule: {:?}, layout: {:?}, dense_range: {}, len: {}, \
+                             run_size: {}",
+                            schedule,
+                            layout,
+                            dense_range,
+                            len,
+                            run_size
+                        );
+                        if *len == entries.len() {
+                            let lookup = Map::new(&bytes);
+                            assert_eq!(*dense_range, lookup.dense.is_some());
+                            assert_eq!("entry_42", lookup.get(42).unwrap());
+                            assert_eq!("entry_1", lookup.get(1).unwrap());
+                        }
+                    }
+                }
+            }
+        }
+        // All temporary files are gone.
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn order() {
+        let levels = Levels::new(vec![3, 5, 16, 16, 16, 8]);
+        for key in &[0, 1, 0b1000, 0x1234_5678_9abc_def0, u64::MAX] {
+            assert_eq!(*key, levels.key(levels.order(*key)));
+        }
+        // The root cell is in the top bits.
+        assert_eq!(1 << 61, levels.order(1));
+        assert_eq!(1 << 56, levels.order(1 << 3));
+        assert_eq!(0, levels.split_level(levels.order(0), levels.order(1)));
+        assert_eq!(1, levels.split_level(levels.order(0), levels.order(1 << 3)));
+        assert_eq!(
+            5,
+            levels.split_level(levels.order(0), levels.order(1 << 63))
+        );
+        assert_eq!(0, levels.prefix(u64::MAX, 0));
+        assert_eq!(0b111 << 61, levels.prefix(u64::MAX, 1));
+        assert_eq!(u64::MAX << 8, levels.prefix(u64::MAX, 5));
+    }
+
+    #[test]
+    fn sorter() {
+        let dir = temp_dir("sorter");
+        let mut sorter = Sorter::new(&dir, 100);
+        let mut expected = vec![];
+        for value in 0..1000_u64 {
+            let record = (value * 7919 % 1009, format!("{}", value));
+            expected.push(record.clone());
+            sorter.push(record).unwrap();
+        }
+        expected.sort();
+        assert!(sorter.runs.len() > 10);
+        let mut merge = sorter.finish().unwrap();
+        let mut sorted = vec![];
+        while let Some(record) = merge.next().unwrap() {
+            sorted.push(record);
+        }
+        assert_eq!(expected, sorted);
+        drop(merge);
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
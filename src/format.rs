@@ -0,0 +1,277 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The byte format of a map, as produced by [Builder::build](crate::Builder::build).
+//!
+//! This module only holds documentation.  [reference](crate::reference) is a
+//! reader which is written from this description alone.
+//!
+//! # Conventions
+//!
+//! All integers are unsigned, and in the byte order of the machine that
+//! built the map.  Offsets are 64 bits wide.  All offsets are relative to
+//! the start of the buffer, which is aligned to 8 bytes, except for string
+//! offsets, which are relative to the start of the string section.  Bytes
+//! marked as padding are zero.
+//!
+//! # Root
+//!
+//! The buffer starts with a 56 byte root header:
+//!
+//! | Offset | Size | Field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | type, always 1                                     |
+//! | 4      | 1    | string format: 0 plain, 1 symbols, 2 blocks        |
+//! | 5      | 1    | the number of extension words                      |
+//! | 6      | 1    | index kind: 0 trie, 1 perfect hash                 |
+//! | 7      | 1    | required features                                  |
+//! | 8      | 8    | offset of the root table, or 0 if the map is empty |
+//! | 16     | 8    | offset of the string section                       |
+//! | 24     | 32   | bits per trie level                                |
+//!
+//! The bits per trie level are one byte each, starting with the root table's
+//! level.  They are followed by zero bytes if there are fewer than 32 levels.
+//! They add up to at least 64.  Maps built by this version add up to exactly
+//! 64, as the deepest level only has the bits that are left.  They record how
+//! the map was built.  A reader does not need them, since each table has its
+//! own width.
+//!
+//! The required features are bits, each of which is set if the map uses a
+//! feature that a reader must know of to find all of its keys.  Bit 0 is set
+//! if the map has a dense segment.  The other bits are zero.  A reader
+//! rejects a map in which a bit is set that it does not know.
+//!
+//! In maps with the perfect hash index kind, the offset of the root table is
+//! that of the perfect hash index instead, and there are no tables.
+//!
+//! # Extension
+//!
+//! The root header is followed by the number of 8 byte extension words that
+//! it gives, which hold the fields of optional sections:
+//!
+//! | Word | Field                                                |
+//! |------|------------------------------------------------------|
+//! | 0    | offset of the reverse index, or 0 if there is none   |
+//! | 1    | offset of the dense segment, or 0 if there is none   |
+//!
+//! A map may have fewer words than there are fields, in which case the
+//! missing fields are 0.  Readers ignore the words which they do not know,
+//! so new words may only be added for sections which a reader can do without,
+//! unless a required feature bit is set for them.  The dense segment is one
+//! which needs such a bit.
+//! Maps without any optional sections have no extension words.
+//!
+//! # Tables
+//!
+//! Tables start at offsets aligned to 8 bytes.  Every table begins with a
+//! 16 byte header:
+//!
+//! | Offset | Size | Field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | type: 2 for dense, 4 for sparse                    |
+//! | 4      | 4    | padding                                            |
+//! | 8      | 1    | `bits`, the number of key bits it indexes: 1 to 16 |
+//! | 9      | 1    | cell format: 0 for wide, 1 for compact             |
+//! | 10     | 6    | padding                                            |
+//!
+//! A table has `2^bits` slots.  A dense table follows its header with one
+//! cell per slot, in slot order.
+//!
+//! A sparse table follows its header with `ceil(2^bits / 32)` occupancy
+//! words of 8 bytes each.  Word `w` covers slots `32 * w` to `32 * w + 31`:
+//!
+//! | Offset | Size | Field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 4    | rank: the number of occupied slots in all earlier words  |
+//! | 4      | 4    | bitmap: bit `i` is set if slot `32 * w + i` is occupied  |
+//!
+//! The words are followed by one cell per occupied slot, in slot order.  So
+//! the cell of an occupied slot `s` is number `rank + popcount(bitmap & ((1 <<
+//! (s % 32)) - 1))` of the word `s / 32`.  Unoccupied slots are empty.
+//!
+//! # Cells
+//!
+//! A wide cell is 16 bytes:
+//!
+//! | Offset | Size | Field                                           |
+//! |--------|------|-------------------------------------------------|
+//! | 0      | 8    | `tag` in the low 3 bits, and `index` above them |
+//! | 8      | 8    | `extra`                                         |
+//!
+//! A compact cell is 8 bytes, with the same fields 4 bytes wide each.
+//!
+//! The `tag` is one of:
+//!
+//! * 0, empty: the slot holds nothing.  The other fields are zero.
+//! * 1, string: the slot holds a key.  `index` is the offset of its value in
+//!   the string section.  In a wide cell, `extra` is the complete key.  In a
+//!   compact cell, it is the key shifted right by the number of key bits that
+//!   the tables up to and including this one index.  If that number is 64 or
+//!   more, it is zero.
+//! * 2, table: `index` is the offset of the table one level below.
+//! * 3, skip: `index` is the offset of a table several levels below.
+//!   `extra` holds `n`, the number of key bits between this table and that
+//!   one, in the position of its highest set bit.  The `n` bits below it are
+//!   the values that those key bits must have.
+//!
+//! # Lookup
+//!
+//! If the map requires the dense segment feature, and the key is in the range
+//! of its dense segment, the key is
+//! looked up there, and not in the tables.  Otherwise, a key is looked up
+//! starting at the root table, with all 64 key bits remaining, and with the
+//! running key equal to the key:
+//!
+//! 1. If no key bits remain, the key is not in the map.
+//! 2. The slot is the low `bits` bits of the running key.  The running key
+//!    is shifted right by `bits`, and `bits` is subtracted from the remaining
+//!    bits, down to zero.
+//! 3. The cell of the slot determines what happens:
+//!    * Empty: the key is not in the map.
+//!    * String: the key is in the map if the wide cell's `extra` equals the
+//!      key, or if the compact cell's `extra` equals the running key.
+//!    * Table: the lookup continues at step 1 with the table at `index`.
+//!    * Skip: the key is not in the map unless the low `n` bits of the
+//!      running key equal the `n` bits of `extra`.  Otherwise the running key
+//!      is shifted right by `n`, `n` is subtracted from the remaining bits,
+//!      and the lookup continues at step 1 with the table at `index`.
+//!
+//! # Dense segment
+//!
+//! The dense segment holds the keys of a range, in which most keys are in the
+//! map.  It lies between the tables and the string section, at an offset
+//! aligned to 8 bytes, and is made of 8 byte words:
+//!
+//! | Words   | Field                                                       |
+//! |---------|-------------------------------------------------------------|
+//! | 1       | `base`, the first key of the range                          |
+//! | 1       | `n`, the number of keys in the range: at least 1            |
+//! | `n`     | for each key, the index of its value, as in a cell          |
+//!
+//! Key `k` is in the range if `k - base`, wrapping around, is less than `n`.
+//! Its value is at the index in word `k - base`, unless all the bits of that
+//! word are set, in which case the key is not in the map.  The keys in the
+//! range are not in the tables, or in the perfect hash index.
+//!
+//! # Perfect hash index
+//!
+//! The perfect hash index maps each key to a slot of its own, and has as many
+//! slots as keys.  Keys are first hashed to one of `m` positions, where `m`
+//! is at least the number of keys `n`, and usually about 1% more.  The
+//! positions below `n` are the slots, and the positions from `n` on are
+//! mapped to the slots which no key is hashed to.  It starts at an offset
+//! aligned to 8 bytes:
+//!
+//! | Size             | Field                                          |
+//! |------------------|------------------------------------------------|
+//! | 8                | `seed`                                         |
+//! | 8                | `b`, the number of buckets: at least 1         |
+//! | 8                | `m`, the number of positions: at least `n`     |
+//! | 8                | `n`, the number of slots: at least 1           |
+//! | 4 * `b`          | the pilot of each bucket                       |
+//! | 4 * (`b` mod 2)  | padding                                        |
+//! | 8 * (`m` - `n`)  | the slot of each position from `n` on          |
+//! | 16 * `n`         | the slots                                      |
+//!
+//! Each slot holds a key, 8 bytes, followed by the index of its value, 8
+//! bytes, as in a cell.  With `mix(x)` defined as the finalizer of
+//! MurmurHash3 on 64 bits:
+//!
+//! ```text
+//! x ^= x >> 33; x *= 0xff51afd7ed558ccd;
+//! x ^= x >> 33; x *= 0xc4ceb9fe1a85ec53;
+//! x ^= x >> 33;
+//! ```
+//!
+//! and `reduce(x, n)` as the high 64 bits of the 128 bit product `x * n`, a
+//! key is looked up as follows:
+//!
+//! 1. `h = mix(key ^ seed)`.
+//! 2. `p` is the pilot of bucket `reduce(h, b)`.
+//! 3. The position is `reduce(mix(h ^ mix(p)), m)`.  The slot is the
+//!    position if it is below `n`, and otherwise the slot given for the
+//!    position.
+//! 4. The key is in the map if the slot holds it.
+//!
+//! # Strings
+//!
+//! The string section starts at the string offset and extends to the end of
+//! the buffer.  Equal values are stored once and shared between keys.
+//!
+//! In the plain string format, it holds UTF-8 strings, each followed by a
+//! zero byte.  A value which is the end of another value may be stored within
+//! it, so an index may point into the middle of a string.
+//!
+//! In the symbols string format, it starts with a symbol table:
+//!
+//! | Size       | Field                                          |
+//! |------------|------------------------------------------------|
+//! | 1          | `n`, the number of symbols: 0 to 255           |
+//! | `n`        | the length of each symbol: 1 to 8              |
+//! | the total  | the bytes of each symbol, one after the other  |
+//!
+//! The table is followed by the values.  Each value is a number `len`, as
+//! unsigned LEB128: seven bits per byte, least significant first, with the
+//! high bit set on all but the last byte.  It is followed by `len` bytes of
+//! codes, which are decoded one after the other.  A code below `n` stands
+//! for the bytes of that symbol.  The code 255 stands for the byte after
+//! it.  The decoded bytes are the UTF-8 value, without a zero byte.
+//!
+//! In the blocks string format, it starts with a block table:
+//!
+//! | Size          | Field                                        |
+//! |---------------|----------------------------------------------|
+//! | 4             | `m`, the number of blocks                    |
+//! | 4             | `b`, the number of offset bits: 1 to 32      |
+//! | 8 * (`m` + 1) | block offsets, relative to the string offset |
+//!
+//! Block `i` extends from block offset `i` up to block offset `i + 1`.  It holds a
+//! compressed sequence of UTF-8 strings, each followed by a zero byte, as in
+//! the plain string format.  The index of a value in a cell is made of the
+//! number of its block, shifted left by `b`, and the offset of the value in
+//! the decompressed block, in the low `b` bits.
+//!
+//! A block starts with the length of the decompressed bytes, as unsigned
+//! LEB128.  It is followed by sequences, each of which appends literal bytes,
+//! and then copies bytes which were decompressed before:
+//!
+//! 1. A token byte.  The high 4 bits are the number of literals, and the low
+//!    4 bits are the length of the copy, minus 4.  If either is 15, it is
+//!    continued: each following byte is added to it, up to and including the
+//!    first byte which is not 255.
+//! 2. The continuation of the number of literals, if any.
+//! 3. The literals.
+//! 4. If this completes the decompressed bytes, the block ends here.
+//! 5. The distance back to the bytes to copy, 2 bytes little endian, from 1
+//!    up to the number of bytes decompressed so far.
+//! 6. The continuation of the length of the copy, if any.  The copy is made
+//!    one byte at a time, so it may repeat bytes which it appends itself.
+//!
+//! # Reverse index
+//!
+//! The reverse index lists the keys which map to each value.  It lies
+//! between the tables and the string section, at an offset aligned to 8
+//! bytes, and is made of 8 byte words:
+//!
+//! | Words     | Field                                                     |
+//! |-----------|-----------------------------------------------------------|
+//! | 1         | `n`, the number of distinct values                        |
+//! | `n`       | the index of each value, as in a cell                     |
+//! | `n` + 1   | the position of the first key of each value, and then `k` |
+//! | `k`       | the keys                                                  |
+//!
+//! The values are in the byte order of their UTF-8 contents, so a value can
+//! be found by binary search.  The keys which map to value `i` are the ones
+//! from its position up to the position of value `i + 1`, in ascending
+//! order.
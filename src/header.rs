@@ -20,7 +20,25 @@ use zerocopy::LayoutVerified;
 
 pub type TypeSize = u32;
 
-#[derive(Debug, Eq, PartialEq)]
+/// The alignment, in bytes, required of the start of a map buffer, and of each
+/// table within it.
+pub const ALIGNMENT: usize = 8;
+
+/// The maximum number of trie levels.  Every level consumes at least 2 bits of
+/// a 64 bit key.
+pub const MAX_LEVELS: usize = 32;
+
+/// The maximum number of key bits that a single table indexes.
+pub const MAX_TABLE_BITS: usize = 16;
+
+/// The bit of [Root::required_features] which says that the map has a dense
+/// segment.  Readers which do not know of it would miss the keys in it.
+pub const REQUIRE_DENSE: u8 = 1;
+
+/// All the bits of [Root::required_features] which this version supports.
+pub const KNOWN_FEATURES: u8 = REQUIRE_DENSE;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[allow(dead_code)] // We want a zero value to be defined.
 pub enum Type {
     // The table is empty.  This is not explicitly used, but is a consequence
@@ -29,6 +47,8 @@ pub enum Type {
     Root = 1,
     Table = 2,
     String = 3,
+    // A table which stores only its occupied cells.
+    SparseTable = 4,
     Unknown = 255,
 }
 
@@ -46,6 +66,9 @@ impl From<TypeSize> for Type {
         if val == Type::String as TypeSize {
             return Type::String;
         }
+        if val == Type::SparseTable as TypeSize {
+            return Type::SparseTable;
+        }
         Type::Unknown
     }
 }
@@ -54,15 +77,42 @@ impl From<TypeSize> for Type {
 #[repr(C)]
 pub struct Root {
     pub htype: TypeSize,
-    pad0: [u8; 4],
+    // One of Compression, as a number.
+    pub string_format: u8,
+    // The number of 8 byte words of the Extension which follows this header.
+    // Maps which use none of its fields have none.
+    pub extension_words: u8,
+    // One of IndexKind, as a number.
+    pub index_kind: u8,
+    // The features which a reader must support to read the map, as a set of
+    // the REQUIRE_ bits.
+    pub required_features: u8,
     pub root_table_offset: usize,
     pub string_offset: usize,
+    // The number of bits used at each trie level that the map was built with.
+    // Unused levels are set to zero.
+    pub level_bits: [u8; MAX_LEVELS],
 }
 
 impl Root {
     pub fn set_type(&mut self, t: Type) {
         self.htype = t as TypeSize;
     }
+    pub fn set_level_bits(&mut self, level_bits: &[u8]) {
+        assert!(level_bits.len() <= MAX_LEVELS);
+        self.level_bits = [0; MAX_LEVELS];
+        self.level_bits[..level_bits.len()].copy_from_slice(level_bits);
+    }
+
+    pub fn level_bits(&self) -> &[u8] {
+        let len = self
+            .level_bits
+            .iter()
+            .position(|bits| *bits == 0)
+            .unwrap_or(MAX_LEVELS);
+        &self.level_bits[..len]
+    }
+
     pub fn set_table_offset(&mut self, offset: usize) {
         self.root_table_offset = offset;
     }
@@ -72,6 +122,33 @@ impl Root {
     }
 }
 
+/// The optional fields of a map, which follow its root header.  A map may
+/// store only the first few of them, in which case the others are zero.
+#[derive(Debug, Default, AsBytes, FromBytes)]
+#[repr(C)]
+pub struct Extension {
+    // The offset of the reverse index, or zero if there is none.
+    pub reverse_index_offset: usize,
+    // The offset of the dense segment, or zero if there is none.
+    pub dense_offset: usize,
+}
+
+impl Extension {
+    /// The number of 8 byte words of a complete extension.
+    pub const WORDS: usize = size_of::<Extension>() / 8;
+
+    /// Reads the extension of the map held by `rep`, whose root header says
+    /// that it has `words` words.  Returns `None` if `rep` is too short.
+    pub fn read(rep: &[u8], words: u8) -> Option<Extension> {
+        let start = size_of::<Root>();
+        let bytes = rep.get(start..start + words as usize * 8)?;
+        let mut extension = Extension::default();
+        let len = bytes.len().min(size_of::<Extension>());
+        extension.as_bytes_mut()[..len].copy_from_slice(&bytes[..len]);
+        Some(extension)
+    }
+}
+
 #[derive(AsBytes, FromBytes)]
 #[repr(C)]
 pub struct TableHeader {
@@ -79,11 +156,40 @@ pub struct TableHeader {
     pad0: [u8; 4],
     // Number of bits in this table
     pub bits: u8,
-    pad1: [u8; 7],
-    // Followed by payload of 2^bits copies of cell::Instance.
+    // One of CellFormat, as a number.
+    pub cell_format: u8,
+    pad1: [u8; 6],
+    // For Type::Table, followed by payload of 2^bits cells.
+    //
+    // For Type::SparseTable, followed by ceil(2^bits / 32) copies of
+    // SparseWord, which record which cells are occupied, followed by one
+    // cell for each occupied cell.
+    //
+    // The cells are either cell::Instance or cell::Compact, as determined by
+    // cell_format.
+}
+
+/// The format of the cells of a table.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CellFormat {
+    // cell::Instance
+    Wide = 0,
+    // cell::Compact
+    Compact = 1,
 }
 
 impl TableHeader {
+    pub fn new(t: Type, bits: u8, cell_format: CellFormat) -> TableHeader {
+        assert!(t == Type::Table || t == Type::SparseTable, "type: {:?}", t);
+        TableHeader {
+            htype: t as TypeSize,
+            pad0: [0; 4],
+            bits,
+            cell_format: cell_format as u8,
+            pad1: [0; 6],
+        }
+    }
+
     pub fn set_bits(&mut self, bits: u8) {
         assert!(bits <= 64);
         self.htype = Type::Table as TypeSize;
@@ -91,27 +197,127 @@ impl TableHeader {
     }
 }
 
+/// Records the occupancy of 32 consecutive cells of a sparse table.
+#[derive(AsBytes, FromBytes, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SparseWord {
+    // The number of occupied cells in all the words before this one.
+    pub rank: u32,
+    // Bit `i` is set if cell `i` of the 32 cells in this word is occupied.
+    pub bitmap: u32,
+}
+
+impl SparseWord {
+    /// The number of cells that a single word covers.
+    pub const CELLS: usize = 32;
+
+    /// Returns the number of words that a sparse table with 2^bits cells needs.
+    pub fn count(bits: u8) -> usize {
+        ((1_usize << bits) + SparseWord::CELLS - 1) / SparseWord::CELLS
+    }
+}
+
+// The cells of a table, in either of the cell formats.
+enum Cells<'a> {
+    Wide(&'a [cell::Instance]),
+    Compact(&'a [cell::Compact]),
+}
+
+impl<'a> Cells<'a> {
+    fn overlay(cell_format: u8, elems: usize, bytes: &'a [u8]) -> Option<Cells<'a>> {
+        if cell_format == CellFormat::Wide as u8 {
+            let size = elems * size_of::<cell::Instance>();
+            let cells = LayoutVerified::new_slice(bytes.get(..size)?)?;
+            return Some(Cells::Wide(cells.into_slice()));
+        }
+        if cell_format == CellFormat::Compact as u8 {
+            let size = elems * size_of::<cell::Compact>();
+            let cells = LayoutVerified::new_slice(bytes.get(..size)?)?;
+            return Some(Cells::Compact(cells.into_slice()));
+        }
+        None
+    }
+
+    // Returns the cell at `position`, or an empty cell if there is none.
+    fn get(&self, position: usize) -> cell::Ref<'a> {
+        let cell = match self {
+            Cells::Wide(cells) => cells.get(position).map(cell::Ref::Wide),
+            Cells::Compact(cells) => cells.get(position).map(cell::Ref::Compact),
+        };
+        cell.unwrap_or(cell::Ref::Wide(&cell::EMPTY))
+    }
+}
+
 pub struct Table<'a> {
     header: &'a TableHeader,
-    cells: &'a [cell::Instance],
+    // Empty for tables that are not sparse.
+    words: &'a [SparseWord],
+    cells: Cells<'a>,
 }
 
 impl<'a> Table<'a> {
-    // Overlays a table on top of this slice.  Assumes it is initialized.
-    pub fn overlay(bytes: &'a [u8]) -> Table {
+    /// Overlays a table on top of this slice, returning `None` if the slice
+    /// does not hold a valid table.  Tables must index at least one and at
+    /// most [MAX_TABLE_BITS] bits.
+    pub fn try_overlay(bytes: &'a [u8]) -> Option<Table<'a>> {
         let (header, rest): (LayoutVerified<_, TableHeader>, _) =
-            LayoutVerified::new_from_prefix(bytes).unwrap();
+            LayoutVerified::new_from_prefix(bytes)?;
         let header = header.into_ref();
-        assert_eq!(Type::from(header.htype), Type::Table);
-        let elems = 1 << header.bits;
-        let size = elems * size_of::<cell::Instance>();
-        let cells = LayoutVerified::new_slice(&rest[..size]).unwrap();
-        let cells = cells.into_slice();
-        Table { header, cells }
+        if header.bits == 0 || header.bits as usize > MAX_TABLE_BITS {
+            return None;
+        }
+        match Type::from(header.htype) {
+            Type::Table => {
+                let elems = 1 << header.bits;
+                let cells = Cells::overlay(header.cell_format, elems, rest)?;
+                Some(Table {
+                    header,
+                    words: &[],
+                    cells,
+                })
+            }
+            Type::SparseTable => {
+                let size = SparseWord::count(header.bits) * size_of::<SparseWord>();
+                let words = rest.get(..size)?;
+                let words: &[SparseWord] = LayoutVerified::new_slice(words)?.into_slice();
+                let last = words.last().expect("at least one word");
+                let elems = last.rank as usize + last.bitmap.count_ones() as usize;
+                let cells = Cells::overlay(header.cell_format, elems, &rest[size..])?;
+                Some(Table {
+                    header,
+                    words,
+                    cells,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the number of bits that this table indexes.
+    pub fn bits(&self) -> u8 {
+        self.header.bits
+    }
+
+    /// Returns the cell at `index`, which is empty for the unoccupied cells of
+    /// a sparse table.
+    pub fn cell(&self, index: usize) -> cell::Ref<'a> {
+        if self.words.is_empty() {
+            return self.cells.get(index);
+        }
+        let word = &self.words[index / SparseWord::CELLS];
+        let bit = 1 << (index % SparseWord::CELLS);
+        if word.bitmap & bit == 0 {
+            return cell::Ref::Wide(&cell::EMPTY);
+        }
+        let position = word.rank as usize + (word.bitmap & (bit - 1)).count_ones() as usize;
+        self.cells.get(position)
     }
 
-    pub fn cell(&'a self, index: usize) -> &'a cell::Instance {
-        &self.cells[index]
+    /// Returns the occupied cells of this table, along with their indexes.
+    pub fn cells(&self) -> impl Iterator<Item = (usize, cell::Ref<'a>)> + '_ {
+        (0..1 << self.header.bits)
+            .map(move |index| (index, self.cell(index)))
+            .filter(|(_, cell)| cell.get_type() != cell::Type::Empty)
     }
 
     pub fn index(&self, key: u64) -> usize {
@@ -141,16 +347,17 @@ pub struct TableMut<'a> {
 
 impl<'a> TableMut<'a> {
     // Initializes a table for 2^bits entries.
-    pub fn init(bits: u8, bytes: &'a mut [u8]) -> TableMut {
+    pub fn init(bits: u8, bytes: &'a mut [u8]) -> TableMut<'a> {
         assert!(bits <= 64);
 
         let bytes_len = bytes.len();
         let (header, rest): (LayoutVerified<_, TableHeader>, _) =
-            LayoutVerified::new_from_prefix_zeroed(bytes).expect(&format!(
-                "TableMut::init layout verified: bits: {}, len: {}",
-                bits,
-                bytes_len,
-            ));
+            LayoutVerified::new_from_prefix_zeroed(bytes).unwrap_or_else(|| {
+                panic!(
+                    "TableMut::init layout verified: bits: {}, len: {}",
+                    bits, bytes_len,
+                )
+            });
         let elems = 1 << bits;
         let size = elems * size_of::<cell::Instance>();
         let cells = LayoutVerified::new_slice_zeroed(&mut rest[..size]).unwrap();
@@ -161,7 +368,7 @@ impl<'a> TableMut<'a> {
     }
 
     // Overlays a mutable table on top of this slice.  Assumes it is initialized.
-    pub fn overlay_mut(bytes: &'a mut [u8]) -> TableMut {
+    pub fn overlay_mut(bytes: &'a mut [u8]) -> TableMut<'a> {
         let (header, rest): (LayoutVerified<_, TableHeader>, _) =
             LayoutVerified::new_from_prefix(bytes).unwrap();
         let header = header.into_mut();
@@ -197,6 +404,7 @@ impl<'a> TableMut<'a> {
     }
 }
 
+#[allow(dead_code)]
 #[derive(AsBytes, FromBytes)]
 #[repr(C)]
 pub struct String {
@@ -206,6 +414,7 @@ pub struct String {
     // Followed by payload which ends with a '/0' byte.
 }
 
+#[allow(dead_code)]
 #[derive(AsBytes, FromBytes)]
 #[repr(C)]
 pub struct Empty {
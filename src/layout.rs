@@ -0,0 +1,749 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lays out the tables of a trie into their final byte representation.
+//!
+//! The builder inserts into a trie made of dense tables only, since those can
+//! be updated in place.  Once the trie is complete, it is read back into a
+//! [Trie] and written out again, which is when the representation of each
+//! table can be chosen based on its contents.
+
+use crate::cell;
+use crate::header;
+use std::collections::HashSet;
+use std::mem::size_of;
+use zerocopy::AsBytes;
+
+/// The order in which the tables of a map are laid out in its byte
+/// representation.  Placing tables that are visited one after another during
+/// a lookup close to each other makes better use of the CPU caches.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum Layout {
+    /// Tables are laid out in the order in which they were created.  This is
+    /// the default.
+    #[default]
+    Insertion,
+    /// Each table is followed by the tables below it, one subtrie at a time.
+    DepthFirst,
+    /// The root table is followed by all tables on the second level, which
+    /// are followed by all tables on the third level, and so on.
+    BreadthFirst,
+    /// The trie is split into a top half of the levels and the subtries below
+    /// it.  The top half is laid out first, followed by each of the subtries,
+    /// and the same is done for each of those recursively.  This keeps any
+    /// path through the trie in few cache lines, regardless of the cache line
+    /// size.
+    VanEmdeBoas,
+}
+
+/// Determines how a [Trie] is written out.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// If set, tables with few occupied cells are written out as sparse
+    /// tables.  Otherwise, all tables are dense.
+    pub sparse: bool,
+    /// The order in which the tables are written out.
+    pub layout: Layout,
+    /// If set, tables are written out with compact cells where possible.
+    /// This requires the entire buffer to be small enough.
+    pub compact: bool,
+    /// The length of the string section that follows the tables.
+    pub strings_len: usize,
+}
+
+/// The content of an occupied cell in a [Trie].
+#[derive(Debug, Clone, Copy)]
+pub enum Child {
+    /// A string at `index` in the string section, stored under `key`.
+    String { index: usize, key: u64 },
+    /// A table, by its index in [Trie::nodes].
+    Table(usize),
+    /// A table, by its index in [Trie::nodes], which is reached by skipping
+    /// over the next `bits` key bits.  These must be equal to `pattern`.
+    Skip {
+        bits: u8,
+        pattern: u64,
+        table: usize,
+    },
+}
+
+/// A single table of a [Trie].
+#[derive(Debug)]
+pub struct Node {
+    bits: u8,
+    // The offset of the table in the buffer it was read from.  Tables are
+    // written out in the same order in which they were read.
+    offset: usize,
+    // Occupied cells, ordered by cell index.
+    cells: Vec<(usize, Child)>,
+}
+
+/// A trie, decoupled from its byte representation.
+#[derive(Debug, Default)]
+pub struct Trie {
+    nodes: Vec<Node>,
+    root: Option<usize>,
+}
+
+impl Trie {
+    /// Reads the trie rooted at `root_table_offset` from `index`.  An offset
+    /// of zero means that the trie is empty.
+    pub fn read(index: &[u8], root_table_offset: usize) -> Trie {
+        Trie::try_read(index, root_table_offset).expect("valid trie")
+    }
+
+    /// Same as [Trie::read], except that `None` is returned if `index` does
+    /// not hold a valid trie.  In a valid trie, each table is reached once,
+    /// only through cells which leave it key bits to index, and each string
+    /// is stored under a key that agrees with the path to it.
+    pub fn try_read(index: &[u8], root_table_offset: usize) -> Option<Trie> {
+        let mut trie = Trie::default();
+        if root_table_offset != 0 {
+            let mut visited = HashSet::new();
+            let root = trie.read_table(index, root_table_offset, 64, 0, &mut visited)?;
+            trie.root = Some(root);
+        }
+        Some(trie)
+    }
+
+    // Reads the table at `offset`.  `remaining_bits` is the number of key bits
+    // not used by the tables above it, and `path` holds the key bits that are.
+    // `visited` holds the offsets of the tables read so far.
+    fn read_table(
+        &mut self,
+        index: &[u8],
+        offset: usize,
+        remaining_bits: usize,
+        path: u64,
+        visited: &mut HashSet<usize>,
+    ) -> Option<usize> {
+        if remaining_bits == 0 || !visited.insert(offset) {
+            return None;
+        }
+        let table = header::Table::try_overlay(index.get(offset..)?)?;
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            bits: table.bits(),
+            offset,
+            cells: vec![],
+        });
+        let used_bits = 64 - remaining_bits;
+        let remaining_bits = table.decrement_bits(remaining_bits);
+        let mut cells = vec![];
+        for (slot, cell) in table.cells() {
+            let path = path | (slot as u64).checked_shl(used_bits as u32).unwrap_or(0);
+            let child = match cell.get_type() {
+                cell::Type::StringPtr => {
+                    let cell = cell.to_instance(remaining_bits, path);
+                    let (index, key) = cell.string_index_and_key();
+                    // The used key bits of wide cells are stored as well.
+                    if key & u64::MAX.checked_shr(remaining_bits as u32).unwrap_or(0) != path {
+                        return None;
+                    }
+                    Child::String { index, key }
+                }
+                cell::Type::TablePtr => {
+                    let table = cell.table_index();
+                    Child::Table(self.read_table(index, table, remaining_bits, path, visited)?)
+                }
+                cell::Type::Skip => {
+                    let (table, bits, pattern) = cell.skip();
+                    if bits as usize >= remaining_bits {
+                        return None;
+                    }
+                    let path = path | pattern << (64 - remaining_bits);
+                    let remaining_bits = remaining_bits - bits as usize;
+                    let table = self.read_table(index, table, remaining_bits, path, visited)?;
+                    Child::Skip {
+                        bits,
+                        pattern,
+                        table,
+                    }
+                }
+                _ => return None,
+            };
+            cells.push((slot, child));
+        }
+        self.nodes[id].cells = cells;
+        Some(id)
+    }
+
+    /// Joins `tries` into a single trie, whose root table has `bits` bits.  The
+    /// root tables of `tries` must have `bits` bits as well, and no two of
+    /// them may have the same cell occupied.  The tables of each trie are laid
+    /// out after those of the tries before it in the [Layout::Insertion]
+    /// order.
+    #[cfg(feature = "rayon")]
+    pub fn join(bits: u8, tries: Vec<Trie>) -> Trie {
+        let mut result = Trie::default();
+        let mut cells = vec![];
+        let mut next_offset = 0;
+        for trie in tries {
+            let root = match trie.root {
+                None => continue,
+                Some(root) => root,
+            };
+            // The root of a trie is the first table read, and all others are
+            // moved over without it.
+            assert_eq!(0, root, "root must be the first table");
+            assert_eq!(bits, trie.nodes[root].bits);
+            let base = result.nodes.len();
+            let relocate = |id: usize| base + id - 1;
+            let relocate_child = |child: Child| match child {
+                Child::String { .. } => child,
+                Child::Table(table) => Child::Table(relocate(table)),
+                Child::Skip {
+                    bits,
+                    pattern,
+                    table,
+                } => Child::Skip {
+                    bits,
+                    pattern,
+                    table: relocate(table),
+                },
+            };
+            let base_offset = next_offset;
+            for (id, mut node) in trie.nodes.into_iter().enumerate() {
+                next_offset = next_offset.max(base_offset + node.offset + 1);
+                for (_, child) in &mut node.cells {
+                    *child = relocate_child(*child);
+                }
+                if id == root {
+                    cells.extend(node.cells);
+                } else {
+                    node.offset += base_offset;
+                    result.nodes.push(node);
+                }
+            }
+        }
+        if cells.is_empty() {
+            return result;
+        }
+        cells.sort_by_key(|(slot, _)| *slot);
+        assert!(
+            cells.windows(2).all(|pair| pair[0].0 != pair[1].0),
+            "tries overlap"
+        );
+        result.root = Some(result.nodes.len());
+        result.nodes.push(Node {
+            bits,
+            offset: 0,
+            cells,
+        });
+        result
+    }
+
+    /// Replaces chains of tables that have a single occupied cell each with
+    /// skip cells.  Tables that have a single string in them, and empty tables
+    /// are removed as well.  These are left behind when keys are removed.
+    pub fn compress(&mut self) {
+        if let Some(root) = self.root {
+            self.compress_table(root);
+        }
+    }
+
+    fn compress_table(&mut self, id: usize) {
+        let cells = std::mem::take(&mut self.nodes[id].cells);
+        self.nodes[id].cells = cells
+            .into_iter()
+            .filter_map(|(slot, child)| self.compress_child(child).map(|child| (slot, child)))
+            .collect();
+    }
+
+    // Returns the compressed replacement for `child`, or `None` if nothing
+    // remains of it.
+    fn compress_child(&mut self, child: Child) -> Option<Child> {
+        let (skip_bits, skip_pattern, table) = match child {
+            Child::String { .. } => return Some(child),
+            Child::Table(table) => (0, 0, table),
+            Child::Skip {
+                bits,
+                pattern,
+                table,
+            } => (bits, pattern, table),
+        };
+        self.compress_table(table);
+        let node = &self.nodes[table];
+        if node.cells.len() != 1 {
+            return match node.cells.len() {
+                0 => None,
+                _ => Some(child),
+            };
+        }
+        let (slot, only) = node.cells[0];
+        // Skip over this table, and whatever the only cell skips over.
+        let (bits, pattern, table) = match only {
+            Child::String { .. } => return Some(only),
+            Child::Table(table) => (node.bits, slot as u64, table),
+            Child::Skip {
+                bits,
+                pattern,
+                table,
+            } => (node.bits + bits, slot as u64 | pattern << node.bits, table),
+        };
+        Some(Child::Skip {
+            bits: skip_bits + bits,
+            pattern: skip_pattern | pattern << skip_bits,
+            table,
+        })
+    }
+
+    /// Removes the strings whose keys `f` returns `false` for, and the tables
+    /// which are left without any cells.
+    pub fn retain_strings<F>(&mut self, mut f: F)
+    where
+        F: FnMut(u64) -> bool,
+    {
+        if let Some(root) = self.root {
+            if !self.retain_table(root, &mut f) {
+                self.root = None;
+            }
+        }
+    }
+
+    // Returns whether any cells are left in table `id`.
+    fn retain_table<F>(&mut self, id: usize, f: &mut F) -> bool
+    where
+        F: FnMut(u64) -> bool,
+    {
+        let cells = std::mem::take(&mut self.nodes[id].cells);
+        let cells: Vec<(usize, Child)> = cells
+            .into_iter()
+            .filter(|(_, child)| match *child {
+                Child::String { key, .. } => f(key),
+                Child::Table(table) | Child::Skip { table, .. } => self.retain_table(table, f),
+            })
+            .collect();
+        let retained = !cells.is_empty();
+        self.nodes[id].cells = cells;
+        retained
+    }
+
+    /// Replaces skip cells with chains of tables that have a single occupied
+    /// cell each.  `schedule` determines the number of bits for each level
+    /// of the newly created tables.  Returns `false` if the tables, or the
+    /// levels that skip cells skip over, do not have the bits which
+    /// `schedule` gives for their level, in which case the trie is left
+    /// partly expanded.
+    pub fn expand(&mut self, schedule: &[u8]) -> bool {
+        match self.root {
+            Some(root) => self.expand_table(root, 0, schedule),
+            None => true,
+        }
+    }
+
+    fn expand_table(&mut self, id: usize, level: usize, schedule: &[u8]) -> bool {
+        if schedule.get(level) != Some(&self.nodes[id].bits) {
+            return false;
+        }
+        for position in 0..self.nodes[id].cells.len() {
+            let (slot, child) = self.nodes[id].cells[position];
+            let (mut bits, mut pattern, table) = match child {
+                Child::String { .. } => continue,
+                Child::Table(table) => (0, 0, table),
+                Child::Skip {
+                    bits,
+                    pattern,
+                    table,
+                } => (bits, pattern, table),
+            };
+            // Each skipped level gets a table which points to the next one.
+            let mut parent = (id, slot);
+            let mut level = level + 1;
+            while bits > 0 {
+                let level_bits = match schedule.get(level) {
+                    Some(level_bits) if (1..=bits).contains(level_bits) => *level_bits,
+                    _ => return false,
+                };
+                let new_id = self.nodes.len();
+                self.nodes.push(Node {
+                    bits: level_bits,
+                    offset: self.nodes[id].offset,
+                    cells: vec![],
+                });
+                self.set_child(parent, Child::Table(new_id));
+                parent = (new_id, (pattern & ((1 << level_bits) - 1)) as usize);
+                pattern >>= level_bits;
+                bits -= level_bits;
+                level += 1;
+            }
+            self.set_child(parent, Child::Table(table));
+            if !self.expand_table(table, level, schedule) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Replaces the index of each string with the one that `f` returns for
+    /// it, for when the string section is rewritten.
+    pub fn map_strings<F>(&mut self, mut f: F)
+    where
+        F: FnMut(usize) -> usize,
+    {
+        self.try_map_strings(|index| Some(f(index)));
+    }
+
+    /// Same as [Trie::map_strings], except that `f` may return `None`, in
+    /// which case `false` is returned, and the remaining strings are left
+    /// unchanged.
+    pub fn try_map_strings<F>(&mut self, mut f: F) -> bool
+    where
+        F: FnMut(usize) -> Option<usize>,
+    {
+        for node in &mut self.nodes {
+            for (_, child) in &mut node.cells {
+                if let Child::String { index, .. } = child {
+                    match f(*index) {
+                        Some(mapped) => *index = mapped,
+                        None => return false,
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Returns the index of the string and the key of each cell which holds
+    /// one, in the order of a depth first traversal of the trie.
+    pub fn strings(&self) -> Vec<(usize, u64)> {
+        let mut result = vec![];
+        if let Some(root) = self.root {
+            self.table_strings(root, &mut result);
+        }
+        result
+    }
+
+    fn table_strings(&self, id: usize, result: &mut Vec<(usize, u64)>) {
+        for (_, child) in &self.nodes[id].cells {
+            match *child {
+                Child::String { index, key } => result.push((index, key)),
+                Child::Table(table) | Child::Skip { table, .. } => {
+                    self.table_strings(table, result)
+                }
+            }
+        }
+    }
+
+    // Sets the cell `slot` of table `id` to `child`.
+    fn set_child(&mut self, (id, slot): (usize, usize), child: Child) {
+        let cells = &mut self.nodes[id].cells;
+        match cells.binary_search_by_key(&slot, |(slot, _)| *slot) {
+            Ok(position) => cells[position].1 = child,
+            Err(position) => cells.insert(position, (slot, child)),
+        }
+    }
+
+    // Returns the tables that the cells of table `id` point to, in cell order.
+    fn children(&self, id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.nodes[id]
+            .cells
+            .iter()
+            .filter_map(|(_, child)| match *child {
+                Child::String { .. } => None,
+                Child::Table(table) | Child::Skip { table, .. } => Some(table),
+            })
+    }
+
+    // Returns the tables reachable from `root`, in the order given by `layout`.
+    fn order(&self, root: usize, layout: Layout) -> Vec<usize> {
+        let mut result = vec![];
+        match layout {
+            Layout::Insertion => {
+                result = self.order(root, Layout::DepthFirst);
+                result.sort_by_key(|id| self.nodes[*id].offset);
+            }
+            Layout::DepthFirst => {
+                let mut pending = vec![root];
+                while let Some(id) = pending.pop() {
+                    result.push(id);
+                    let first = pending.len();
+                    pending.extend(self.children(id));
+                    pending[first..].reverse();
+                }
+            }
+            Layout::BreadthFirst => {
+                result.push(root);
+                let mut next = 0;
+                while next < result.len() {
+                    let id = result[next];
+                    result.extend(self.children(id));
+                    next += 1;
+                }
+            }
+            Layout::VanEmdeBoas => {
+                let levels = self.levels(root);
+                self.van_emde_boas(root, levels, &mut result);
+            }
+        }
+        result
+    }
+
+    // Returns the number of table levels in the subtrie rooted at `id`.
+    fn levels(&self, id: usize) -> usize {
+        1 + self
+            .children(id)
+            .map(|table| self.levels(table))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Appends the top `levels` levels of the subtrie rooted at `id` to `result`
+    // in van Emde Boas order: the top half of the levels is laid out first,
+    // followed by each of the subtries hanging off the top half.  Returns the
+    // tables just below the laid out levels.
+    fn van_emde_boas(&self, id: usize, levels: usize, result: &mut Vec<usize>) -> Vec<usize> {
+        if levels == 1 {
+            result.push(id);
+            return self.children(id).collect();
+        }
+        let top = levels / 2;
+        let mut below = vec![];
+        for table in self.van_emde_boas(id, top, result) {
+            below.extend(self.van_emde_boas(table, levels - top, result));
+        }
+        below
+    }
+
+    // Returns the number of key bits not used by the tables up to and
+    // including each table reachable from `id`, indexed by table.
+    fn remaining_bits(&self, id: usize, remaining_bits: usize, result: &mut Vec<usize>) {
+        let remaining_bits = remaining_bits.saturating_sub(self.nodes[id].bits as usize);
+        result[id] = remaining_bits;
+        for (_, child) in &self.nodes[id].cells {
+            match *child {
+                Child::String { .. } => {}
+                Child::Table(table) => self.remaining_bits(table, remaining_bits, result),
+                Child::Skip { bits, table, .. } => {
+                    self.remaining_bits(table, remaining_bits - bits as usize, result)
+                }
+            }
+        }
+    }
+
+    /// Appends the tables of this trie to `index`, returning the offset of
+    /// the root table, or zero if the trie is empty.
+    pub fn write(&self, index: &mut Vec<u8>, options: Options) -> usize {
+        let root = match self.root {
+            None => return 0,
+            Some(root) => root,
+        };
+        let order = self.order(root, options.layout);
+        let mut remaining_bits = vec![0; self.nodes.len()];
+        self.remaining_bits(root, 64, &mut remaining_bits);
+
+        // Compact cells can only be used if all offsets are small enough, which
+        // is the case if the buffer is small enough with wide cells only.
+        let mut plans = vec![Plan::default(); self.nodes.len()];
+        let mut size = index.len() + options.strings_len;
+        for id in &order {
+            plans[*id] = self.nodes[*id].plan(options, header::CellFormat::Wide);
+            size = align(size) + self.nodes[*id].size(plans[*id]);
+        }
+        if options.compact && size < cell::COMPACT_INDEX_LIMIT {
+            for id in &order {
+                let node = &self.nodes[*id];
+                if node.fits_compact(remaining_bits[*id]) {
+                    plans[*id] = node.plan(options, header::CellFormat::Compact);
+                }
+            }
+        }
+
+        // Tables may point to tables which come after them, so all offsets
+        // need to be known before anything is written.
+        let mut offsets = vec![0; self.nodes.len()];
+        let mut offset = index.len();
+        for id in &order {
+            offset = align(offset);
+            offsets[*id] = offset;
+            offset += self.nodes[*id].size(plans[*id]);
+        }
+        for id in &order {
+            index.resize(align(index.len()), 0);
+            assert_eq!(index.len(), offsets[*id]);
+            self.nodes[*id].write(index, &offsets, plans[*id], remaining_bits[*id]);
+        }
+        offsets[root]
+    }
+}
+
+// Aligns `offset` so that a table may start there.
+fn align(offset: usize) -> usize {
+    (offset + header::ALIGNMENT - 1) / header::ALIGNMENT * header::ALIGNMENT
+}
+
+// Determines how a single table is written out.
+#[derive(Debug, Clone, Copy)]
+struct Plan {
+    table_type: header::Type,
+    cell_format: header::CellFormat,
+}
+
+impl Default for Plan {
+    fn default() -> Plan {
+        Plan {
+            table_type: header::Type::Table,
+            cell_format: header::CellFormat::Wide,
+        }
+    }
+}
+
+impl Node {
+    // Tables in which at most this fraction of the cells is occupied are
+    // written out as sparse tables.
+    const SPARSE_FRACTION: usize = 4;
+
+    /// Creates a table of `bits` bits with the occupied `cells`, ordered by
+    /// cell index, for writing it out on its own.  The tables that its cells
+    /// point to are given by their index in the offsets passed to
+    /// [Node::write_alone].
+    pub fn new(bits: u8, cells: Vec<(usize, Child)>) -> Node {
+        Node {
+            bits,
+            offset: 0,
+            cells,
+        }
+    }
+
+    /// Returns the size of this table when written out with
+    /// [Node::write_alone].
+    pub fn size_alone(&self, compact: bool, remaining_bits: usize) -> usize {
+        self.size(self.plan_alone(compact, remaining_bits))
+    }
+
+    /// Appends this table to `index` the way that [Trie::write] would, with
+    /// sparse tables allowed.  If `compact` is set, the table is written out
+    /// with compact cells if they fit.  `remaining_bits` is the number of key
+    /// bits not used by the tables up to and including this one.
+    pub fn write_alone(
+        &self,
+        index: &mut Vec<u8>,
+        offsets: &[usize],
+        compact: bool,
+        remaining_bits: usize,
+    ) {
+        let plan = self.plan_alone(compact, remaining_bits);
+        self.write(index, offsets, plan, remaining_bits);
+    }
+
+    fn plan_alone(&self, compact: bool, remaining_bits: usize) -> Plan {
+        let options = Options {
+            sparse: true,
+            layout: Layout::DepthFirst,
+            compact,
+            strings_len: 0,
+        };
+        if compact && self.fits_compact(remaining_bits) {
+            self.plan(options, header::CellFormat::Compact)
+        } else {
+            self.plan(options, header::CellFormat::Wide)
+        }
+    }
+
+    fn plan(&self, options: Options, cell_format: header::CellFormat) -> Plan {
+        let table_type =
+            if options.sparse && self.cells.len() * Node::SPARSE_FRACTION <= 1 << self.bits {
+                header::Type::SparseTable
+            } else {
+                header::Type::Table
+            };
+        Plan {
+            table_type,
+            cell_format,
+        }
+    }
+
+    // Returns true if all cells fit into compact cells, provided that the
+    // offsets are small enough.
+    fn fits_compact(&self, remaining_bits: usize) -> bool {
+        self.cells.iter().all(|(_, child)| {
+            let mut cell = cell::EMPTY;
+            match *child {
+                Child::String { key, .. } => cell.become_string_ptr(0, key),
+                Child::Table(_) => cell.become_table_ptr(0),
+                Child::Skip { bits, pattern, .. } => cell.become_skip(0, bits, pattern),
+            }
+            cell::Compact::from_instance(&cell, remaining_bits).is_some()
+        })
+    }
+
+    fn size(&self, plan: Plan) -> usize {
+        let cell_size = match plan.cell_format {
+            header::CellFormat::Wide => size_of::<cell::Instance>(),
+            header::CellFormat::Compact => size_of::<cell::Compact>(),
+        };
+        let cells = match plan.table_type {
+            header::Type::SparseTable => {
+                header::SparseWord::count(self.bits) * size_of::<header::SparseWord>()
+                    + self.cells.len() * cell_size
+            }
+            _ => (1 << self.bits) * cell_size,
+        };
+        size_of::<header::TableHeader>() + cells
+    }
+
+    fn write(&self, index: &mut Vec<u8>, offsets: &[usize], plan: Plan, remaining_bits: usize) {
+        let cell = |child: &Child| {
+            let mut cell = cell::EMPTY;
+            match *child {
+                Child::String { index, key } => cell.become_string_ptr(index, key),
+                Child::Table(id) => cell.become_table_ptr(offsets[id]),
+                Child::Skip {
+                    bits,
+                    pattern,
+                    table,
+                } => cell.become_skip(offsets[table], bits, pattern),
+            }
+            cell
+        };
+        let header = header::TableHeader::new(plan.table_type, self.bits, plan.cell_format);
+        index.extend_from_slice(header.as_bytes());
+        let cells: Vec<cell::Instance> = match plan.table_type {
+            header::Type::SparseTable => {
+                let mut words =
+                    vec![header::SparseWord::default(); header::SparseWord::count(self.bits)];
+                for (slot, _) in &self.cells {
+                    words[slot / header::SparseWord::CELLS].bitmap |=
+                        1 << (slot % header::SparseWord::CELLS);
+                }
+                let mut rank = 0;
+                for word in &mut words {
+                    word.rank = rank;
+                    rank += word.bitmap.count_ones();
+                }
+                index.extend_from_slice(words.as_bytes());
+                self.cells.iter().map(|(_, child)| cell(child)).collect()
+            }
+            _ => {
+                let mut cells = vec![cell::EMPTY; 1 << self.bits];
+                for (slot, child) in &self.cells {
+                    cells[*slot] = cell(child);
+                }
+                cells
+            }
+        };
+        match plan.cell_format {
+            header::CellFormat::Wide => index.extend_from_slice(cells.as_bytes()),
+            header::CellFormat::Compact => {
+                let cells: Vec<cell::Compact> = cells
+                    .iter()
+                    .map(|cell| {
+                        cell::Compact::from_instance(cell, remaining_bits).expect("compact cell")
+                    })
+                    .collect();
+                index.extend_from_slice(cells.as_bytes());
+            }
+        }
+    }
+}
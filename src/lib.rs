@@ -20,12 +20,15 @@
 //! The special property of the implementation is that it encodes all the data needed for the
 //! lookup in a single sequence of bytes.  This makes it rather interesting for dynamic loading of
 //! data that can then be placed in an operating system's read only memory.  The internal structure
-//! requires no decoding when it is loaded (say from a file).
+//! requires no decoding when it is loaded (say from a file).  The only requirement is that the
+//! sequence of bytes starts at an address aligned to [ALIGNMENT] bytes, so that all reads are
+//! aligned.
 //!
 //! The map is internally represented as a trie with each level of the trie being indexed by a
 //! number of bits of the key, starting from the least-significant bit side.  So for example, when
 //! creating the builder with 2 bits, then 2 bits will be chopped off the provided key for each
-//! descent into the trie by one level.
+//! descent into the trie by one level.  The number of bits may also be chosen per level with
+//! [Builder::with_schedule], for example to use a wide root table and narrow tables below it.
 //!
 //! Example:
 //!
@@ -53,19 +56,55 @@
 //! assert!(lookup.get(100).is_none());
 //! ```
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi;
+use std::fmt;
 use std::mem::size_of;
 use zerocopy::LayoutVerified;
 
+#[cfg(feature = "capi")]
+pub mod capi;
 mod cell;
+mod compression;
+mod dense;
+mod external;
+pub mod format;
 mod header;
+mod layout;
+mod lz;
+mod map_buf;
+mod perfect_hash;
+pub mod reference;
+mod reload;
+mod reverse;
 mod string_slice;
 
+pub use compression::Compression;
+pub use external::ExternalBuilder;
+pub use layout::Layout;
+pub use map_buf::{MapBuf, Storage};
+pub use perfect_hash::IndexKind;
+pub use reload::ReloadableMap;
+
+/// The alignment, in bytes, which the start of a buffer passed to [Map::new]
+/// must have.  This allows all fields of the map to be read with aligned
+/// accesses, which are faster, and on some CPUs the only ones possible.
+pub const ALIGNMENT: usize = header::ALIGNMENT;
+
 /// A map builder.  Creates a sequence map, allowing the user to insert, repeatedly, a number of
-/// key-value pairs.  Use `Builder::new` to create.
+/// key-value pairs.  Use `Builder::new` or `Builder::with_schedule` to create.
 #[derive(Debug)]
 pub struct Builder {
-    bits: u8,
+    // The number of bits used for each trie level, starting from the root.
+    schedule: Vec<u8>,
+    layout: Layout,
+    compression: Compression,
+    suffix_sharing: bool,
+    canonical: bool,
+    reverse_index: bool,
+    index_kind: IndexKind,
+    dense_range: bool,
     index: Vec<u8>,
     strings: string_slice::Intern,
 }
@@ -76,9 +115,52 @@ impl Builder {
     /// more bits are used, the faster the lookup, but the larger the resulting
     /// binary format.
     pub fn new(bits: usize) -> Builder {
-        assert!(bits >= 2 && bits <= 16);
+        Builder::with_schedule(&[bits])
+    }
+
+    /// Creates a new map builder which uses a different number of bits for
+    /// each level of the internal trie.  `schedule[0]` is the number of bits
+    /// used for the root table, `schedule[1]` for the tables one level below
+    /// and so on; the last entry is repeated for all remaining levels.  Each
+    /// entry must be between 2 and 16.  If 64 is not a multiple of the bits
+    /// per level, the deepest level only uses the key bits that remain, so
+    /// that the levels cover exactly 64 bits.
+    ///
+    /// A wide root with narrower levels below it is usually a good choice for
+    /// key sets which are dense in the low bits but sparse elsewhere, as the
+    /// deeper tables remain small even if they are mostly empty.
+    pub fn with_schedule(schedule: &[usize]) -> Builder {
+        assert!(!schedule.is_empty(), "schedule must not be empty");
+        let mut level_bits = vec![];
+        let mut total_bits = 0;
+        while total_bits < 64 {
+            let bits = schedule[level_bits.len().min(schedule.len() - 1)];
+            assert!(
+                (2..=header::MAX_TABLE_BITS).contains(&bits),
+                "bits: {}",
+                bits
+            );
+            // The deepest tables only index the bits that are left, instead
+            // of having slots for key bits which do not exist.
+            let bits = bits.min(64 - total_bits);
+            level_bits.push(bits as u8);
+            total_bits += bits;
+        }
+        Builder::with_level_bits(level_bits)
+    }
+
+    // Creates a new map builder which uses `level_bits` bits for each level of
+    // the trie.  These must add up to exactly 64.
+    fn with_level_bits(level_bits: Vec<u8>) -> Builder {
         let mut builder = Builder {
-            bits: bits as u8,
+            schedule: level_bits,
+            layout: Layout::default(),
+            compression: Compression::default(),
+            suffix_sharing: false,
+            canonical: false,
+            reverse_index: false,
+            index_kind: IndexKind::default(),
+            dense_range: true,
             index: vec![],
             strings: string_slice::Intern::new(),
         };
@@ -86,6 +168,191 @@ impl Builder {
         builder
     }
 
+    /// Creates a map builder from the contents of an existing [Map], so that
+    /// more key-value pairs can be inserted into, or removed from it before
+    /// building it again.  The bit widths are the ones that `map` was built
+    /// with.  Panics if the tables or values of `map` are damaged; see
+    /// [Builder::try_from_map].
+    pub fn from_map(map: &Map) -> Builder {
+        Builder::try_from_map(map).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [Builder::from_map], except that an error is returned if the
+    /// tables or values of `map` are damaged, instead of panicking.
+    /// [Map::try_new] only checks the header of a map, so this is the case
+    /// for maps read from untrusted sources.
+    pub fn try_from_map(map: &Map) -> Result<Builder, Error> {
+        Builder::try_from_bytes(map.rep.to_vec())
+    }
+
+    /// Creates a map builder from a byte sequence previously produced by
+    /// [Builder::build].  This is the same as [Builder::from_map], except that
+    /// the bytes are reused instead of copied.  The builder uses the same
+    /// [Compression] and [IndexKind] as the map, and builds a reverse index and
+    /// a dense range if the map has them.  Unlike [Builder::from_map], this
+    /// also accepts maps with compressed values.  Panics if `bytes` is not a
+    /// valid map; see [Builder::try_from_bytes].
+    pub fn from_bytes(bytes: Vec<u8>) -> Builder {
+        Builder::try_from_bytes(bytes).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [Builder::from_bytes], except that an error is returned if
+    /// `bytes` is not a valid map, instead of panicking.  Besides the errors
+    /// of [CompressedMap::try_new], this returns [Error::Corrupt] if the tables or
+    /// values of the map are damaged.
+    pub fn try_from_bytes(mut bytes: Vec<u8>) -> Result<Builder, Error> {
+        let (schedule, string_offset, string_format, reverse_index, dense_range, index_kind, loose) = {
+            let map = Map::open(&bytes)?;
+            let root = map.header();
+            // New keys are inserted into tables with these widths.
+            if root
+                .level_bits()
+                .iter()
+                .any(|bits| *bits as usize > header::MAX_TABLE_BITS)
+            {
+                return Err(Error::Corrupt);
+            }
+            let index_kind = match root.index_kind {
+                kind if kind == IndexKind::PerfectHash as u8 => IndexKind::PerfectHash,
+                _ => IndexKind::Trie,
+            };
+            // The keys which are not in the trie of the map.
+            let mut loose: Vec<(u64, usize)> =
+                map.dense.iter().flat_map(|dense| dense.entries()).collect();
+            if let Some(perfect_hash) = &map.perfect_hash {
+                loose.extend(perfect_hash.entries());
+            }
+            (
+                root.level_bits().to_vec(),
+                root.string_offset,
+                root.string_format,
+                map.reverse_index().is_some(),
+                map.dense.is_some(),
+                index_kind,
+                loose,
+            )
+        };
+        let strings = bytes.split_off(string_offset);
+        // Compressed strings are decoded, which moves them.
+        let (compression, mut strings, indexes) =
+            compression::decompress(string_format, strings).ok_or(Error::Corrupt)?;
+        let loose: Vec<(u64, usize)> = loose
+            .into_iter()
+            .map(|(key, index)| {
+                let index = match &indexes {
+                    Some(indexes) => indexes.get(&index).copied(),
+                    None => strings.try_mark(index),
+                };
+                index.map(|index| (key, index)).ok_or(Error::Corrupt)
+            })
+            .collect::<Result<_, _>>()?;
+        let mut builder = Builder {
+            schedule,
+            layout: Layout::default(),
+            compression,
+            suffix_sharing: false,
+            canonical: false,
+            reverse_index,
+            index_kind,
+            dense_range,
+            index: bytes,
+            strings: string_slice::Intern::new(),
+        };
+        if index_kind == IndexKind::Trie {
+            // Tables are only updated in place if they are dense, and if there
+            // is a table for each trie level.
+            let valid = builder.relayout(
+                |trie, schedule| {
+                    trie.expand(schedule)
+                        && match indexes {
+                            Some(indexes) => {
+                                trie.try_map_strings(|index| indexes.get(&index).copied())
+                            }
+                            // Values may be stored within others, if they were
+                            // shared.
+                            None => trie.try_map_strings(|index| strings.try_mark(index)),
+                        }
+                },
+                layout::Options {
+                    sparse: false,
+                    layout: Layout::Insertion,
+                    compact: false,
+                    strings_len: 0,
+                },
+            );
+            if !valid {
+                return Err(Error::Corrupt);
+            }
+        } else {
+            // None of the keys are in a trie, so they all go into a new one.
+            builder.index.clear();
+            builder.reserve_header();
+        }
+        builder.strings = strings;
+        for (key, index) in loose {
+            builder.insert_string(key, |_| index);
+        }
+        Ok(builder)
+    }
+
+    /// Sets the order in which the tables of the map are laid out when it is
+    /// built.  See [Layout] for the available orders.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Sets how the values of the map are stored when it is built.  See
+    /// [Compression] for the available choices.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Sets whether values which are the end of other values are stored
+    /// within those, instead of on their own, when the map is built.  For
+    /// example, "World!" can be stored as the end of "Hello, World!".  This
+    /// is off by default, since it makes building slower, and applies only to
+    /// maps without [Compression].  See [Builder::build_with_stats] for the
+    /// number of bytes that it saves.
+    pub fn set_suffix_sharing(&mut self, suffix_sharing: bool) {
+        self.suffix_sharing = suffix_sharing;
+    }
+
+    /// Sets whether the bytes of the map depend only on its key-value pairs
+    /// and bits per level, when it is built.  Otherwise, they also depend on
+    /// the order in which the pairs were inserted, and on the values which
+    /// were removed.  In this canonical form, the values are stored in the
+    /// order of their keys in the trie, removed values are dropped, and
+    /// [Layout::Insertion] is replaced by [Layout::DepthFirst].  This is off
+    /// by default.
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    /// Sets whether the map stores a reverse index when it is built, which
+    /// finds the keys that map to a value.  See [Map::keys_for].  This is off
+    /// by default, since it takes 8 bytes for each key, and 16 bytes for each
+    /// value.
+    pub fn set_reverse_index(&mut self, reverse_index: bool) {
+        self.reverse_index = reverse_index;
+    }
+
+    /// Sets the kind of index which the map uses to find the values of keys,
+    /// when it is built.  See [IndexKind] for the available kinds.
+    pub fn set_index_kind(&mut self, index_kind: IndexKind) {
+        self.index_kind = index_kind;
+    }
+
+    /// Sets whether the longest range of keys in which at most every other
+    /// key is missing, such as `0..n`, is stored in an array when the map is
+    /// built, if it holds at least 64 keys.  The keys in the range are looked
+    /// up with a single load, and take 8 bytes each, and all other keys are
+    /// looked up in the index.  This is on by default.  Maps with a dense
+    /// range can not be read by versions of this crate which predate it, so
+    /// it should be turned off for maps which those need to read.
+    pub fn set_dense_range(&mut self, dense_range: bool) {
+        self.dense_range = dense_range;
+    }
+
     fn allocate_string(&mut self, s: &str) -> usize {
         self.strings.add(s)
     }
@@ -108,47 +375,347 @@ impl Builder {
         assert_eq!(self.index.len(), 0);
         self.index
             .resize(self.index.len() + size_of::<header::Root>(), 0);
+        let schedule = self.schedule.clone();
         let root = self.header_unchecked();
         root.set_type(header::Type::Root);
+        root.set_level_bits(&schedule);
         root.set_table_offset(0);
         root.set_string_offset(0);
         assert_ne!(self.index.len(), 0);
     }
 
-    fn append_table(&mut self) -> usize {
+    // Appends a table for the trie level `level`, returning its offset.
+    fn append_table(&mut self, level: usize) -> usize {
+        let bits = self.schedule[level];
         let index = self.index.len();
-        let entries: usize = 1 << self.bits;
+        assert_eq!(index % header::ALIGNMENT, 0, "index: {}", index);
+        let entries: usize = 1 << bits;
         let size = size_of::<header::TableHeader>() + entries * size_of::<cell::Instance>();
         self.index.resize(index + size, 0);
         {
-            header::TableMut::init(self.bits, &mut self.index[index..index + size]);
+            header::TableMut::init(bits, &mut self.index[index..index + size]);
         }
         index
     }
 
+    // Rewrites all tables of the index, after applying `transform` to the trie.
+    // The tables are written as determined by `options`.  Returns `false`, and
+    // leaves the index unchanged, if the tables are not a valid trie, or if
+    // `transform` returns `false`.
+    fn relayout<F>(&mut self, transform: F, options: layout::Options) -> bool
+    where
+        F: FnOnce(&mut layout::Trie, &[u8]) -> bool,
+    {
+        let root_table_offset = {
+            let header = self.header();
+            header.root_table_offset
+        };
+        let mut trie = match layout::Trie::try_read(&self.index, root_table_offset) {
+            Some(trie) => trie,
+            None => return false,
+        };
+        if !transform(&mut trie, &self.schedule) {
+            return false;
+        }
+        self.write_trie(&trie, options, false);
+        true
+    }
+
+    // Replaces all tables of the index with those of `trie`, written as
+    // determined by `options`.  The tables follow an extension if `extension`
+    // is set.
+    fn write_trie(&mut self, trie: &layout::Trie, options: layout::Options, extension: bool) {
+        self.clear_tables(extension);
+        let root_table_offset = trie.write(&mut self.index, options);
+        let root = self.header();
+        root.set_table_offset(root_table_offset);
+        root.index_kind = IndexKind::Trie as u8;
+    }
+
+    // Replaces all tables of the index with a perfect hash index of the keys
+    // of `trie`, which follows an extension if `extension` is set.  Returns
+    // `false` if no perfect hash function was found, in which case the index
+    // is left without tables.
+    fn write_perfect_hash(&mut self, trie: &layout::Trie, extension: bool) -> bool {
+        self.clear_tables(extension);
+        let entries: Vec<(u64, usize)> = trie
+            .strings()
+            .into_iter()
+            .map(|(index, key)| (key, index))
+            .collect();
+        // As with tries, maps without keys have no index.
+        let mut offset = 0;
+        if !entries.is_empty() {
+            offset = self.index.len();
+            if !perfect_hash::write(&entries, &mut self.index) {
+                return false;
+            }
+        }
+        let root = self.header();
+        root.set_table_offset(offset);
+        root.index_kind = IndexKind::PerfectHash as u8;
+        true
+    }
+
+    // Removes all tables and sections of the index, which leaves the root
+    // header, followed by an empty extension if `extension` is set.
+    fn clear_tables(&mut self, extension: bool) {
+        self.index.truncate(size_of::<header::Root>());
+        // The extension is only stored when one of its fields is used, so
+        // that other maps remain the same.
+        let extension_words = if extension {
+            header::Extension::WORDS
+        } else {
+            0
+        };
+        self.index.resize(self.index.len() + extension_words * 8, 0);
+        let root = self.header();
+        root.extension_words = extension_words as u8;
+        root.required_features = 0;
+    }
+
+    fn extension(&mut self) -> &mut header::Extension {
+        let start = size_of::<header::Root>();
+        let bytes = &mut self.index[start..start + size_of::<header::Extension>()];
+        let extension: LayoutVerified<_, header::Extension> =
+            LayoutVerified::new(bytes).expect("extension");
+        extension.into_mut()
+    }
+
     /// Creates the resulting vector of bytes that encodes this sequence map.
-    pub fn build(mut self) -> Vec<u8> {
+    /// Tables in which only a few cells are occupied are stored in a compact
+    /// form, which only includes the occupied cells.  Chains of tables which
+    /// have only a single occupied cell each are skipped over, so that keys
+    /// which share many bits do not require many levels of tables.
+    pub fn build(self) -> Vec<u8> {
+        self.build_with_stats().0
+    }
+
+    /// Same as [Builder::build], and also returns statistics about the values
+    /// of the map.
+    pub fn build_with_stats(mut self) -> (Vec<u8>, Stats) {
+        let root_table_offset = self.header().root_table_offset;
+        let trie = layout::Trie::read(&self.index, root_table_offset);
+        self.build_trie(trie)
+    }
+
+    // Builds the map made of the tables of `trie`, which replace those of the
+    // index, and the strings of this builder.
+    fn build_trie(mut self, mut trie: layout::Trie) -> (Vec<u8>, Stats) {
+        let mut strings = std::mem::replace(&mut self.strings, string_slice::Intern::new());
+        let mut layout = self.layout;
+        if self.canonical {
+            let mut canonical = string_slice::Intern::new();
+            let indexes: HashMap<usize, usize> = trie
+                .strings()
+                .into_iter()
+                .map(|(index, _)| (index, canonical.add(strings.get(index).to_str())))
+                .collect();
+            trie.map_strings(|index| indexes[&index]);
+            strings = canonical;
+            if layout == Layout::Insertion {
+                layout = Layout::DepthFirst;
+            }
+        }
+        // The keys of each value, in the order of the values.  Equal values
+        // are only listed once, even if they are stored more than once.
+        let mut reverse_index = vec![];
+        if self.reverse_index {
+            let mut values: BTreeMap<&str, (usize, Vec<u64>)> = BTreeMap::new();
+            for (index, key) in trie.strings() {
+                let value = strings.get(index).to_str();
+                values.entry(value).or_insert((index, vec![])).1.push(key);
+            }
+            reverse_index = values.into_values().collect();
+            for (_, keys) in &mut reverse_index {
+                keys.sort_unstable();
+            }
+        }
+        let uncompressed_len = strings.len();
+        let mut stats = Stats {
+            values: strings.iter().count(),
+            ..Stats::default()
+        };
+        let (mut strings, indexes) = if self.suffix_sharing && self.compression == Compression::None
+        {
+            let (shared, indexes, shared_values) = strings.share_suffixes();
+            stats.shared_values = shared_values;
+            // Counted against each value stored on its own, so that a
+            // map which already shares its values reports the same.
+            let unshared_len: usize = strings
+                .iter()
+                .map(|(_, content)| string_slice::String::required_len(content))
+                .sum();
+            stats.shared_bytes = unshared_len - shared.len();
+            (shared.into(), Some(indexes))
+        } else {
+            compression::compress(strings, self.compression)
+        };
+        stats.string_bytes = strings.len();
+        let options = layout::Options {
+            sparse: true,
+            layout,
+            compact: true,
+            // The string indexes of block compressed strings are based on
+            // the uncompressed strings, which may be longer.
+            strings_len: strings.len().max(uncompressed_len),
+        };
+        // The keys in a dense range are stored in an array instead of the
+        // trie.  Each is given by the string index of its value.
+        let mut dense = None;
+        if self.dense_range {
+            let mut entries = trie.strings();
+            entries.sort_unstable_by_key(|(_, key)| *key);
+            let keys: Vec<u64> = entries.iter().map(|(_, key)| *key).collect();
+            if let Some(range) = dense::find_range(&keys) {
+                let base = *range.start();
+                let mut indexes = vec![dense::ABSENT; (range.end() - base) as usize + 1];
+                for (index, key) in entries {
+                    if range.contains(&key) {
+                        indexes[(key - base) as usize] = index as u64;
+                    }
+                }
+                trie.retain_strings(|key| !range.contains(&key));
+                dense = Some((base, indexes));
+            }
+        }
+        trie.compress();
+        if let Some(indexes) = indexes {
+            trie.map_strings(|index| indexes[&index]);
+            for (index, _) in &mut reverse_index {
+                *index = indexes[index];
+            }
+            if let Some((_, dense_indexes)) = &mut dense {
+                for index in dense_indexes
+                    .iter_mut()
+                    .filter(|index| **index != dense::ABSENT)
+                {
+                    *index = indexes[&(*index as usize)] as u64;
+                }
+            }
+        }
+        let extension = self.reverse_index || dense.is_some();
+        match self.index_kind {
+            IndexKind::Trie => self.write_trie(&trie, options, extension),
+            IndexKind::PerfectHash => {
+                if !self.write_perfect_hash(&trie, extension) {
+                    self.write_trie(&trie, options, extension);
+                }
+            }
+        }
+        if let Some((base, indexes)) = dense {
+            let offset = self.index.len();
+            dense::write(base, &indexes, &mut self.index);
+            self.extension().dense_offset = offset;
+            self.header().required_features |= header::REQUIRE_DENSE;
+        }
+        if self.reverse_index {
+            let offset = self.index.len();
+            reverse::write(&reverse_index, &mut self.index);
+            self.extension().reverse_index_offset = offset;
+        }
         {
             let len = self.index.len();
+            let compression = self.compression;
             // This will fail if nothing has been inserted!
             let root = self.header();
             root.set_string_offset(len);
+            root.string_format = compression as u8;
         }
         let mut result = self.index;
-        let mut strings: Vec<u8> = self.strings.into();
         result.append(&mut strings);
-        result
+        (result, stats)
+    }
+
+    /// Same as inserting each of `entries` into this builder, in order, and
+    /// then calling [Builder::build], except that the work is spread over
+    /// multiple threads.  The keys are split up by the cell of the root table
+    /// that they fall into, and the tables and strings below the cells are
+    /// created concurrently.  Equal strings of different cells are then
+    /// merged concurrently as well, and only the final steps of
+    /// [Builder::build] run on the calling thread.  The resulting map has the
+    /// same contents as one built sequentially, but its bytes may differ.
+    ///
+    /// Panics if anything was inserted into this builder before, or if it was
+    /// created from an existing map.  Requires the `rayon` feature.
+    #[cfg(feature = "rayon")]
+    pub fn build_parallel(mut self, entries: &[(u64, &str)]) -> Vec<u8> {
+        use rayon::prelude::*;
+
+        assert_eq!(
+            0,
+            self.header().root_table_offset,
+            "build_parallel requires an empty builder"
+        );
+        // Each partition holds the keys of a range of cells of the root table.
+        // Each one has a root table of its own, so there are only a few more
+        // of them than threads.
+        let root_bits = self.schedule[0];
+        let count = (rayon::current_num_threads() * 4).min(1 << root_bits);
+        let partition = |key: u64| (((key & ((1 << root_bits) - 1)) as usize) * count) >> root_bits;
+        // Each chunk of the entries is split up into partitions on its own.
+        // The pieces of a partition are inserted in the order of the chunks,
+        // which keeps the order of the entries.
+        let chunk_len = ((entries.len() + count - 1) / count).max(1);
+        let chunks: Vec<Vec<Vec<(u64, &str)>>> = entries
+            .par_chunks(chunk_len)
+            .map(|chunk| {
+                let mut pieces = vec![vec![]; count];
+                for (key, value) in chunk {
+                    pieces[partition(*key)].push((*key, *value));
+                }
+                pieces
+            })
+            .collect();
+        let schedule = &self.schedule;
+        let (mut tries, parts): (Vec<layout::Trie>, Vec<string_slice::Intern>) = (0..count)
+            .into_par_iter()
+            .filter(|partition| chunks.iter().any(|pieces| !pieces[*partition].is_empty()))
+            .map(|partition| {
+                let mut builder = Builder::with_level_bits(schedule.clone());
+                for pieces in &chunks {
+                    for (key, value) in &pieces[partition] {
+                        builder.insert(*key, value);
+                    }
+                }
+                let root_table_offset = builder.header().root_table_offset;
+                let trie = layout::Trie::read(&builder.index, root_table_offset);
+                (trie, builder.strings)
+            })
+            .unzip();
+
+        // Strings which are equal across the parts are only stored once.
+        let (strings, indexes) = string_slice::Intern::merge(&parts);
+        tries
+            .par_iter_mut()
+            .zip(indexes)
+            .for_each(|(trie, indexes)| trie.map_strings(|index| indexes[&index]));
+
+        self.strings = strings;
+        self.build_trie(layout::Trie::join(root_bits, tries)).0
     }
 
-    /// Inserts this `key`-`value` pair into the map.
+    /// Inserts this `key`-`value` pair into the map.  Values are stored as C
+    /// strings, so `value` must not contain NUL characters.
     pub fn insert(&mut self, key: u64, value: &str) {
+        assert!(!value.contains('\0'), "value contains NUL: {:?}", value);
+        self.insert_string(key, |builder| builder.allocate_string(value));
+    }
+
+    // Inserts `key`, with the value at the string index that `allocate`
+    // returns.  It is only called if `key` is not in the map yet.
+    fn insert_string<F>(&mut self, key: u64, allocate: F)
+    where
+        F: FnOnce(&mut Builder) -> usize,
+    {
+        let mut allocate = Some(allocate);
         let root_table_initialized = {
             let root = self.header();
             root.root_table_offset != 0
         };
 
         if !root_table_initialized {
-            let index = self.append_table();
+            let index = self.append_table(0);
             assert_ne!(index, 0);
             let root = self.header();
             root.root_table_offset = index;
@@ -161,12 +728,18 @@ impl Builder {
             header.root_table_offset
         };
         assert_ne!(table_index, 0, "table: {:?}", self.index);
+        let mut level = 0;
         loop {
             if remaining_bits == 0 {
                 break;
             }
             let mut table = header::TableMut::overlay_mut(&mut self.index[table_index..]);
             let index = table.index(running_key);
+            // The key and the number of bits remaining once this table is
+            // descended through.  Tables on different levels may use different
+            // numbers of bits, so these are computed based on this table.
+            let next_running_key = table.next_key(running_key);
+            let next_remaining_bits = table.decrement_bits(remaining_bits);
             // If it is empty, allocate string and put it here.
             // If it is already allocated, allocate new table and move string around.
             // If it is a table pointer, decrement and descend into table.
@@ -176,7 +749,8 @@ impl Builder {
             let cell = (); // Release self.
             match cell_type {
                 cell::Type::Empty => {
-                    let str_index = self.allocate_string(value);
+                    let allocate = allocate.take().expect("allocated once");
+                    let str_index = allocate(self);
                     let mut table = header::TableMut::overlay_mut(&mut self.index[table_index..]);
                     let cell = table.cell_mut(index);
                     cell.become_string_ptr(str_index, key);
@@ -202,14 +776,15 @@ impl Builder {
                         return;
                     }
 
-                    // Adjust the key of the string which was already there to the same
-                    // number of remaining bits.
-                    assert!(remaining_bits <= 64, "remaining_bits: {}", remaining_bits);
-                    let new_str_key = str_key >> (64 - remaining_bits);
+                    // Adjust the key of the string which was already there to the
+                    // number of bits remaining below this table.  Distinct keys can not
+                    // end up in the same cell once all bits are used up.
+                    assert!(next_remaining_bits > 0, "key: {}", key);
+                    let new_str_key = str_key >> (64 - next_remaining_bits);
 
                     // Create a new table to place the old string into.  Once created,
                     // make a pointer from this cell to the new table.
-                    let new_table_index = self.append_table();
+                    let new_table_index = self.append_table(level + 1);
                     let mut table = header::TableMut::overlay_mut(&mut self.index[table_index..]);
                     let cell = table.cell_mut(index);
                     cell.become_table_ptr(new_table_index);
@@ -217,7 +792,6 @@ impl Builder {
                     // Place the old string into the new table.
                     let mut new_table =
                         header::TableMut::overlay_mut(&mut self.index[new_table_index..]);
-                    let new_str_key = new_table.next_key(new_str_key);
                     let new_cell_index = new_table.index(new_str_key);
                     let cell = new_table.cell_mut(new_cell_index);
                     cell.become_string_ptr(str_index, str_key);
@@ -230,121 +804,797 @@ impl Builder {
                     let mut table = header::TableMut::overlay_mut(&mut self.index[table_index..]);
                     let cell = table.cell_mut(index);
                     table_index = cell.table_index();
-                    let table = header::TableMut::overlay_mut(&mut self.index[table_index..]);
-                    running_key = table.next_key(running_key);
-                    remaining_bits = table.decrement_bits(remaining_bits);
+                    running_key = next_running_key;
+                    remaining_bits = next_remaining_bits;
+                    level += 1;
                 }
-                cell::Type::Unknown => panic!("unknown cell type"),
+                // The builder expands skip cells before updating tables in place.
+                cell::Type::Skip | cell::Type::Unknown => panic!("unexpected cell type"),
             }
         }
     }
+
+    /// Removes `key` from the map, returning `true` if the key was present.
+    /// The space taken up by the value is not reclaimed, but the value is
+    /// reused if it is inserted again.
+    pub fn remove(&mut self, key: u64) -> bool {
+        let mut table_index = {
+            let header = self.header();
+            header.root_table_offset
+        };
+        if table_index == 0 {
+            // Nothing was ever inserted.
+            return false;
+        }
+        let mut running_key = key;
+        loop {
+            let mut table = header::TableMut::overlay_mut(&mut self.index[table_index..]);
+            let index = table.index(running_key);
+            running_key = table.next_key(running_key);
+            let cell = table.cell_mut(index);
+            match cell.get_type() {
+                cell::Type::Empty => return false,
+                cell::Type::StringPtr => {
+                    let (_, str_key) = cell.string_index_and_key();
+                    if str_key != key {
+                        return false;
+                    }
+                    cell.become_empty();
+                    return true;
+                }
+                cell::Type::TablePtr => {
+                    table_index = cell.table_index();
+                }
+                // The builder expands skip cells before updating tables in place.
+                cell::Type::Skip | cell::Type::Unknown => panic!("unexpected cell type"),
+            }
+        }
+    }
+}
+
+/// The reasons for which a buffer can not be used as a [Map].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Error {
+    /// The buffer does not start at an address aligned to [ALIGNMENT] bytes.
+    Unaligned,
+    /// The buffer is too short to hold a map.
+    TooShort,
+    /// The buffer does not start with the header of a map.
+    NotAMap,
+    /// The header of the map is inconsistent with the buffer, or, for
+    /// [Builder::try_from_bytes], the tables or values of the map are damaged.
+    Corrupt,
+    /// The map uses features which this version does not support.
+    Unsupported,
+    /// The values of the map are compressed, so that they can not be borrowed
+    /// from it.  Such maps are looked up with a [CompressedMap].
+    Compressed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unaligned => write!(f, "map buffer must be aligned to {} bytes", ALIGNMENT),
+            Error::TooShort => write!(f, "map buffer is too short"),
+            Error::NotAMap => write!(f, "map buffer does not start with a map header"),
+            Error::Corrupt => write!(f, "map buffer is corrupt"),
+            Error::Unsupported => write!(f, "map buffer uses unsupported features"),
+            Error::Compressed => write!(f, "map buffer has compressed values"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Statistics about the values of a map, as returned by
+/// [Builder::build_with_stats].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// The number of distinct values.  Values which were removed from the
+    /// builder, but not inserted again, are included.
+    pub values: usize,
+    /// The number of values which are stored within other values.  See
+    /// [Builder::set_suffix_sharing].
+    pub shared_values: usize,
+    /// The number of bytes which storing values within others saved, compared
+    /// to storing each value on its own.
+    pub shared_bytes: usize,
+    /// The size of the section which holds the values, in bytes.
+    pub string_bytes: usize,
 }
 
 /// A read-only [Map], backed by a linear buffer.  The contents of that buffer
 /// are expected to have been generated with [Builder].
 pub struct Map<'a> {
     rep: &'a [u8],
+    strings: compression::Strings<'a>,
+    // The index of the keys, unless they are in a trie.
+    perfect_hash: Option<perfect_hash::Index<'a>>,
+    // The keys in a dense range, which are not in the index.
+    dense: Option<dense::Segment<'a>>,
+}
+
+// The number of lookups that Map::get_many interleaves.
+const LANES: usize = 8;
+
+// The state of the lookup of a single key, part way down the trie.
+#[derive(Clone, Copy)]
+struct Walk {
+    key: u64,
+    // The key bits not yet used for indexing.
+    running_key: u64,
+    remaining_bits: usize,
+    // The offset of the table that the lookup is at.
+    table_index: usize,
+}
+
+// The result of looking up a key in a single table.
+enum Step {
+    // The lookup continues in another table.
+    Descend,
+    // The key maps to the string at this index in the intern table.
+    Found(usize),
+    Missing,
+}
+
+/// An iterator over the keys and values of a [Map].  See [Map::iter].
+pub struct Iter<'a> {
+    map: &'a Map<'a>,
+    // The keys of the dense segment which are not visited yet.
+    dense: dense::Entries<'a>,
+    // The keys of a perfect hash index which are not visited yet.
+    slots: perfect_hash::Entries<'a>,
+    // The tables on the path to the next cell to visit.
+    stack: Vec<Frame<'a>>,
+    // The offsets of all the tables visited so far.  Each table is only
+    // visited once, so that corrupt maps in which tables are shared, or form
+    // a cycle, do not take exponential time.
+    visited: HashSet<usize>,
+}
+
+// A table that an Iter is in the middle of.
+struct Frame<'a> {
+    table: header::Table<'a>,
+    // The next slot of the table to visit.
+    slot: usize,
+    // The key bits used to get to the table.
+    path: u64,
+    used_bits: usize,
+    // The number of key bits not used by the tables up to and including this
+    // one.
+    remaining_bits: usize,
+}
+
+impl<'a> Iter<'a> {
+    // Visits the table at `offset` next, unless it is not a valid table.
+    fn push(&mut self, offset: usize, remaining_bits: usize, path: u64) {
+        if offset == 0 || remaining_bits == 0 || !self.visited.insert(offset) {
+            return;
+        }
+        let table = match self
+            .map
+            .rep
+            .get(offset..)
+            .and_then(header::Table::try_overlay)
+        {
+            Some(table) => table,
+            None => return,
+        };
+        self.stack.push(Frame {
+            remaining_bits: table.decrement_bits(remaining_bits),
+            table,
+            slot: 0,
+            path,
+            used_bits: 64 - remaining_bits,
+        });
+    }
+
+    // Returns the key and the string index of the next string cell.
+    fn next_string(&mut self) -> Option<(u64, usize)> {
+        if let Some(entry) = self.dense.next() {
+            return Some(entry);
+        }
+        if let Some(entry) = self.slots.next() {
+            return Some(entry);
+        }
+        loop {
+            let frame = self.stack.last_mut()?;
+            if frame.slot >= 1 << frame.table.bits() {
+                self.stack.pop();
+                continue;
+            }
+            let slot = frame.slot;
+            frame.slot += 1;
+            let cell = frame.table.cell(slot);
+            let remaining_bits = frame.remaining_bits;
+            let path = frame.path
+                | (slot as u64)
+                    .checked_shl(frame.used_bits as u32)
+                    .unwrap_or(0);
+            match cell.get_type() {
+                cell::Type::StringPtr => {
+                    let (index, key) = cell
+                        .to_instance(remaining_bits, path)
+                        .string_index_and_key();
+                    return Some((key, index));
+                }
+                cell::Type::TablePtr => self.push(cell.table_index(), remaining_bits, path),
+                cell::Type::Skip => {
+                    let (table, bits, pattern) = cell.skip();
+                    if (bits as usize) < remaining_bits {
+                        let path = path | pattern << (64 - remaining_bits);
+                        self.push(table, remaining_bits - bits as usize, path);
+                    }
+                }
+                cell::Type::Empty | cell::Type::Unknown => {}
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (u64, &'a str);
+
+    fn next(&mut self) -> Option<(u64, &'a str)> {
+        loop {
+            let (key, index) = self.next_string()?;
+            let value = self.map.string(index).and_then(|cstr| cstr.to_str().ok());
+            if let Some(value) = value {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+/// An iterator over the keys and values of a [CompressedMap], which decodes
+/// compressed values.  See [CompressedMap::iter].
+pub struct CowIter<'a> {
+    iter: Iter<'a>,
+}
+
+impl<'a> Iterator for CowIter<'a> {
+    type Item = (u64, Cow<'a, str>);
+
+    fn next(&mut self) -> Option<(u64, Cow<'a, str>)> {
+        loop {
+            let (key, index) = self.iter.next_string()?;
+            if let Some(value) = self.iter.map.value(index) {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+// Hints the CPU to start loading the memory at `address` into the cache.
+#[allow(unused_unsafe)]
+fn prefetch(address: *const u8) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        // Prefetching is only a hint, and never faults, even on invalid
+        // addresses.
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch(address as *const i8, _MM_HINT_T0);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = address;
 }
 
 impl<'a> Map<'a> {
     /// Creates a new [Map], with a representation based on the passed in slice
     /// `rep`.  The contents of `rep` are opaque.
+    ///
+    /// The start of `rep` must be aligned to [ALIGNMENT] bytes.  The heap
+    /// allocations backing a `Vec<u8>`, such as the one returned by
+    /// [Builder::build] or `std::fs::read`, are aligned this way by the common
+    /// system allocators, but a slice starting at an arbitrary offset within
+    /// them, or within a memory mapped file, may not be.
+    ///
+    /// Panics if `rep` is not a valid map.  See [Map::try_new] for a version
+    /// which returns an error instead.
     pub fn new(rep: &'a [u8]) -> Map<'a> {
-        Map { rep }
+        Map::try_new(rep).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Same as [Map::new], except that the problems found with `rep` are
+    /// returned as an [Error].  Only the map header is checked, so this is
+    /// cheap even for large maps.  Maps with compressed values are rejected
+    /// with [Error::Compressed], and are looked up with a [CompressedMap].
+    pub fn try_new(rep: &'a [u8]) -> Result<Map<'a>, Error> {
+        let map = Map::open(rep)?;
+        if map.compression() != Compression::None {
+            return Err(Error::Compressed);
+        }
+        Ok(map)
+    }
+
+    // Same as Map::try_new, except that maps with compressed values are
+    // accepted as well.  Of the lookup methods, only those which decode values
+    // may be used on such maps.
+    pub(crate) fn open(rep: &'a [u8]) -> Result<Map<'a>, Error> {
+        if rep.as_ptr() as usize % ALIGNMENT != 0 {
+            return Err(Error::Unaligned);
+        }
+        if rep.len() < size_of::<header::Root>() {
+            return Err(Error::TooShort);
+        }
+        let mut map = Map {
+            rep,
+            strings: compression::Strings::Plain,
+            perfect_hash: None,
+            dense: None,
+        };
+        let root = map.header();
+        if root.htype != header::Type::Root as header::TypeSize {
+            return Err(Error::NotAMap);
+        }
+        if root.required_features & !header::KNOWN_FEATURES != 0 {
+            return Err(Error::Unsupported);
+        }
+        let table_offset = root.root_table_offset;
+        let string_offset = root.string_offset;
+        let header_size = size_of::<header::Root>() + root.extension_words as usize * 8;
+        let extension = header::Extension::read(rep, root.extension_words);
+        let strings = rep
+            .get(string_offset..)
+            .filter(|_| string_offset >= header_size)
+            .and_then(|strings| compression::Strings::read(root.string_format, strings));
+        let strings_consistent = strings.is_some();
+        let reverse_index_offset = extension
+            .as_ref()
+            .map_or(0, |extension| extension.reverse_index_offset);
+        let dense_offset = extension
+            .as_ref()
+            .map_or(0, |extension| extension.dense_offset);
+        // Like the reverse index, the dense segment is between the tables and
+        // the strings.
+        let dense = rep
+            .get(dense_offset..string_offset)
+            .filter(|_| dense_offset >= header_size)
+            .and_then(dense::Segment::read);
+        // The keys of maps with a perfect hash index are not in a trie.
+        let perfect_hash = rep
+            .get(table_offset..string_offset)
+            .filter(|_| table_offset != 0 && root.index_kind == IndexKind::PerfectHash as u8)
+            .and_then(perfect_hash::Index::read);
+        let index_consistent = root.index_kind == IndexKind::Trie as u8
+            || (root.index_kind == IndexKind::PerfectHash as u8
+                && (table_offset == 0 || perfect_hash.is_some()));
+        let consistent = root.level_bits().iter().map(|bits| *bits as usize).sum::<usize>() >= 64
+            && extension.is_some()
+            && strings_consistent
+            && index_consistent
+            // A map has a dense segment if, and only if, it requires it.
+            && (dense_offset != 0) == (root.required_features & header::REQUIRE_DENSE != 0)
+            && (dense_offset == 0 || dense.is_some())
+            // The root table offset is zero in maps without any keys.
+            && (table_offset == 0
+                || (table_offset >= header_size
+                    && table_offset % ALIGNMENT == 0
+                    && table_offset <= string_offset - size_of::<header::TableHeader>()))
+            // The reverse index is between the tables and the strings.
+            && (reverse_index_offset == 0
+                || (reverse_index_offset >= header_size
+                    && rep
+                        .get(reverse_index_offset..string_offset)
+                        .and_then(reverse::Index::read)
+                        .is_some()));
+        if !consistent {
+            return Err(Error::Corrupt);
+        }
+        map.strings = strings.expect("consistent strings");
+        map.perfect_hash = perfect_hash;
+        map.dense = dense;
+        Ok(map)
     }
 
     /// Looks up `key`, returning the found value in the form of a C string.
     /// (Because it's possible).
     pub fn get_cstr(&'a self, key: u64) -> Option<&'a ffi::CStr> {
-        use std::ffi::CStr;
-        use std::os::raw::c_char;
+        self.find(key)
+            .and_then(|string_index| self.string(string_index))
+    }
 
-        let (table_index, string_offset) = {
-            let header = self.header();
-            (header.root_table_offset, header.string_offset)
-        };
-        assert!(table_index > 0);
-        let mut remaining_bits = 64;
-        let mut running_key = key;
-        let mut running_table_index = table_index;
+    // Returns the string index of the value of `key`, if it is in the map.
+    #[inline]
+    pub(crate) fn find(&'a self, key: u64) -> Option<usize> {
+        if let Some(found) = self.dense.as_ref().and_then(|dense| dense.get(key)) {
+            return found;
+        }
+        if let Some(perfect_hash) = &self.perfect_hash {
+            return perfect_hash.get(key);
+        }
+        let mut walk = self.walk(key);
         loop {
-            if remaining_bits == 0 {
-                break;
+            match self.step(&mut walk) {
+                Step::Descend => {}
+                Step::Found(string_index) => return Some(string_index),
+                Step::Missing => return None,
             }
-            let table = header::Table::overlay(&self.rep[running_table_index..]);
-            let index = table.index(running_key);
-            let cell = table.cell(index);
-            let cell_type = cell.get_type();
-            match cell_type {
-                cell::Type::Empty => {
-                    return None;
+        }
+    }
+
+    /// Looks up each of `keys`, and stores the found strings into the
+    /// corresponding elements of `values`.  This is faster than calling
+    /// [Map::get] for each key in turn, since the lookups of several keys are
+    /// interleaved, so that the memory accesses for one key overlap with the
+    /// work on the others.
+    ///
+    /// Panics if `keys` and `values` are not of the same length.
+    pub fn get_many(&'a self, keys: &[u64], values: &mut [Option<&'a str>]) {
+        assert_eq!(
+            keys.len(),
+            values.len(),
+            "keys and values must be of the same length"
+        );
+        if self.perfect_hash.is_some() {
+            // Each lookup is a single probe already.
+            for (key, value) in keys.iter().zip(values) {
+                *value = self.get(*key);
+            }
+            return;
+        }
+        let string_offset = self.header().string_offset;
+        for (keys, values) in keys.chunks(LANES).zip(values.chunks_mut(LANES)) {
+            let mut walks = [self.walk(0); LANES];
+            for (walk, key) in walks.iter_mut().zip(keys) {
+                *walk = self.walk(*key);
+            }
+            // The string indexes found so far, which are read once all the
+            // walks are done.
+            let mut found = [None; LANES];
+            let mut done = [false; LANES];
+            let mut pending = keys.len();
+            // Keys in the dense range need no walk.
+            if let Some(dense) = &self.dense {
+                for (lane, key) in keys.iter().enumerate() {
+                    if let Some(string_index) = dense.get(*key) {
+                        found[lane] = string_index;
+                        done[lane] = true;
+                        pending -= 1;
+                    }
                 }
-                cell::Type::StringPtr => {
-                    let (string_index, string_key) = cell.string_index_and_key();
-                    match key == string_key {
-                        false => return None,
-                        true => {
-                            // Find that string.
-                            let string_index = string_offset + string_index;
-                            let cstr = unsafe {
-                                // We know that the strings in the intern table
-                                // are C strings (UTF-8 with a trailing '/0').
-                                let ptr = self.rep[string_index..].as_ptr() as *const c_char;
-                                CStr::from_ptr(ptr)
-                            };
-                            return Some(cstr);
+            }
+            while pending > 0 {
+                for lane in 0..keys.len() {
+                    if done[lane] {
+                        continue;
+                    }
+                    let walk = &mut walks[lane];
+                    match self.step(walk) {
+                        Step::Descend => prefetch(self.rep.as_ptr().wrapping_add(walk.table_index)),
+                        Step::Found(string_index) => {
+                            let string = string_offset.wrapping_add(string_index);
+                            prefetch(self.rep.as_ptr().wrapping_add(string));
+                            found[lane] = Some(string_index);
+                            done[lane] = true;
+                            pending -= 1;
+                        }
+                        Step::Missing => {
+                            done[lane] = true;
+                            pending -= 1;
                         }
                     }
                 }
-                cell::Type::TablePtr => {
-                    remaining_bits = table.decrement_bits(remaining_bits);
-                    running_key = table.next_key(running_key);
-                    running_table_index = cell.table_index();
-                    // Descend one level deeper.
+            }
+            for (value, string_index) in values.iter_mut().zip(&found) {
+                *value = string_index
+                    .and_then(|string_index| self.string(string_index))
+                    .and_then(|cstr| cstr.to_str().ok());
+            }
+        }
+    }
+
+    // Starts the lookup of `key` at the root table.
+    fn walk(&'a self, key: u64) -> Walk {
+        let table_index = self.header().root_table_offset;
+        Walk {
+            key,
+            running_key: key,
+            // Maps without any keys have no root table to look in.
+            remaining_bits: if table_index == 0 { 0 } else { 64 },
+            table_index,
+        }
+    }
+
+    // Looks up the key of `walk` in the table that it is at.
+    fn step(&'a self, walk: &mut Walk) -> Step {
+        if walk.remaining_bits == 0 {
+            return Step::Missing;
+        }
+        let table = match self
+            .rep
+            .get(walk.table_index..)
+            .and_then(header::Table::try_overlay)
+        {
+            Some(table) => table,
+            None => return Step::Missing,
+        };
+        let index = table.index(walk.running_key);
+        let cell = table.cell(index);
+        let cell_type = cell.get_type();
+        match cell_type {
+            cell::Type::Empty => Step::Missing,
+            cell::Type::StringPtr => {
+                match cell.is_string_for(walk.key, table.next_key(walk.running_key)) {
+                    false => Step::Missing,
+                    true => Step::Found(cell.string_index()),
                 }
-                cell::Type::Unknown => {
-                    panic!("reached unknown cell");
+            }
+            cell::Type::TablePtr => {
+                walk.remaining_bits = table.decrement_bits(walk.remaining_bits);
+                walk.running_key = table.next_key(walk.running_key);
+                walk.table_index = cell.table_index();
+                // Descend one level deeper.
+                Step::Descend
+            }
+            cell::Type::Skip => {
+                walk.remaining_bits = table.decrement_bits(walk.remaining_bits);
+                walk.running_key = table.next_key(walk.running_key);
+                let (table_index, bits, pattern) = cell.skip();
+                // The skipped levels would each have had only this key in
+                // them, so any other key is not in the map.
+                if walk.running_key & ((1 << bits) - 1) != pattern
+                    || bits as usize > walk.remaining_bits
+                {
+                    return Step::Missing;
                 }
+                walk.remaining_bits -= bits as usize;
+                walk.running_key >>= bits;
+                walk.table_index = table_index;
+                // Descend several levels deeper.
+                Step::Descend
             }
+            // Only in corrupt maps.
+            cell::Type::Unknown => Step::Missing,
         }
-        None
     }
 
     /// Looks up `key` in the map, returning the found string if possible.
     pub fn get(&'a self, key: u64) -> Option<&'a str> {
-        self.get_cstr(key)
-            .map(|cstr| cstr.to_str().expect("UTF-8 encoding"))
+        self.find(key)
+            .and_then(|string_index| self.string(string_index))
+            .and_then(|cstr| cstr.to_str().ok())
+    }
+
+    /// Returns an iterator over all the keys in the map, and their values.
+    /// The keys are returned in no particular order.
+    pub fn iter(&'a self) -> Iter<'a> {
+        self.iter_strings()
+    }
+
+    // Returns an iterator over all the keys in the map, and their values,
+    // whether they are compressed or not.
+    fn iter_strings(&'a self) -> Iter<'a> {
+        let mut iter = Iter {
+            map: self,
+            dense: self.dense.clone().unwrap_or_default().entries(),
+            slots: perfect_hash::Entries::default(),
+            stack: vec![],
+            visited: HashSet::new(),
+        };
+        match &self.perfect_hash {
+            Some(perfect_hash) => iter.slots = perfect_hash.entries(),
+            None => iter.push(self.header().root_table_offset, 64, 0),
+        }
+        iter
+    }
+
+    /// Returns the keys which map to `value`, in ascending order.  Finds
+    /// nothing unless the map was built with a reverse index, see
+    /// [Builder::set_reverse_index].
+    pub fn keys_for(&'a self, value: &str) -> impl Iterator<Item = u64> + 'a {
+        let keys = self
+            .reverse_index()
+            .and_then(|reverse_index| {
+                // The values are in the order of their contents, which is
+                // searched for the first one which is not less than `value`.
+                // Values which cannot be read count as less.
+                let (mut low, mut high) = (0, reverse_index.len());
+                while low < high {
+                    let middle = low + (high - low) / 2;
+                    let found = self.value(reverse_index.string_index(middle));
+                    if found.map_or(true, |found| *found < *value) {
+                        low = middle + 1;
+                    } else {
+                        high = middle;
+                    }
+                }
+                if low == reverse_index.len() {
+                    return None;
+                }
+                let found = self.value(reverse_index.string_index(low))?;
+                (*found == *value).then(|| reverse_index.keys(low))
+            })
+            .unwrap_or(&[]);
+        keys.iter().copied()
+    }
+
+    // Returns how the values of the map are stored.  This is always
+    // Compression::None, unless the map was created with Map::open.
+    pub(crate) fn compression(&self) -> Compression {
+        match self.strings {
+            compression::Strings::Plain => Compression::None,
+            compression::Strings::Symbols(_) => Compression::Symbols,
+            compression::Strings::Blocks(_) => Compression::Blocks,
+        }
+    }
+
+    // Returns the string at `string_index` in the intern table, or `None` if
+    // there is no string there in a corrupt map.  The strings must not be
+    // compressed.
+    fn string(&'a self, string_index: usize) -> Option<&'a ffi::CStr> {
+        let string_index = self.header().string_offset.checked_add(string_index)?;
+        ffi::CStr::from_bytes_until_nul(self.rep.get(string_index..)?).ok()
+    }
+
+    // Returns the value at `string_index` in the intern table, decoding it if
+    // the strings are compressed.
+    pub(crate) fn value(&'a self, string_index: usize) -> Option<Cow<'a, str>> {
+        match &self.strings {
+            compression::Strings::Plain => self
+                .string(string_index)
+                .and_then(|cstr| cstr.to_str().ok())
+                .map(Cow::Borrowed),
+            compression::Strings::Symbols(symbols) => symbols
+                .decode(string_index)
+                .map(|(value, _)| Cow::Owned(value)),
+            compression::Strings::Blocks(blocks) => blocks.decode(string_index).map(Cow::Owned),
+        }
     }
 
     fn header(&'a self) -> &'a header::Root {
         assert!(self.rep.len() >= size_of::<header::Root>());
         let (root, _): (LayoutVerified<_, header::Root>, _) =
-            LayoutVerified::new_from_prefix(&self.rep[..]).expect("header check");
+            LayoutVerified::new_from_prefix(self.rep).expect("header check");
         root.into_ref()
     }
+
+    // Returns the reverse index of the map, if it has one.
+    fn reverse_index(&'a self) -> Option<reverse::Index<'a>> {
+        let root = self.header();
+        let extension = header::Extension::read(self.rep, root.extension_words)?;
+        if extension.reverse_index_offset == 0 {
+            return None;
+        }
+        reverse::Index::read(
+            self.rep
+                .get(extension.reverse_index_offset..root.string_offset)?,
+        )
+    }
 }
 
-#[cfg(test)]
-mod tests {
+/// A read-only map, like [Map], whose values may be compressed.  Compressed
+/// values can not be borrowed from the map, so they are decoded into a new
+/// `String` each time they are looked up.  The values of maps without
+/// compression are borrowed, as with [Map].  See [Builder::set_compression].
+///
+/// Example:
+///
+/// ```rust
+/// use sequence_map::{Builder, CompressedMap, Compression, Error, Map};
+///
+/// let mut builder = Builder::new(4);
+/// builder.set_compression(Compression::Symbols);
+/// builder.insert(42, "Hello!");
+/// let bytes = builder.build();
+///
+/// assert_eq!(Some(Error::Compressed), Map::try_new(&bytes).err());
+/// let map = CompressedMap::new(&bytes);
+/// assert_eq!("Hello!", map.get(42).unwrap());
+/// ```
+pub struct CompressedMap<'a> {
+    map: Map<'a>,
+}
 
-    use super::*;
-    use std::collections::BTreeMap;
+impl<'a> CompressedMap<'a> {
+    /// Creates a new [CompressedMap], with a representation based on the
+    /// passed in slice `rep`.  The same requirements as for [Map::new] apply,
+    /// except that the values may be compressed.
+    ///
+    /// Panics if `rep` is not a valid map.  See [CompressedMap::try_new] for
+    /// a version which returns an error instead.
+    pub fn new(rep: &'a [u8]) -> CompressedMap<'a> {
+        CompressedMap::try_new(rep).unwrap_or_else(|err| panic!("{}", err))
+    }
 
-    #[test]
-    fn basic() {
-        let mut builder = Builder::new(2);
-        builder.insert(42, "Hello!");
-        builder.insert(84, "World!");
+    /// Same as [CompressedMap::new], except that the problems found with
+    /// `rep` are returned as an [Error], as by [Map::try_new].
+    pub fn try_new(rep: &'a [u8]) -> Result<CompressedMap<'a>, Error> {
+        Ok(CompressedMap {
+            map: Map::open(rep)?,
+        })
+    }
+
+    /// Looks up `key`, returning the found value.  Compressed values are
+    /// decoded into a new `String`.
+    pub fn get(&'a self, key: u64) -> Option<Cow<'a, str>> {
+        self.map
+            .find(key)
+            .and_then(|string_index| self.map.value(string_index))
+    }
+
+    /// Returns an iterator over all the keys in the map, and their values,
+    /// which are returned as by [CompressedMap::get].  The keys are returned
+    /// in no particular order.
+    pub fn iter(&'a self) -> CowIter<'a> {
+        CowIter {
+            iter: self.map.iter_strings(),
+        }
+    }
+
+    /// Same as [Map::keys_for].
+    pub fn keys_for(&'a self, value: &str) -> impl Iterator<Item = u64> + 'a {
+        self.map.keys_for(value)
+    }
+
+    /// Returns how the values of the map are stored.
+    pub fn compression(&self) -> Compression {
+        self.map.compression()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn basic() {
+        let mut builder = Builder::new(2);
+        builder.insert(42, "Hello!");
+        builder.insert(84, "World!");
         let expected: Vec<u8> = vec![
-            1, 0, 0, 0, 0, 0, 0, 0, 24, 0, 0, 0, 0, 0, 0, 0, 108, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0,
-            0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 7, 0, 0, 0, 0, 0, 0, 0, 84, 0, 0, 0, 0, 0, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 72, 101, 108, 108,
-            111, 33, 0, 87, 111, 114, 108, 100, 33, 0,
+            1, 0, 0, 0, 0, 0, 0, 0, 56, 0, 0, 0, 0, 0, 0, 0, 104, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2,
+            2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+            0, 0, 0, 0, 0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0, 57, 0, 0, 0, 21, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 1, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 72, 101, 108, 108, 111, 33,
+            0, 87, 111, 114, 108, 100, 33, 0,
         ];
         assert_eq!(expected, builder.build());
     }
 
+    #[test]
+    #[should_panic(expected = "aligned")]
+    fn unaligned_buffer() {
+        let mut builder = Builder::new(2);
+        builder.insert(42, "Hello!");
+        let bytes = builder.build();
+        let mut shifted = vec![0; bytes.len() + 1];
+        shifted[1..].copy_from_slice(&bytes);
+        Map::new(&shifted[1..]);
+    }
+
+    #[test]
+    fn invalid_buffers() {
+        let mut builder = Builder::new(2);
+        builder.insert(42, "Hello!");
+        let bytes = builder.build();
+        assert!(Map::try_new(&bytes).is_ok());
+
+        let mut shifted = vec![0; bytes.len() + 1];
+        shifted[1..].copy_from_slice(&bytes);
+        assert_eq!(Error::Unaligned, Map::try_new(&shifted[1..]).err().unwrap());
+        assert_eq!(Error::TooShort, Map::try_new(&bytes[..8]).err().unwrap());
+        assert_eq!(Error::NotAMap, Map::try_new(&vec![0; 256]).err().unwrap());
+        assert_eq!(
+            Error::Corrupt,
+            Map::try_new(&bytes[..bytes.len() - 1]).err().unwrap()
+        );
+
+        let mut corrupt = bytes.clone();
+        // Points the root table past the end of the buffer.
+        corrupt[8] = 0xff;
+        assert_eq!(Error::Corrupt, Map::try_new(&corrupt).err().unwrap());
+        corrupt[8..16].copy_from_slice(&usize::MAX.to_ne_bytes());
+        assert_eq!(Error::Corrupt, Map::try_new(&corrupt).err().unwrap());
+        let mut corrupt = bytes.clone();
+        // Points the strings into the root header.
+        corrupt[16..24].copy_from_slice(&8_usize.to_ne_bytes());
+        assert_eq!(Error::Corrupt, Map::try_new(&corrupt).err().unwrap());
+    }
+
     #[test]
     fn no_insert() {
         let builder = Builder::new(2);
@@ -387,6 +1637,1000 @@ mod tests {
         assert_eq!("World!", lookup.get(0x11_11_11).unwrap());
     }
 
+    #[test]
+    fn schedule() {
+        let mut reference_map = BTreeMap::new();
+        let mut builder = Builder::with_schedule(&[8, 3]);
+        for entry in (0..2000).chain((0..64).map(|bit| 1 << bit)) {
+            let entry_str = format!("entry_{}", entry);
+            reference_map.insert(entry, entry_str.clone());
+            builder.insert(entry, &entry_str);
+        }
+        let buffer = builder.build();
+        let lookup = Map::new(&buffer);
+        assert_eq!(
+            &[8, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 2],
+            lookup.header().level_bits()
+        );
+        for (key, value) in &reference_map {
+            assert_eq!(lookup.get(*key).unwrap(), *value, "key={}", key);
+        }
+        assert!(lookup.get(3000).is_none());
+
+        // The schedule survives a round trip through the builder.
+        let mut builder = Builder::from_map(&lookup);
+        builder.insert(3000, "Hello!");
+        let buffer = builder.build();
+        let lookup = Map::new(&buffer);
+        assert_eq!("Hello!", lookup.get(3000).unwrap());
+        for (key, value) in &reference_map {
+            assert_eq!(lookup.get(*key).unwrap(), *value, "key={}", key);
+        }
+    }
+
+    #[test]
+    fn sparse_tables() {
+        let mut builder = Builder::new(16);
+        builder.insert(42, "Hello!");
+        builder.insert(84, "World!");
+        builder.insert(42 + (1 << 16), "Again!");
+        let bytes = builder.build();
+        // There are two tables, each of which would take up more than a
+        // megabyte if it were dense.
+        assert!(bytes.len() < 40_000, "len: {}", bytes.len());
+
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(42).unwrap());
+        assert_eq!("World!", lookup.get(84).unwrap());
+        assert_eq!("Again!", lookup.get(42 + (1 << 16)).unwrap());
+        assert!(lookup.get(43).is_none());
+        assert!(lookup.get(42 + (2 << 16)).is_none());
+
+        // Sparse tables are expanded again when rebuilding.
+        let mut builder = Builder::from_map(&lookup);
+        builder.insert(43, "Diddy!");
+        let rebuilt = builder.build();
+        let lookup = Map::new(&rebuilt);
+        assert_eq!("Diddy!", lookup.get(43).unwrap());
+        assert_eq!("Again!", lookup.get(42 + (1 << 16)).unwrap());
+        assert_eq!(bytes, Builder::from_bytes(bytes.clone()).build());
+    }
+
+    #[test]
+    fn path_compression() {
+        let mut builder = Builder::new(2);
+        builder.insert(0, "Hello!");
+        builder.insert(1 << 62, "World!");
+        builder.insert(1, "Again!");
+        let bytes = builder.build();
+        // The root table, and the table at the end of the chain.  The skip cell
+        // in the root table skips too many bits for it to use compact cells.
+        let wide_table = size_of::<header::TableHeader>() + 4 * size_of::<cell::Instance>();
+        let table = size_of::<header::TableHeader>() + 4 * size_of::<cell::Compact>();
+        assert_eq!(
+            size_of::<header::Root>() + wide_table + table + "Hello!World!Again!".len() + 3,
+            bytes.len()
+        );
+
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(0).unwrap());
+        assert_eq!("World!", lookup.get(1 << 62).unwrap());
+        assert_eq!("Again!", lookup.get(1).unwrap());
+        assert!(lookup.get(1 << 40).is_none());
+        assert!(lookup.get(1 << 63).is_none());
+        assert!(lookup.get(3 << 62).is_none());
+
+        // Insert into the middle of the skipped chain.
+        let mut builder = Builder::from_map(&lookup);
+        builder.insert(1 << 40, "Diddy!");
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(0).unwrap());
+        assert_eq!("World!", lookup.get(1 << 62).unwrap());
+        assert_eq!("Diddy!", lookup.get(1 << 40).unwrap());
+        assert!(lookup.get(1 << 41).is_none());
+        assert_eq!(bytes, Builder::from_bytes(bytes.clone()).build());
+
+        // Removing keys leaves tables behind, which are dropped on build.  The
+        // removed strings are not.
+        let mut builder = Builder::from_map(&lookup);
+        assert!(builder.remove(1 << 40));
+        assert!(builder.remove(1 << 62));
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(0).unwrap());
+        assert!(lookup.get(1 << 62).is_none());
+        assert_eq!(
+            size_of::<header::Root>() + table + "Hello!World!Again!Diddy!".len() + 4,
+            bytes.len()
+        );
+    }
+
+    #[test]
+    fn compact_cells() {
+        let mut builder = Builder::new(4);
+        builder.insert(0x1, "Hello!");
+        builder.insert(0x11, "World!");
+        builder.insert(0xffff_ffff_ffff_fff1, "Again!");
+        builder.insert(0x2, "Diddy!");
+        let bytes = builder.build();
+        // Both tables are sparse.  The root table holds small key residuals
+        // only, but the table below it needs wide cells for the largest key.
+        let tables = 2 * (size_of::<header::TableHeader>() + size_of::<header::SparseWord>())
+            + 2 * size_of::<cell::Compact>()
+            + 3 * size_of::<cell::Instance>();
+        assert_eq!(
+            size_of::<header::Root>() + tables + "Hello!World!Again!Diddy!".len() + 4,
+            bytes.len()
+        );
+
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(0x1).unwrap());
+        assert_eq!("World!", lookup.get(0x11).unwrap());
+        assert_eq!("Again!", lookup.get(0xffff_ffff_ffff_fff1).unwrap());
+        assert_eq!("Diddy!", lookup.get(0x2).unwrap());
+        assert!(lookup.get(0x21).is_none());
+        assert!(lookup.get(0x101).is_none());
+        assert!(lookup.get(0x12).is_none());
+        assert!(lookup.get(0xffff_ffff_ffff_ff01).is_none());
+
+        // Complete keys are reconstructed from the residuals when rebuilding.
+        let mut builder = Builder::from_map(&lookup);
+        builder.insert(0x101, "Yadda!");
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(0x1).unwrap());
+        assert_eq!("Yadda!", lookup.get(0x101).unwrap());
+        assert_eq!("Diddy!", lookup.get(0x2).unwrap());
+        assert_eq!("Again!", lookup.get(0xffff_ffff_ffff_fff1).unwrap());
+    }
+
+    #[test]
+    fn get_many() {
+        let mut builder = Builder::new(4);
+        for key in (0..1000_u64).map(|key| key * key * 7919) {
+            builder.insert(key, &format!("entry_{}", key));
+        }
+        builder.insert(u64::MAX, "Hello!");
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+
+        // Has both present and missing keys, and is not a multiple of the
+        // number of interleaved lookups long.
+        let keys: Vec<u64> = (0..1013_u64)
+            .map(|key| key * 7919)
+            .chain(Some(u64::MAX))
+            .collect();
+        let mut values = vec![None; keys.len()];
+        lookup.get_many(&keys, &mut values);
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(lookup.get(*key), *value, "key={}", key);
+        }
+        assert_eq!(Some("Hello!"), values[keys.len() - 1]);
+        assert_eq!(Some("entry_0"), values[0]);
+        assert!(values[2].is_none());
+
+        lookup.get_many(&[], &mut []);
+    }
+
+    #[test]
+    #[should_panic]
+    fn get_many_length_mismatch() {
+        let mut builder = Builder::new(4);
+        builder.insert(42, "Hello!");
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+        lookup.get_many(&[42, 84], &mut [None]);
+    }
+
+    #[test]
+    fn iter() {
+        assert_eq!(0, Map::new(&Builder::new(4).build()).iter().count());
+
+        for schedule in &[&[2][..], &[16, 4], &[3, 7]] {
+            let mut builder = Builder::with_schedule(schedule);
+            let mut expected = BTreeMap::new();
+            for key in (0..500_u64)
+                .map(|key| key.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+                .chain(0..50)
+            {
+                builder.insert(key, &format!("entry_{}", key % 100));
+                expected.insert(key, format!("entry_{}", key % 100));
+            }
+            for key in (0..50).step_by(3) {
+                builder.remove(key);
+                expected.remove(&key);
+            }
+            let bytes = builder.build();
+            let lookup = Map::new(&bytes);
+            let found: BTreeMap<u64, String> = lookup
+                .iter()
+                .map(|(key, value)| (key, value.to_string()))
+                .collect();
+            assert_eq!(expected, found, "schedule: {:?}", schedule);
+        }
+    }
+
+    #[test]
+    fn corrupt_buffers() {
+        let mut builder = Builder::with_schedule(&[4, 2]);
+        let keys = [0, 1, 2, 42, 84, 1 << 40, u64::MAX];
+        for key in &keys {
+            builder.insert(*key, &format!("entry_{}", key));
+        }
+        let bytes = builder.build();
+        // Lookups in maps with any single byte changed must not panic, or
+        // loop forever.
+        for position in 0..bytes.len() {
+            for value in &[0, 1, 2, 3, 4, 0x80, 0xff, bytes[position] ^ 0x10] {
+                let mut corrupt = bytes.clone();
+                corrupt[position] = *value;
+                let lookup = match Map::try_new(&corrupt) {
+                    Ok(lookup) => lookup,
+                    Err(_) => continue,
+                };
+                for key in &keys {
+                    lookup.get(*key);
+                }
+                let mut values = vec![None; keys.len()];
+                lookup.get_many(&keys, &mut values);
+                lookup.iter().count();
+            }
+        }
+    }
+
+    #[test]
+    fn layouts() {
+        let mut reference_map = BTreeMap::new();
+        let mut key: u64 = 0x1234_5678;
+        for entry in 0..2000 {
+            // A xorshift generator, for keys all over the key space.
+            key ^= key << 13;
+            key ^= key >> 7;
+            key ^= key << 17;
+            reference_map.insert(key, format!("entry_{}", entry));
+        }
+        for layout in &[
+            Layout::Insertion,
+            Layout::DepthFirst,
+            Layout::BreadthFirst,
+            Layout::VanEmdeBoas,
+        ] {
+            let mut builder = Builder::new(3);
+            builder.set_layout(*layout);
+            for (key, value) in &reference_map {
+                builder.insert(*key, value);
+            }
+            let bytes = builder.build();
+            let lookup = Map::new(&bytes);
+            // The root table is always created first.
+            assert_eq!(
+                size_of::<header::Root>(),
+                lookup.header().root_table_offset,
+                "layout: {:?}",
+                layout
+            );
+            for (key, value) in &reference_map {
+                assert_eq!(lookup.get(*key).unwrap(), *value, "layout: {:?}", layout);
+            }
+        }
+    }
+
+    #[test]
+    fn rebuild_from_map() {
+        let mut builder = Builder::new(3);
+        builder.insert(42, "Hello!");
+        builder.insert(84, "World!");
+        let bytes = builder.build();
+
+        let lookup = Map::new(&bytes);
+        let mut builder = Builder::from_map(&lookup);
+        builder.insert(100, "Again!");
+        builder.insert(42, "Ignored!");
+        let rebuilt = builder.build();
+
+        let lookup = Map::new(&rebuilt);
+        assert_eq!("Hello!", lookup.get(42).unwrap());
+        assert_eq!("World!", lookup.get(84).unwrap());
+        assert_eq!("Again!", lookup.get(100).unwrap());
+
+        // Rebuilding without changes yields the same bytes.
+        assert_eq!(rebuilt, Builder::from_bytes(rebuilt.clone()).build());
+    }
+
+    #[test]
+    fn rebuild_from_empty_map() {
+        let bytes = Builder::new(4).build();
+        assert!(Map::new(&bytes).get(42).is_none());
+        let mut builder = Builder::from_bytes(bytes);
+        builder.insert(42, "Hello!");
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(42).unwrap());
+    }
+
+    #[test]
+    fn rebuild_from_damaged_map() {
+        let mut builder = Builder::new(4);
+        builder.insert(42, "Hello!");
+        builder.insert(84, "World!");
+        builder.insert(1 << 40, "Again!");
+        let bytes = builder.build();
+        let (root_table_offset, string_offset) = {
+            let map = Map::new(&bytes);
+            let header = map.header();
+            (header.root_table_offset, header.string_offset)
+        };
+
+        // The root table indexes no bits.
+        let mut damaged = bytes.clone();
+        damaged[root_table_offset + 8] = 0;
+        assert!(Map::try_new(&damaged).is_ok());
+        let map = Map::new(&damaged);
+        assert_eq!(Some(Error::Corrupt), Builder::try_from_map(&map).err());
+        assert_eq!(Some(Error::Corrupt), Builder::try_from_bytes(damaged).err());
+
+        // The first value is not UTF-8.
+        let mut damaged = bytes.clone();
+        damaged[string_offset] = 0xff;
+        assert_eq!(Some(Error::Corrupt), Builder::try_from_bytes(damaged).err());
+
+        assert_eq!(
+            Some(Error::NotAMap),
+            Builder::try_from_bytes(vec![0; bytes.len()]).err()
+        );
+
+        // Damaged tables are either rejected, or make valid maps.
+        for position in root_table_offset..string_offset {
+            for bit in 0..8 {
+                let mut damaged = bytes.clone();
+                damaged[position] ^= 1 << bit;
+                if let Ok(builder) = Builder::try_from_bytes(damaged) {
+                    assert!(Map::try_new(&builder.build()).is_ok());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn remove() {
+        let mut builder = Builder::new(2);
+        builder.insert(0b0101, "Hello!");
+        builder.insert(0b1001, "World!");
+        builder.insert(0b0010, "Again!");
+        assert!(builder.remove(0b1001));
+        assert!(!builder.remove(0b1001));
+        assert!(!builder.remove(0b1101));
+        assert!(!builder.remove(0b0011));
+        let bytes = builder.build();
+
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(0b0101).unwrap());
+        assert_eq!("Again!", lookup.get(0b0010).unwrap());
+        assert!(lookup.get(0b1001).is_none());
+
+        // Removed keys can be inserted again, with a new value.
+        let mut builder = Builder::from_map(&lookup);
+        builder.insert(0b1001, "Hello!");
+        assert!(builder.remove(0b0010));
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+        assert_eq!("Hello!", lookup.get(0b1001).unwrap());
+        assert!(lookup.get(0b0010).is_none());
+    }
+
+    #[test]
+    fn compression() {
+        let messages = ["could not open", "could not read", "could not write"];
+        let mut reference_map = BTreeMap::new();
+        for key in 0..1000_u64 {
+            let message = messages[key as usize % messages.len()];
+            reference_map.insert(key * 7, format!("File {}: {}.", key, message));
+        }
+        let mut sizes = vec![];
+        for compression in &[Compression::None, Compression::Symbols, Compression::Blocks] {
+            let mut builder = Builder::new(4);
+            builder.set_compression(*compression);
+            for (key, value) in &reference_map {
+                builder.insert(*key, value);
+            }
+            let bytes = builder.build();
+            sizes.push(bytes.len());
+            let lookup = CompressedMap::new(&bytes);
+            assert_eq!(*compression, lookup.compression());
+            for (key, value) in &reference_map {
+                assert_eq!(*value, lookup.get(*key).unwrap());
+            }
+            assert!(lookup.get(1).is_none());
+            let found: BTreeMap<u64, String> = lookup
+                .iter()
+                .map(|(key, value)| (key, value.into_owned()))
+                .collect();
+            assert_eq!(reference_map, found);
+
+            // The compression survives a round trip through the builder.
+            let mut builder = Builder::from_bytes(bytes.clone());
+            builder.insert(1, "Hello!");
+            let rebuilt = builder.build();
+            let lookup = CompressedMap::new(&rebuilt);
+            assert_eq!("Hello!", lookup.get(1).unwrap());
+            assert_eq!(reference_map[&7], lookup.get(7).unwrap());
+            assert_eq!(rebuilt, Builder::from_bytes(rebuilt.clone()).build());
+        }
+        assert!(sizes[1] < sizes[0], "sizes: {:?}", sizes);
+        assert!(sizes[2] < sizes[0], "sizes: {:?}", sizes);
+
+        let mut builder = Builder::new(4);
+        builder.set_compression(Compression::Symbols);
+        builder.insert(42, "Hello!");
+        let bytes = builder.build();
+        // Compressed values can not be borrowed.
+        assert_eq!(Some(Error::Compressed), Map::try_new(&bytes).err());
+        assert_eq!(Some(Error::Compressed), MapBuf::new(bytes.clone()).err());
+        let lookup = CompressedMap::new(&bytes);
+        assert!(matches!(lookup.get(42), Some(Cow::Owned(_))));
+        // Those of other maps are.
+        let mut builder = Builder::new(4);
+        builder.insert(42, "Hello!");
+        let plain = builder.build();
+        let lookup = CompressedMap::new(&plain);
+        assert!(matches!(lookup.get(42), Some(Cow::Borrowed("Hello!"))));
+
+        let mut corrupt = bytes.clone();
+        corrupt[4] = 3;
+        assert_eq!(
+            Error::Corrupt,
+            CompressedMap::try_new(&corrupt).err().unwrap()
+        );
+        // A symbol table which does not fit.
+        let mut corrupt = bytes.clone();
+        let string_offset = CompressedMap::new(&bytes).map.header().string_offset;
+        corrupt.truncate(string_offset + 1);
+        assert_eq!(
+            Error::Corrupt,
+            CompressedMap::try_new(&corrupt).err().unwrap()
+        );
+    }
+
+    #[test]
+    fn block_compression() {
+        let mut builder = Builder::new(8);
+        builder.set_compression(Compression::Blocks);
+        // Enough values for many blocks, and a value longer than a block.
+        let long = "Hello! ".repeat(1000);
+        builder.insert(0, &long);
+        for key in 1..5000 {
+            builder.insert(key, &format!("entry_{}", key));
+        }
+        let bytes = builder.build();
+        let lookup = CompressedMap::new(&bytes);
+        // In an order which misses the cache of recent blocks.
+        for key in (1..5000).step_by(97).chain((1..5000).rev().step_by(89)) {
+            assert_eq!(format!("entry_{}", key), lookup.get(key).unwrap());
+        }
+        assert_eq!(long, lookup.get(0).unwrap());
+        assert_eq!(5000, lookup.iter().count());
+
+        // Threads may share a single map, and its cache.
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let lookup = &lookup;
+                scope.spawn(move || {
+                    for key in (1 + thread..5000).step_by(4) {
+                        assert_eq!(format!("entry_{}", key), lookup.get(key).unwrap());
+                    }
+                });
+            }
+        });
+
+        // A block header which does not fit.
+        let string_offset = lookup.map.header().string_offset;
+        let mut corrupt = bytes.clone();
+        corrupt.truncate(string_offset + 16);
+        assert_eq!(
+            Error::Corrupt,
+            CompressedMap::try_new(&corrupt).err().unwrap()
+        );
+        // Blocks which are not valid are not found.
+        let mut corrupt = bytes.clone();
+        corrupt[string_offset + 16..string_offset + 24].copy_from_slice(&[0xff; 8]);
+        let lookup = CompressedMap::new(&corrupt);
+        assert!(lookup.get(1).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "map buffer has compressed values")]
+    fn new_compressed() {
+        let mut builder = Builder::new(4);
+        builder.set_compression(Compression::Blocks);
+        builder.insert(42, "Hello!");
+        let bytes = builder.build();
+        Map::new(&bytes);
+    }
+
+    #[test]
+    fn suffix_sharing() {
+        let values = [
+            "Hello, World!",
+            "World!",
+            "!",
+            "",
+            "Goodbye, World!",
+            "Hello!",
+        ];
+        let mut builder = Builder::new(4);
+        builder.set_suffix_sharing(true);
+        for (key, value) in values.iter().enumerate() {
+            builder.insert(key as u64, value);
+        }
+        let (bytes, stats) = builder.build_with_stats();
+        assert_eq!(
+            Stats {
+                values: 6,
+                shared_values: 3,
+                shared_bytes: 7 + 2 + 1,
+                string_bytes: 14 + 16 + 7,
+            },
+            stats
+        );
+        let lookup = Map::new(&bytes);
+        for (key, value) in values.iter().enumerate() {
+            assert_eq!(*value, lookup.get(key as u64).unwrap());
+        }
+
+        // Shared values survive a round trip through the builder, and are
+        // reused by equal values.
+        let mut builder = Builder::from_map(&lookup);
+        builder.set_suffix_sharing(true);
+        builder.insert(100, "World!");
+        let (rebuilt, rebuilt_stats) = builder.build_with_stats();
+        assert_eq!(stats, rebuilt_stats);
+        let lookup = Map::new(&rebuilt);
+        assert_eq!("World!", lookup.get(100).unwrap());
+        for (key, value) in values.iter().enumerate() {
+            assert_eq!(*value, lookup.get(key as u64).unwrap());
+        }
+        let mut builder = Builder::from_bytes(rebuilt.clone());
+        builder.set_suffix_sharing(true);
+        assert_eq!(rebuilt, builder.build());
+
+        // Also when the values are stored on their own again, or compressed.
+        for compression in &[Compression::None, Compression::Symbols] {
+            let mut builder = Builder::from_map(&lookup);
+            builder.set_compression(*compression);
+            let (bytes, stats) = builder.build_with_stats();
+            assert_eq!(0, stats.shared_values);
+            let lookup = CompressedMap::new(&bytes);
+            for (key, value) in values.iter().enumerate() {
+                assert_eq!(*value, lookup.get(key as u64).unwrap());
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn build_parallel() {
+        let mut key: u64 = 0x1234_5678;
+        let mut values = vec![];
+        for entry in 0..3000_u64 {
+            // A xorshift generator, for keys all over the key space.
+            key ^= key << 13;
+            key ^= key >> 7;
+            key ^= key << 17;
+            values.push((key, format!("entry_{}", entry % 1000)));
+            values.push((entry, format!("entry_{}", entry)));
+        }
+        // Only the first value inserted under a key is kept.
+        values.push((42, "Again!".to_string()));
+        let entries: Vec<(u64, &str)> = values
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect();
+
+        for schedule in &[&[4][..], &[16, 4], &[2]] {
+            for compression in &[Compression::None, Compression::Symbols, Compression::Blocks] {
+                let new_builder = || {
+                    let mut builder = Builder::with_schedule(schedule);
+                    builder.set_compression(*compression);
+                    builder
+                };
+                let mut builder = new_builder();
+                for (key, value) in &entries {
+                    builder.insert(*key, value);
+                }
+                let bytes = builder.build();
+                let parallel = new_builder().build_parallel(&entries);
+                let lookup = CompressedMap::new(&bytes);
+                let parallel_lookup = CompressedMap::new(&parallel);
+                assert_eq!(
+                    lookup.iter().collect::<Vec<_>>(),
+                    parallel_lookup.iter().collect::<Vec<_>>(),
+                    "schedule: {:?}, compression: {:?}",
+                    schedule,
+                    compression
+                );
+                // Only the order of the tables and the values may differ, but
+                // compressed values take up more or less space in another order.
+                assert_eq!(
+                    lookup.map.header().string_offset,
+                    parallel_lookup.map.header().string_offset
+                );
+                if *compression == Compression::None {
+                    assert_eq!(bytes.len(), parallel.len());
+                }
+                assert_eq!("entry_42", parallel_lookup.get(42).unwrap());
+            }
+        }
+
+        let bytes = Builder::new(4).build_parallel(&[]);
+        assert!(Map::new(&bytes).iter().next().is_none());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    #[should_panic(expected = "empty builder")]
+    fn build_parallel_after_insert() {
+        let mut builder = Builder::new(4);
+        builder.insert(42, "Hello!");
+        builder.build_parallel(&[(84, "World!")]);
+    }
+
+    // Builds a canonical map of `entries`, in the given order.
+    fn build_canonical<'a, I>(bits: usize, entries: I) -> Vec<u8>
+    where
+        I: IntoIterator<Item = &'a (u64, String)>,
+    {
+        let mut builder = Builder::new(bits);
+        builder.set_canonical(true);
+        for (key, value) in entries {
+            builder.insert(*key, value);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn canonical() {
+        let mut entries = vec![];
+        let mut key: u64 = 0x1234_5678;
+        for entry in 0..500_u64 {
+            // A xorshift generator, for keys all over the key space.
+            key ^= key << 13;
+            key ^= key >> 7;
+            key ^= key << 17;
+            entries.push((key, format!("entry_{}", entry % 100)));
+            entries.push((entry, format!("entry_{}", entry)));
+        }
+        let bytes = build_canonical(4, &entries);
+        assert_eq!(bytes, build_canonical(4, entries.iter().rev()));
+        let mut shuffled = entries.clone();
+        shuffled.sort_by_key(|(key, _)| key.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        assert_eq!(bytes, build_canonical(4, &shuffled));
+
+        // Without it, the order shows.
+        let mut builder = Builder::new(4);
+        for (key, value) in entries.iter().rev() {
+            builder.insert(*key, value);
+        }
+        let reversed = builder.build();
+        assert_ne!(bytes, reversed);
+        let lookup = Map::new(&bytes);
+        for (key, value) in &entries {
+            assert_eq!(value, lookup.get(*key).unwrap());
+        }
+
+        // Neither removed values, nor rebuilding a map changes the result.
+        let mut builder = Builder::new(4);
+        builder.set_canonical(true);
+        for key in 1000..1100 {
+            builder.insert(key, &format!("removed_{}", key));
+        }
+        for (key, value) in &shuffled {
+            builder.insert(*key, value);
+        }
+        for key in 1000..1100 {
+            assert!(builder.remove(key));
+        }
+        assert_eq!(bytes, builder.build());
+        let mut builder = Builder::from_bytes(reversed);
+        builder.set_canonical(true);
+        assert_eq!(bytes, builder.build());
+
+        // Nor does it for the other ways to store the values, or to lay out
+        // the tables.
+        let build = |entries: &[(u64, String)], configure: fn(&mut Builder)| {
+            let mut builder = Builder::new(4);
+            builder.set_canonical(true);
+            configure(&mut builder);
+            for (key, value) in entries {
+                builder.insert(*key, value);
+            }
+            builder.build()
+        };
+        let configurations: [fn(&mut Builder); 4] = [
+            |builder| builder.set_compression(Compression::Symbols),
+            |builder| builder.set_compression(Compression::Blocks),
+            |builder| builder.set_suffix_sharing(true),
+            |builder| builder.set_layout(Layout::VanEmdeBoas),
+        ];
+        for configure in &configurations {
+            assert_eq!(build(&entries, *configure), build(&shuffled, *configure));
+        }
+    }
+
+    #[test]
+    fn reverse_index() {
+        let mut entries: Vec<(u64, String)> = (0..1000_u64)
+            .map(|key| (key * 0x9e37_79b9, format!("value_{}", key % 37)))
+            .collect();
+        // Stored within the other values, when they are shared.
+        entries.push((1 << 42, "_5".to_string()));
+        let keys_for = |value: &str| -> Vec<u64> {
+            let mut keys: Vec<u64> = entries
+                .iter()
+                .filter(|(_, entry)| entry == value)
+                .map(|(key, _)| *key)
+                .collect();
+            keys.sort_unstable();
+            keys
+        };
+        let configurations: [fn(&mut Builder); 5] = [
+            |_| {},
+            |builder| builder.set_compression(Compression::Symbols),
+            |builder| builder.set_compression(Compression::Blocks),
+            |builder| builder.set_suffix_sharing(true),
+            |builder| builder.set_canonical(true),
+        ];
+        for configure in &configurations {
+            let mut builder = Builder::new(4);
+            builder.set_reverse_index(true);
+            configure(&mut builder);
+            builder.insert(1 << 40, "removed");
+            for (key, value) in &entries {
+                builder.insert(*key, value);
+            }
+            assert!(builder.remove(1 << 40));
+            let bytes = builder.build();
+            let lookup = CompressedMap::new(&bytes);
+            for (_, value) in &entries {
+                assert_eq!(keys_for(value), lookup.keys_for(value).collect::<Vec<_>>());
+            }
+            for value in &["", "removed", "value_", "value_99", "zzz"] {
+                assert_eq!(0, lookup.keys_for(value).count(), "value: {}", value);
+            }
+
+            // The reverse index is kept when the map is rebuilt.
+            let mut builder = Builder::from_bytes(bytes.clone());
+            builder.insert(1 << 41, "value_0");
+            let rebuilt = builder.build();
+            let mut expected = keys_for("value_0");
+            expected.push(1 << 41);
+            expected.sort_unstable();
+            assert_eq!(
+                expected,
+                CompressedMap::new(&rebuilt)
+                    .keys_for("value_0")
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // Without one, nothing is found, and the map is the same as before
+        // there were reverse indexes.
+        let mut builder = Builder::new(4);
+        for (key, value) in &entries {
+            builder.insert(*key, value);
+        }
+        let plain = builder.build();
+        assert_eq!(0, Map::new(&plain).keys_for("value_0").count());
+        assert_eq!(0, Map::new(&plain).header().extension_words);
+        let mut builder = Builder::from_bytes(plain.clone());
+        builder.set_reverse_index(true);
+        let indexed = builder.build();
+        assert_eq!(
+            keys_for("value_0"),
+            Map::new(&indexed).keys_for("value_0").collect::<Vec<_>>()
+        );
+        let mut builder = Builder::from_bytes(indexed.clone());
+        builder.set_reverse_index(false);
+        assert_eq!(plain, builder.build());
+
+        // A reverse index which overlaps the strings is corrupt.
+        let mut corrupt = indexed;
+        let string_offset = Map::new(&corrupt).header().string_offset;
+        let start = size_of::<header::Root>();
+        corrupt[start..start + 8].copy_from_slice(&(string_offset - 8).to_ne_bytes());
+        assert_eq!(Some(Error::Corrupt), Map::try_new(&corrupt).err());
+    }
+
+    #[test]
+    fn perfect_hash() {
+        let entries: Vec<(u64, String)> = (0..5000_u64)
+            .map(|key| (key * 0x9e37_79b9, format!("entry_{}", key % 1000)))
+            .collect();
+        for compression in &[Compression::None, Compression::Symbols, Compression::Blocks] {
+            let mut builder = Builder::new(4);
+            builder.set_index_kind(IndexKind::PerfectHash);
+            builder.set_compression(*compression);
+            builder.set_reverse_index(true);
+            for (key, value) in &entries {
+                builder.insert(*key, value);
+            }
+            let bytes = builder.build();
+            let lookup = CompressedMap::new(&bytes);
+            assert_eq!(
+                IndexKind::PerfectHash as u8,
+                lookup.map.header().index_kind,
+                "compression: {:?}",
+                compression
+            );
+            for (key, value) in &entries {
+                assert_eq!(value, &lookup.get(*key).unwrap());
+                assert_eq!(None, lookup.get(key + 1));
+            }
+            assert_eq!(entries.len(), lookup.iter().count());
+            let mut keys: Vec<u64> = (0..5).map(|key| key * 1000 * 0x9e37_79b9).collect();
+            keys.sort_unstable();
+            assert_eq!(keys, lookup.keys_for("entry_0").collect::<Vec<_>>());
+
+            // Rebuilt maps keep the index kind.
+            let mut builder = Builder::from_bytes(bytes.clone());
+            assert!(builder.remove(0));
+            builder.insert(1, "one");
+            let rebuilt = builder.build();
+            let lookup = CompressedMap::new(&rebuilt);
+            assert_eq!(IndexKind::PerfectHash as u8, lookup.map.header().index_kind);
+            assert_eq!(None, lookup.get(0));
+            assert_eq!("one", lookup.get(1).unwrap());
+            assert_eq!(entries.len(), lookup.iter().count());
+        }
+
+        // Maps without keys have no index.
+        let mut builder = Builder::new(4);
+        builder.set_index_kind(IndexKind::PerfectHash);
+        let bytes = builder.build();
+        let lookup = Map::new(&bytes);
+        assert_eq!(None, lookup.get(0));
+        assert_eq!(0, lookup.iter().count());
+
+        // An unknown index kind is corrupt.
+        let mut bytes = bytes;
+        bytes[6] = 2;
+        assert_eq!(Some(Error::Corrupt), Map::try_new(&bytes).err());
+    }
+
+    #[test]
+    fn dense_range() {
+        // A run of keys, with a few missing, and a few keys outside of it.
+        let mut entries: BTreeMap<u64, String> = (1000..3000_u64)
+            .filter(|key| key % 7 != 0)
+            .map(|key| (key, format!("entry_{}", key % 100)))
+            .collect();
+        for key in &[0, 1, 3010, 1 << 40, u64::MAX] {
+            entries.insert(*key, format!("outlier_{}", key));
+        }
+        for compression in &[Compression::None, Compression::Symbols, Compression::Blocks] {
+            for index_kind in &[IndexKind::Trie, IndexKind::PerfectHash] {
+                let context = format!(
+                    "compression: {:?}, index kind: {:?}",
+                    compression, index_kind
+                );
+                let build = |dense_range: bool| {
+                    let mut builder = Builder::new(4);
+                    builder.set_compression(*compression);
+                    builder.set_index_kind(*index_kind);
+                    builder.set_reverse_index(true);
+                    builder.set_dense_range(dense_range);
+                    for (key, value) in &entries {
+                        builder.insert(*key, value);
+                    }
+                    builder.build()
+                };
+                let bytes = build(true);
+                let lookup = CompressedMap::new(&bytes);
+                assert!(lookup.map.dense.is_some(), "{}", context);
+                assert_eq!(header::REQUIRE_DENSE, lookup.map.header().required_features);
+                let sparse = build(false);
+                assert!(
+                    CompressedMap::new(&sparse).map.dense.is_none(),
+                    "{}",
+                    context
+                );
+                assert_eq!(
+                    0,
+                    CompressedMap::new(&sparse).map.header().required_features
+                );
+                for key in 990..3020 {
+                    assert_eq!(
+                        entries.get(&key).map(|value| &value[..]),
+                        lookup.get(key).as_deref(),
+                        "key: {}, {}",
+                        key,
+                        context
+                    );
+                }
+                for (key, value) in &entries {
+                    assert_eq!(value, &lookup.get(*key).unwrap(), "{}", context);
+                }
+                if *compression == Compression::None {
+                    let keys: Vec<u64> = (995..3005).collect();
+                    let mut found = vec![None; keys.len()];
+                    lookup.map.get_many(&keys, &mut found);
+                    for (key, value) in keys.iter().zip(&found) {
+                        assert_eq!(entries.get(key).map(|value| &value[..]), *value);
+                    }
+                }
+                let mut iterated: Vec<(u64, String)> = lookup
+                    .iter()
+                    .map(|(key, value)| (key, value.into_owned()))
+                    .collect();
+                iterated.sort_unstable();
+                assert_eq!(entries.clone().into_iter().collect::<Vec<_>>(), iterated);
+                assert_eq!(
+                    vec![1042, 1142, 1242],
+                    lookup.keys_for("entry_42").take(3).collect::<Vec<_>>()
+                );
+
+                // Rebuilt maps find a new range, with the removed keys
+                // absent.
+                let mut builder = Builder::from_bytes(bytes.clone());
+                assert!(builder.remove(1500));
+                builder.insert(7, "seven");
+                builder.insert(1001, "one");
+                let rebuilt = builder.build();
+                let lookup = CompressedMap::new(&rebuilt);
+                assert!(lookup.map.dense.is_some(), "{}", context);
+                assert_eq!(None, lookup.get(1500));
+                assert_eq!("seven", lookup.get(7).unwrap());
+                assert_eq!("one", lookup.get(1001).unwrap());
+                assert_eq!(entries.len() + 1, lookup.iter().count());
+
+                // Once the range is turned off, the map no longer requires
+                // it.
+                let mut builder = Builder::from_bytes(rebuilt);
+                builder.set_dense_range(false);
+                let rebuilt = builder.build();
+                let lookup = CompressedMap::new(&rebuilt);
+                assert!(lookup.map.dense.is_none(), "{}", context);
+                assert_eq!(0, lookup.map.header().required_features);
+                assert_eq!("one", lookup.get(1001).unwrap());
+            }
+        }
+
+        // Dense ranges are on by default, and only used for enough keys.
+        let mut builder = Builder::new(4);
+        for key in 0..63 {
+            builder.insert(key, "value");
+        }
+        assert!(Map::new(&builder.build()).dense.is_none());
+
+        // A dense segment which overlaps the strings is corrupt.
+        let mut builder = Builder::new(4);
+        for key in 0..100 {
+            builder.insert(key, "value");
+        }
+        let bytes = builder.build();
+        let mut corrupt = bytes.clone();
+        let offset = size_of::<header::Root>() + 8;
+        let string_offset = Map::new(&bytes).header().string_offset as u64;
+        corrupt[offset..offset + 8].copy_from_slice(&string_offset.to_ne_bytes());
+        assert_eq!(Some(Error::Corrupt), Map::try_new(&corrupt).err());
+
+        // So is a dense segment which the map does not require, and the
+        // other way around.
+        let mut corrupt = bytes.clone();
+        corrupt[7] = 0;
+        assert_eq!(Some(Error::Corrupt), Map::try_new(&corrupt).err());
+        assert_eq!(None, reference::lookup(&corrupt, 0));
+        let mut corrupt = bytes.clone();
+        corrupt[offset..offset + 8].copy_from_slice(&0_u64.to_ne_bytes());
+        assert_eq!(Some(Error::Corrupt), Map::try_new(&corrupt).err());
+
+        // Maps which require unknown features are rejected.
+        let mut unsupported = bytes;
+        unsupported[7] |= 2;
+        assert_eq!(Some(Error::Unsupported), Map::try_new(&unsupported).err());
+        assert_eq!(None, reference::lookup(&unsupported, 0));
+    }
+
     fn insert_and_lookup_random_strings(bits: usize) {
         let mut reference_map = BTreeMap::new();
         let mut builder = Builder::new(bits);
@@ -412,8 +2656,166 @@ mod tests {
 
     #[test]
     fn test_insert_and_lookup_for_bits() {
-        for bits in 2..16 {
+        for bits in 2..=16 {
             insert_and_lookup_random_strings(bits);
         }
     }
+
+    #[test]
+    fn keys_differing_in_top_bits() {
+        // The deepest level has at most 12 bits when 64 is not a multiple of
+        // the bits per level, so these are all the values that it can see.
+        let keys: Vec<u64> = (0..1 << 12)
+            .flat_map(|high: u64| vec![high << 52, high << 52 | 1])
+            .collect();
+        for bits in 2..=16 {
+            let mut builder = Builder::new(bits);
+            for key in &keys {
+                builder.insert(*key, &format!("{:x}", key));
+            }
+            let buffer = builder.build();
+            let lookup = Map::new(&buffer);
+            let level_bits = lookup.header().level_bits();
+            let total: usize = level_bits.iter().map(|bits| *bits as usize).sum();
+            assert_eq!(64, total, "bits: {}", bits);
+            let last = if 64 % bits == 0 { bits } else { 64 % bits };
+            assert_eq!(last, *level_bits.last().unwrap() as usize);
+            for key in &keys {
+                let expected = format!("{:x}", key);
+                assert_eq!(Some(&expected[..]), lookup.get(*key), "bits: {}", bits);
+                assert_eq!(Some(expected), reference::lookup(&buffer, *key));
+                assert!(lookup.get(key | 1 << 51).is_none(), "bits: {}", bits);
+            }
+            assert_eq!(keys.len(), lookup.iter().count(), "bits: {}", bits);
+        }
+    }
+
+    // Keys with only the given number of high bits set.
+    fn high_bits_keys(bits: u32) -> impl Strategy<Value = u64> {
+        any::<u64>().prop_map(move |key| key << (64 - bits))
+    }
+
+    // Keys which are close together, around a few random places.
+    fn clustered_keys() -> impl Strategy<Value = u64> {
+        (0..4_u64, 0..256_u64).prop_map(|(cluster, offset)| {
+            cluster
+                .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                .wrapping_add(offset)
+        })
+    }
+
+    // Keys which differ from each other in only one or two bits, or are at the
+    // edges of the key space.
+    fn adversarial_keys() -> impl Strategy<Value = u64> {
+        prop_oneof![
+            Just(0),
+            Just(1),
+            Just(u64::MAX),
+            Just(u64::MAX - 1),
+            Just(1 << 63),
+            Just(1 << 63 | 1),
+            Just(u64::MAX >> 1),
+            (0..64_u32).prop_map(|bit| 1 << bit),
+            (0..64_u32).prop_map(|bit| !(1 << bit)),
+            (0..64_u32).prop_map(|bit| 1 << 63 | 1 << bit),
+        ]
+    }
+
+    // Inserts `entries` into a map, removes `removed` from it, and checks
+    // that it has the same contents as a BTreeMap which is changed the same.
+    fn check_against_btree_map(bits: usize, entries: &[(u64, String)], removed: &[u64]) {
+        let mut builder = Builder::new(bits);
+        let mut expected = BTreeMap::new();
+        for (key, value) in entries {
+            builder.insert(*key, value);
+            // Inserting does not replace existing values.
+            expected.entry(*key).or_insert_with(|| value.clone());
+        }
+        for key in removed {
+            assert_eq!(expected.remove(key).is_some(), builder.remove(*key));
+        }
+
+        let bytes = builder.build();
+        // The same keys, in a perfect hash index.
+        let mut builder = Builder::from_bytes(bytes.clone());
+        builder.set_index_kind(IndexKind::PerfectHash);
+        let perfect_hash = builder.build();
+        for bytes in &[bytes, perfect_hash] {
+            let lookup = Map::new(bytes);
+            let keys: Vec<u64> = entries
+                .iter()
+                .map(|(key, _)| *key)
+                .chain(removed.iter().cloned())
+                .collect();
+            let mut values = vec![None; keys.len()];
+            lookup.get_many(&keys, &mut values);
+            for (key, value) in keys.iter().zip(&values) {
+                let expected = expected.get(key).map(|value| value.as_str());
+                assert_eq!(expected, lookup.get(*key), "key: {}, bits: {}", key, bits);
+                assert_eq!(expected, *value, "key: {}, bits: {}", key, bits);
+            }
+            let found: BTreeMap<u64, String> = lookup
+                .iter()
+                .map(|(key, value)| (key, value.to_string()))
+                .collect();
+            assert_eq!(expected, found, "bits: {}", bits);
+        }
+    }
+
+    fn entries<S: Strategy<Value = u64>>(keys: S) -> impl Strategy<Value = Vec<(u64, String)>> {
+        prop::collection::vec((keys, "[a-z]{0,4}"), 0..100)
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn random_keys(
+            bits in 2..=16_usize,
+            entries in entries(any::<u64>()),
+            removed in prop::collection::vec(any::<u64>(), 0..10),
+        ) {
+            check_against_btree_map(bits, &entries, &removed);
+        }
+
+        #[test]
+        fn clustered(
+            bits in 2..=16_usize,
+            entries in entries(clustered_keys()),
+            removed in prop::collection::vec(clustered_keys(), 0..30),
+        ) {
+            check_against_btree_map(bits, &entries, &removed);
+        }
+
+        #[test]
+        fn high_bits_only(
+            bits in 2..=16_usize,
+            entries in (1..=16_u32).prop_flat_map(|high_bits| entries(high_bits_keys(high_bits))),
+        ) {
+            // Removes some of the keys.
+            let removed: Vec<u64> = entries.iter().step_by(3).map(|(key, _)| *key).collect();
+            check_against_btree_map(bits, &entries, &removed);
+        }
+
+        #[test]
+        fn canonical_ignores_order(
+            bits in 2..=16_usize,
+            (entries, shuffled) in prop::collection::btree_map(any::<u64>(), "[a-z]{0,4}", 0..100)
+                .prop_flat_map(|entries| {
+                    let entries: Vec<(u64, String)> = entries.into_iter().collect();
+                    (Just(entries.clone()), Just(entries).prop_shuffle())
+                }),
+        ) {
+            prop_assert_eq!(build_canonical(bits, &entries), build_canonical(bits, &shuffled));
+        }
+
+        #[test]
+        fn adversarial(
+            bits in 2..=16_usize,
+            entries in entries(adversarial_keys()),
+            removed in prop::collection::vec(adversarial_keys(), 0..10),
+        ) {
+            check_against_btree_map(bits, &entries, &removed);
+        }
+    }
 }
@@ -0,0 +1,216 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small LZ77 codec, in the style of LZ4, for the blocks of a block
+//! compressed string section.
+//!
+//! The compressed form starts with the length of the uncompressed bytes, as
+//! LEB128.  It is followed by sequences, each of which appends some literal
+//! bytes, and then copies some earlier bytes:
+//!
+//! * A token byte.  Its high 4 bits are the number of literals, and its low 4
+//!   bits are the length of the copy, minus 4.  If either is 15, it is
+//!   continued by the bytes after it, up to and including the first byte
+//!   which is not 255, which are added to it.  The continuation of the
+//!   number of literals comes right after the token.
+//! * The literals.
+//! * If the uncompressed bytes are not complete yet: the distance back to the
+//!   bytes to copy, 2 bytes little endian, from 1 up to the number of bytes
+//!   uncompressed so far, then the continuation of the length of the copy.
+//!   The copy may overlap the bytes it appends.
+
+use std::convert::TryInto;
+
+// The shortest copy.
+const MIN_MATCH: usize = 4;
+// The furthest that a copy can reach back.
+const MAX_DISTANCE: usize = u16::MAX as usize;
+// The number of bits of the hashes used to find earlier bytes to copy.
+const HASH_BITS: u32 = 12;
+
+/// Compresses `input`.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    write_leb128(input.len(), &mut out);
+    // The last position at which each hash of 4 bytes was seen, plus one.
+    let mut seen = vec![0_usize; 1 << HASH_BITS];
+    let mut literals = 0;
+    let mut position = 0;
+    while position + MIN_MATCH <= input.len() {
+        let bytes = &input[position..position + MIN_MATCH];
+        let hash = u32::from_le_bytes(bytes.try_into().unwrap()).wrapping_mul(2_654_435_761)
+            >> (32 - HASH_BITS);
+        let candidate = seen[hash as usize];
+        seen[hash as usize] = position + 1;
+        if candidate == 0
+            || position + 1 - candidate > MAX_DISTANCE
+            || input[candidate - 1..candidate - 1 + MIN_MATCH] != *bytes
+        {
+            position += 1;
+            continue;
+        }
+        let start = candidate - 1;
+        let mut len = MIN_MATCH;
+        while position + len < input.len() && input[start + len] == input[position + len] {
+            len += 1;
+        }
+        write_sequence(
+            &input[literals..position],
+            Some((position - start, len)),
+            &mut out,
+        );
+        position += len;
+        literals = position;
+    }
+    if literals < input.len() {
+        write_sequence(&input[literals..], None, &mut out);
+    }
+    out
+}
+
+// Appends a sequence of `literals`, followed by a copy of `len` bytes from
+// `distance` bytes back, if there is one.
+fn write_sequence(literals: &[u8], copy: Option<(usize, usize)>, out: &mut Vec<u8>) {
+    let copy_len = copy.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((literals.len().min(15) << 4 | copy_len.min(15)) as u8);
+    write_continuation(literals.len(), out);
+    out.extend_from_slice(literals);
+    if let Some((distance, _)) = copy {
+        out.extend_from_slice(&(distance as u16).to_le_bytes());
+        write_continuation(copy_len, out);
+    }
+}
+
+// Appends the bytes that continue a length of `len` in a token.
+fn write_continuation(len: usize, out: &mut Vec<u8>) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn write_leb128(mut value: usize, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Decompresses `input`.  Returns `None` if it is not valid.
+pub fn decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = input.iter().copied();
+    let mut len: usize = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = bytes.next()?;
+        len |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if shift + 7 >= 64 {
+            return None;
+        }
+    }
+    // The uncompressed length is not trusted to allocate up front.
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(4)));
+    while out.len() < len {
+        let token = bytes.next()? as usize;
+        let literals = read_len(&mut bytes, token >> 4)?;
+        if literals > len - out.len() {
+            return None;
+        }
+        for _ in 0..literals {
+            out.push(bytes.next()?);
+        }
+        if out.len() == len {
+            break;
+        }
+        let distance = u16::from_le_bytes([bytes.next()?, bytes.next()?]) as usize;
+        let copy_len = read_len(&mut bytes, token & 15)?.checked_add(MIN_MATCH)?;
+        if distance == 0 || distance > out.len() || copy_len > len - out.len() {
+            return None;
+        }
+        let start = out.len() - distance;
+        for index in start..start + copy_len {
+            out.push(out[index]);
+        }
+    }
+    Some(out)
+}
+
+// Reads the continuation of the length `len` from a token, if it has one.
+fn read_len<I: Iterator<Item = u8>>(bytes: &mut I, mut len: usize) -> Option<usize> {
+    if len == 15 {
+        loop {
+            let byte = bytes.next()?;
+            len = len.checked_add(byte as usize)?;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> usize {
+        let compressed = compress(input);
+        assert_eq!(input, &decompress(&compressed).unwrap()[..]);
+        compressed.len()
+    }
+
+    #[test]
+    fn compress_and_decompress() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"abcd");
+        round_trip(b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        round_trip(&(0..=255).collect::<Vec<u8>>());
+        let text = "The quick brown fox jumps over the lazy dog.\0".repeat(1000);
+        let len = round_trip(text.as_bytes());
+        assert!(len * 20 < text.len(), "{} of {}", len, text.len());
+        // Long literals and long copies, which need continuation bytes.
+        let mut input: Vec<u8> = (0..1000_u32)
+            .map(|value| (value * 7919 % 251) as u8)
+            .collect();
+        input.extend(vec![b'x'; 1000]);
+        input.extend_from_within(..1000);
+        round_trip(&input);
+    }
+
+    #[test]
+    fn invalid() {
+        let compressed = compress(b"Hello! Hello! Hello!");
+        for len in 0..compressed.len() {
+            assert!(decompress(&compressed[..len]).is_none(), "len: {}", len);
+        }
+        // A copy from before the start.
+        assert!(decompress(&[8, 0x10, b'a', 2, 0]).is_none());
+        assert!(decompress(&[8, 0x10, b'a', 0, 0]).is_none());
+        // More bytes than announced.
+        assert!(decompress(&[1, 0x20, b'a', b'b']).is_none());
+        assert!(decompress(&[6, 0x10, b'a', 1, 0]).is_none());
+        // A length which does not end.
+        assert!(decompress(&[0xff; 11]).is_none());
+        assert_eq!(Some(vec![b'a'; 5]), decompress(&[5, 0x10, b'a', 1, 0]));
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Error, Map};
+use std::ops::Deref;
+use std::sync::Arc;
+
+// The buffer of a MapBuf, in whatever form it was passed in.
+type Owner = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The owners of bytes which [MapBuf::new] accepts.  Their bytes can not be
+/// changed through a shared reference, and do not move when the owner does,
+/// which [MapBuf] relies on.  This trait is sealed; use
+/// [MapBuf::from_storage] for other owners, such as memory mapped files.
+pub trait Storage: AsRef<[u8]> + Send + Sync + 'static + sealed::Sealed {}
+
+impl sealed::Sealed for Vec<u8> {}
+impl Storage for Vec<u8> {}
+impl sealed::Sealed for Box<[u8]> {}
+impl Storage for Box<[u8]> {}
+impl sealed::Sealed for Arc<[u8]> {}
+impl Storage for Arc<[u8]> {}
+impl sealed::Sealed for &'static [u8] {}
+impl Storage for &'static [u8] {}
+
+/// A [Map] which owns its buffer, instead of borrowing it.  The buffer is
+/// validated once, when the [MapBuf] is created, and is shared by all the
+/// clones of the [MapBuf], so it is cheap to clone and send to other threads.
+///
+/// Dereferences to a [Map], so that all of its lookup methods can be used.
+///
+/// Example:
+///
+/// ```rust
+/// use sequence_map::{Builder, MapBuf};
+///
+/// let mut builder = Builder::new(2);
+/// builder.insert(42, "Hello!");
+/// let map = MapBuf::new(builder.build()).unwrap();
+///
+/// let shared = map.clone();
+/// std::thread::spawn(move || assert_eq!("Hello!", shared.get(42).unwrap()))
+///     .join()
+///     .unwrap();
+/// assert!(map.get(84).is_none());
+/// ```
+///
+/// The [Map] that a [MapBuf] dereferences to can not be copied out of it, so
+/// that it can not outlive the buffer:
+///
+/// ```compile_fail
+/// use sequence_map::{Builder, Map, MapBuf};
+///
+/// let buf = MapBuf::new(Builder::new(2).build()).unwrap();
+/// let map: Map<'static> = (*buf).clone();
+/// drop(buf);
+/// map.get(1);
+/// ```
+pub struct MapBuf {
+    // Borrows the buffer of `storage`, which lives as long as any clone of
+    // this map does.
+    map: Map<'static>,
+    storage: Owner,
+}
+
+impl MapBuf {
+    /// Creates a map which owns `storage`, which holds the bytes produced by
+    /// [Builder::build](crate::Builder::build): a `Vec<u8>`, a `Box<[u8]>`,
+    /// an `Arc<[u8]>`, or a `&'static [u8]`.  The same requirements as for
+    /// [Map::try_new] apply to the bytes, so maps with compressed values are
+    /// rejected.
+    pub fn new<T: Storage>(storage: T) -> Result<MapBuf, Error> {
+        // The bytes of all Storage types stay where they are, unchanged.
+        unsafe { MapBuf::from_storage(storage) }
+    }
+
+    /// Like [MapBuf::new], but accepts any owner of bytes, such as a memory
+    /// mapped file.
+    ///
+    /// # Safety
+    ///
+    /// The bytes which `as_ref` returns for `storage` must stay where they
+    /// are, unchanged, for as long as `storage` lives.
+    pub unsafe fn from_storage<T>(storage: T) -> Result<MapBuf, Error>
+    where
+        T: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        MapBuf::open(storage, Map::try_new)
+    }
+
+    // Same as MapBuf::new, except that maps with compressed values are
+    // accepted as well.  For the C API, which only looks up the values of
+    // such maps with Map::value.
+    #[cfg(feature = "capi")]
+    pub(crate) fn new_compressed(storage: Vec<u8>) -> Result<MapBuf, Error> {
+        // A Vec<u8> is a Storage.
+        unsafe { MapBuf::open(storage, Map::open) }
+    }
+
+    // Creates a map which owns `storage`, with `open`.  Same safety
+    // requirements as for MapBuf::from_storage.
+    unsafe fn open<T>(
+        storage: T,
+        open: fn(&'static [u8]) -> Result<Map<'static>, Error>,
+    ) -> Result<MapBuf, Error>
+    where
+        T: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let storage: Owner = Arc::new(storage);
+        let rep: &[u8] = (*storage).as_ref();
+        // The bytes are owned by `storage`, which does not change them, and is
+        // kept alive for as long as the map is.  The map never hands out
+        // references which outlive the borrow of the MapBuf that they are
+        // obtained through, since all of its methods take `&'a self`, and it
+        // can not be cloned or moved out from behind the shared reference
+        // that Deref gives.  Map must therefore not implement Clone.
+        let rep: &'static [u8] = std::slice::from_raw_parts(rep.as_ptr(), rep.len());
+        let map = open(rep)?;
+        Ok(MapBuf { map, storage })
+    }
+
+    /// Returns the bytes of the map.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.map.rep
+    }
+}
+
+impl Clone for MapBuf {
+    fn clone(&self) -> MapBuf {
+        MapBuf {
+            // The bytes were validated when this map was created.
+            map: Map::open(self.map.rep).expect("valid map"),
+            storage: self.storage.clone(),
+        }
+    }
+}
+
+impl Deref for MapBuf {
+    type Target = Map<'static>;
+
+    fn deref(&self) -> &Map<'static> {
+        &self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Builder;
+    use std::borrow::Cow;
+
+    fn build() -> Vec<u8> {
+        let mut builder = Builder::new(4);
+        for key in 0..100 {
+            builder.insert(key, &format!("entry_{}", key));
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn storage_types() {
+        let bytes = build();
+        let from_vec = MapBuf::new(bytes.clone()).unwrap();
+        let from_box = MapBuf::new(bytes.clone().into_boxed_slice()).unwrap();
+        let from_arc = MapBuf::new(Arc::<[u8]>::from(bytes.clone())).unwrap();
+        let leaked: &'static [u8] = Box::leak(bytes.clone().into_boxed_slice());
+        let from_static = MapBuf::new(leaked).unwrap();
+        let from_storage =
+            unsafe { MapBuf::from_storage(Cow::<[u8]>::Owned(bytes.clone())) }.unwrap();
+        for map in &[from_vec, from_box, from_arc, from_static, from_storage] {
+            assert_eq!("entry_42", map.get(42).unwrap());
+            assert!(map.get(100).is_none());
+            assert_eq!(&bytes[..], map.as_bytes());
+        }
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let map = MapBuf::new(build()).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|thread| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    for key in (thread..100).step_by(4) {
+                        assert_eq!(format!("entry_{}", key), map.get(key).unwrap());
+                    }
+                })
+            })
+            .collect();
+        // The clones outlive the original.
+        drop(map);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(Error::TooShort, MapBuf::new(vec![0_u8; 4]).err().unwrap());
+        assert_eq!(Error::NotAMap, MapBuf::new(vec![0_u8; 4096]).err().unwrap());
+        let mut bytes = build();
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(Error::Corrupt, MapBuf::new(bytes).err().unwrap());
+    }
+}
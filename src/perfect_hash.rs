@@ -0,0 +1,389 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An index which finds keys with a minimal perfect hash function, in the
+//! style of PTHash.  The keys are hashed into buckets, and each bucket has a
+//! pilot, which is chosen so that the keys of all buckets end up in distinct
+//! positions.  There are about 1% more positions than keys, so that the
+//! buckets which are placed last still find free positions quickly.  The keys
+//! at the positions past the last slot are moved into the slots which are
+//! left free, so that there are exactly as many slots as keys.  See
+//! [format](crate::format) for its layout.
+
+use std::cmp::Reverse;
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::ops::Range;
+use zerocopy::{AsBytes, FromBytes, LayoutVerified};
+
+/// The kinds of index which a map can use to find the values of keys.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum IndexKind {
+    /// A trie of tables, each of which is indexed by a few bits of the key,
+    /// as chosen with [Builder::with_schedule](crate::Builder::with_schedule).
+    /// This is the default.
+    #[default]
+    Trie = 0,
+    /// A minimal perfect hash function, which maps each key to a slot of its
+    /// own.  A lookup reads a single pilot, and then a single slot, no matter
+    /// how many keys there are, which makes it faster than a trie for maps
+    /// whose tables do not fit into the CPU caches.  Each key takes about 17
+    /// bytes, and building is slower.  The bits per level and the [Layout] of
+    /// the builder are not used.  If no perfect hash function is found, which
+    /// is extremely unlikely, the map is built with a trie instead.
+    ///
+    /// [Layout]: crate::Layout
+    PerfectHash = 1,
+}
+
+// The average number of keys per bucket.
+const BUCKET_SIZE: usize = 4;
+
+// The number of pilots tried for a single bucket, before starting over with
+// another seed.  This is far more than needed.
+const MAX_PILOT: u32 = 1 << 24;
+
+// The number of seeds tried before giving up.  A seed only fails if a bucket
+// finds no free slots with any pilot.
+const MAX_SEEDS: u64 = 16;
+
+// The percentage of the positions which hold keys.  With all positions taken,
+// the last buckets would need a number of tries that grows with the number of
+// keys.
+const LOAD_PERCENT: usize = 99;
+
+// The start of the index.
+#[derive(AsBytes, FromBytes)]
+#[repr(C)]
+struct Header {
+    seed: u64,
+    buckets: u64,
+    positions: u64,
+    slots: u64,
+}
+
+// A slot of the index, which holds a key and its value.
+#[derive(Debug, Default, Clone, Copy, AsBytes, FromBytes)]
+#[repr(C)]
+struct Slot {
+    key: u64,
+    string_index: u64,
+}
+
+/// Mixes the bits of `value`.  This is the finalizer of MurmurHash3.
+pub fn mix(mut value: u64) -> u64 {
+    value ^= value >> 33;
+    value = value.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    value ^= value >> 33;
+    value = value.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    value ^ value >> 33
+}
+
+// Maps `hash` to a number below `n`, by its high bits.
+fn reduce(hash: u64, n: usize) -> usize {
+    ((hash as u128 * n as u128) >> 64) as usize
+}
+
+// Returns the position of the key with the hash `hash`, for the pilot `pilot`.
+fn position(hash: u64, pilot: u32, positions: usize) -> usize {
+    reduce(mix(hash ^ mix(pilot as u64)), positions)
+}
+
+/// Appends the index of `entries`, which are pairs of a key and the string
+/// index of its value, to `out`.  The keys must be distinct, and there must be
+/// at least one.
+///
+/// Returns `false`, and appends nothing, if no perfect hash function is found.
+pub fn write(entries: &[(u64, usize)], out: &mut Vec<u8>) -> bool {
+    write_with_max_pilot(entries, MAX_PILOT, out)
+}
+
+// Same as write, except that at most `max_pilot` pilots are tried for each
+// bucket.
+fn write_with_max_pilot(entries: &[(u64, usize)], max_pilot: u32, out: &mut Vec<u8>) -> bool {
+    assert!(!entries.is_empty(), "no entries");
+    let buckets = (entries.len() + BUCKET_SIZE - 1) / BUCKET_SIZE;
+    let positions = entries.len() * 100 / LOAD_PERCENT + 1;
+    let found = (0..MAX_SEEDS).find_map(|seed| {
+        let placed = find_pilots(entries, seed, buckets, positions, max_pilot)?;
+        Some((seed, placed))
+    });
+    let (seed, (pilots, placed)) = match found {
+        Some(found) => found,
+        None => return false,
+    };
+    let (remap, slots) = remap(placed, entries.len());
+    let header = Header {
+        seed,
+        buckets: buckets as u64,
+        positions: positions as u64,
+        slots: slots.len() as u64,
+    };
+    out.extend_from_slice(header.as_bytes());
+    out.extend_from_slice(pilots.as_bytes());
+    out.resize(out.len() + pilots_padding(buckets), 0);
+    out.extend_from_slice(remap.as_bytes());
+    out.extend_from_slice(slots.as_bytes());
+    true
+}
+
+// Returns the pilots of `buckets` buckets, and the entry at each of
+// `positions` positions, for the keys hashed with `seed`.  Returns `None` if
+// there are buckets for which no pilot below `max_pilot` was found.
+fn find_pilots(
+    entries: &[(u64, usize)],
+    seed: u64,
+    buckets: usize,
+    positions: usize,
+    max_pilot: u32,
+) -> Option<(Vec<u32>, Vec<Option<Slot>>)> {
+    // The bucket, the hash, the key and the string index of each entry, in
+    // the order of the buckets.
+    let mut hashed: Vec<(usize, u64, u64, usize)> = entries
+        .iter()
+        .map(|(key, string_index)| {
+            let hash = mix(key ^ seed);
+            (reduce(hash, buckets), hash, *key, *string_index)
+        })
+        .collect();
+    hashed.sort_unstable_by_key(|(bucket, _, _, _)| *bucket);
+    let mut members: Vec<(usize, Range<usize>)> = vec![];
+    let mut start = 0;
+    while start < hashed.len() {
+        let bucket = hashed[start].0;
+        let end = start
+            + hashed[start..]
+                .iter()
+                .take_while(|entry| entry.0 == bucket)
+                .count();
+        members.push((bucket, start..end));
+        start = end;
+    }
+    // The largest buckets are placed first, while there are many free slots.
+    members.sort_by_key(|(_, range)| Reverse(range.len()));
+
+    let mut pilots = vec![0; buckets];
+    let mut placed = vec![None; positions];
+    let mut found = vec![];
+    for (bucket, range) in members {
+        let members = &hashed[range];
+        pilots[bucket] = (0..max_pilot).find(|pilot| {
+            found.clear();
+            members.iter().all(|(_, hash, _, _)| {
+                let position = position(*hash, *pilot, positions);
+                let free = placed[position].is_none() && !found.contains(&position);
+                found.push(position);
+                free
+            })
+        })?;
+        for ((_, _, key, string_index), position) in members.iter().zip(&found) {
+            placed[*position] = Some(Slot {
+                key: *key,
+                string_index: *string_index as u64,
+            });
+        }
+    }
+    Some((pilots, placed))
+}
+
+// Moves the entries of `placed` at the positions from `len` on into the free
+// slots below `len`.  Returns the slot of each position from `len` on, which
+// is 0 for the free positions, and the `len` slots.
+fn remap(placed: Vec<Option<Slot>>, len: usize) -> (Vec<u64>, Vec<Slot>) {
+    let free: Vec<usize> = (0..len).filter(|slot| placed[*slot].is_none()).collect();
+    let mut free = free.into_iter();
+    let mut slots: Vec<Slot> = placed[..len]
+        .iter()
+        .map(|slot| slot.unwrap_or_default())
+        .collect();
+    let remap = placed[len..]
+        .iter()
+        .map(|slot| match slot {
+            Some(slot) => {
+                // There are as many free slots below `len` as there are
+                // entries past it.
+                let free = free.next().expect("free slot");
+                slots[free] = *slot;
+                free as u64
+            }
+            None => 0,
+        })
+        .collect();
+    (remap, slots)
+}
+
+// Returns the number of zero bytes after the pilots of `buckets` buckets, so
+// that the slots are aligned.
+fn pilots_padding(buckets: usize) -> usize {
+    buckets % 2 * size_of::<u32>()
+}
+
+/// A perfect hash index, read from the bytes of a map.
+#[derive(Clone)]
+pub struct Index<'a> {
+    seed: u64,
+    pilots: &'a [u32],
+    // The slot of each position past the last slot.
+    remap: &'a [u64],
+    slots: &'a [Slot],
+}
+
+impl<'a> Index<'a> {
+    /// Reads the index which starts at the start of `bytes`.  Returns `None`
+    /// if it does not fit into `bytes`, if they are not aligned, or if it has
+    /// no buckets, no slots, or fewer positions than slots.
+    pub fn read(bytes: &'a [u8]) -> Option<Index<'a>> {
+        let (header, rest) = LayoutVerified::<_, Header>::new_from_prefix(bytes)?;
+        let buckets = usize::try_from(header.buckets).ok()?;
+        let positions = usize::try_from(header.positions).ok()?;
+        let slots = usize::try_from(header.slots).ok()?;
+        if buckets == 0 || slots == 0 || positions < slots {
+            return None;
+        }
+        let (pilots, rest) = LayoutVerified::<_, [u32]>::new_slice_from_prefix(rest, buckets)?;
+        let rest = rest.get(pilots_padding(buckets)..)?;
+        let (remap, rest) =
+            LayoutVerified::<_, [u64]>::new_slice_from_prefix(rest, positions - slots)?;
+        let (slots, _) = LayoutVerified::<_, [Slot]>::new_slice_from_prefix(rest, slots)?;
+        Some(Index {
+            seed: header.seed,
+            pilots: pilots.into_slice(),
+            remap: remap.into_slice(),
+            slots: slots.into_slice(),
+        })
+    }
+
+    /// Returns the string index of the value of `key`, if it is in the index.
+    #[inline]
+    pub fn get(&self, key: u64) -> Option<usize> {
+        let hash = mix(key ^ self.seed);
+        let pilot = self.pilots[reduce(hash, self.pilots.len())];
+        let mut slot = position(hash, pilot, self.slots.len() + self.remap.len());
+        if slot >= self.slots.len() {
+            // The slot of a corrupt index may be out of bounds.
+            slot = usize::try_from(self.remap[slot - self.slots.len()]).ok()?;
+        }
+        let slot = self.slots.get(slot)?;
+        (slot.key == key).then_some(slot.string_index as usize)
+    }
+
+    /// Returns the keys in the index, and the string indexes of their values.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            slots: self.slots.iter(),
+        }
+    }
+}
+
+/// An iterator over the keys in a perfect hash index.  See [Index::entries].
+#[derive(Default)]
+pub struct Entries<'a> {
+    slots: std::slice::Iter<'a, Slot>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = (u64, usize);
+
+    fn next(&mut self) -> Option<(u64, usize)> {
+        let slot = self.slots.next()?;
+        Some((slot.key, slot.string_index as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the index of `entries` into words, so that it is aligned.
+    fn write_aligned(entries: &[(u64, usize)]) -> Vec<u64> {
+        let mut bytes = vec![];
+        assert!(write(entries, &mut bytes));
+        let mut words = vec![0_u64; bytes.len() / 8];
+        words.as_bytes_mut().copy_from_slice(&bytes);
+        words
+    }
+
+    #[test]
+    fn minimal() {
+        for len in [1, 2, 3, 99, 100, 1000] {
+            let entries: Vec<(u64, usize)> = (0..len as u64).map(|key| (key, 0)).collect();
+            let words = write_aligned(&entries);
+            let index = Index::read(words.as_bytes()).unwrap();
+            assert_eq!(len, index.slots.len());
+            assert_eq!(len * 100 / LOAD_PERCENT + 1 - len, index.remap.len());
+            assert!(index.remap.iter().all(|slot| *slot < len as u64));
+        }
+        // Slots past the end of a corrupt index are not found.
+        let mut words = write_aligned(&[(5, 1)]);
+        let remap = words.len() - 3;
+        words[remap] = 1;
+        let index = Index::read(words.as_bytes()).unwrap();
+        for key in (0..100).filter(|key| *key != 5) {
+            assert_eq!(None, index.get(key), "key: {}", key);
+        }
+    }
+
+    #[test]
+    fn no_function() {
+        // Keys which are the same can not be given slots of their own.
+        let mut bytes = vec![];
+        assert!(!write_with_max_pilot(&[(1, 0), (1, 1)], 100, &mut bytes));
+        assert!(bytes.is_empty());
+    }
+
+    // Keys beyond the number of pilots that are tried for a single bucket,
+    // for which the last buckets would need about as many tries as there are
+    // keys if all slots were taken.
+    #[test]
+    #[ignore = "takes minutes, and a few GB of memory"]
+    fn many_keys() {
+        let len = 1_u64 << 25;
+        let entries: Vec<(u64, usize)> = (0..len).map(|key| (key, key as usize)).collect();
+        let mut bytes = vec![];
+        assert!(write(&entries, &mut bytes));
+        drop(entries);
+        let mut words = vec![0_u64; bytes.len() / 8];
+        words.as_bytes_mut().copy_from_slice(&bytes);
+        drop(bytes);
+        let index = Index::read(words.as_bytes()).unwrap();
+        for key in (0..len).step_by(997) {
+            assert_eq!(Some(key as usize), index.get(key), "key: {}", key);
+        }
+        assert_eq!(None, index.get(len));
+    }
+
+    #[test]
+    fn write_and_read() {
+        for len in [1, 2, 3, 5, 100, 10000] {
+            let entries: Vec<(u64, usize)> = (0..len as u64)
+                .map(|key| (key.wrapping_mul(0x9e37_79b9_7f4a_7c15), key as usize))
+                .collect();
+            let words = write_aligned(&entries);
+            let index = Index::read(words.as_bytes()).unwrap();
+            let mut found: Vec<(u64, usize)> = index.entries().collect();
+            found.sort_unstable_by_key(|(_, string_index)| *string_index);
+            assert_eq!(entries, found);
+            for (key, string_index) in &entries {
+                assert_eq!(Some(*string_index), index.get(*key), "key: {}", key);
+            }
+            for key in 1 << 63..(1 << 63) + 100 {
+                assert_eq!(None, index.get(key), "key: {}", key);
+            }
+            let bytes = words.as_bytes();
+            for len in 0..bytes.len() {
+                assert!(Index::read(&bytes[..len]).is_none(), "len: {}", len);
+            }
+        }
+    }
+}
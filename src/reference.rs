@@ -0,0 +1,511 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A simple reader of maps, written from the description in
+//! [format](crate::format) without using any of the code of [Map](crate::Map).
+//! It is meant for checking the format, not for speed.
+
+use std::convert::TryInto;
+
+/// Looks up `key` in the map held by `bytes`, returning a copy of the found
+/// value.  Unlike [Map](crate::Map), this does not require `bytes` to be
+/// aligned, and returns `None` instead of panicking if `bytes` is malformed.
+pub fn lookup(bytes: &[u8], key: u64) -> Option<String> {
+    if read_u32(bytes, 0)? != 1 {
+        return None;
+    }
+    let string_format = *bytes.get(4)?;
+    let index_kind = *bytes.get(6)?;
+    let required_features = *bytes.get(7)?;
+    if required_features & !1 != 0 {
+        return None;
+    }
+    let mut table = read_u64(bytes, 8)?;
+    let strings = read_u64(bytes, 16)?;
+    let extension_words = *bytes.get(5)?;
+    if required_features & 1 != 0 {
+        if extension_words < 2 {
+            return None;
+        }
+        let dense = read_u64(bytes, 64)?;
+        if dense != 0 {
+            let base = read_u64(bytes, dense)?;
+            let len = read_u64(bytes, dense.checked_add(8)?)?;
+            let position = key.wrapping_sub(base);
+            if position < len {
+                let index = read_u64(
+                    bytes,
+                    dense
+                        .checked_add(16)?
+                        .checked_add(position.checked_mul(8)?)?,
+                )?;
+                if index == u64::MAX {
+                    return None;
+                }
+                return read_value(bytes, string_format, strings, index);
+            }
+        }
+    }
+    if table == 0 {
+        return None;
+    }
+    match index_kind {
+        0 => {}
+        1 => {
+            let index = lookup_perfect_hash(bytes, table, key)?;
+            return read_value(bytes, string_format, strings, index);
+        }
+        _ => return None,
+    }
+
+    let mut remaining: u64 = 64;
+    let mut running = key;
+    loop {
+        if remaining == 0 {
+            return None;
+        }
+        let table_type = read_u32(bytes, table)?;
+        let bits = *bytes.get(offset(table, 8)?)? as u64;
+        let format = *bytes.get(offset(table, 9)?)?;
+        if bits == 0 || bits > 16 {
+            return None;
+        }
+        let cell_size = match format {
+            0 => 16,
+            1 => 8,
+            _ => return None,
+        };
+        let slot = running & ((1 << bits) - 1);
+        running >>= bits;
+        remaining = remaining.saturating_sub(bits);
+
+        let cells = table + 16;
+        let position = match table_type {
+            2 => slot,
+            4 => {
+                let word = cells + slot / 32 * 8;
+                let rank = read_u32(bytes, word)? as u64;
+                let bitmap = read_u32(bytes, word + 4)?;
+                let bit = 1 << (slot % 32);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                rank + (bitmap & (bit - 1)).count_ones() as u64
+            }
+            _ => return None,
+        };
+        let words = match table_type {
+            4 => ((1_u64 << bits) + 31) / 32 * 8,
+            _ => 0,
+        };
+        let cell = cells + words + position * cell_size;
+        let (tag_and_index, extra) = match format {
+            0 => (read_u64(bytes, cell)?, read_u64(bytes, cell + 8)?),
+            _ => (
+                read_u32(bytes, cell)? as u64,
+                read_u32(bytes, cell + 4)? as u64,
+            ),
+        };
+        let tag = tag_and_index & 0b111;
+        let index = tag_and_index >> 3;
+        match tag {
+            0 => return None,
+            1 => {
+                let expected = match format {
+                    0 => key,
+                    _ => running,
+                };
+                if extra != expected {
+                    return None;
+                }
+                return read_value(bytes, string_format, strings, index);
+            }
+            2 => table = index,
+            3 => {
+                if extra == 0 {
+                    return None;
+                }
+                let n = 63 - extra.leading_zeros() as u64;
+                let pattern = extra & !(1 << n);
+                if running & ((1 << n) - 1) != pattern {
+                    return None;
+                }
+                running >>= n;
+                remaining = remaining.saturating_sub(n);
+                table = index;
+            }
+            _ => return None,
+        }
+    }
+}
+
+// Returns the index of the value of `key` in the perfect hash index at
+// `at`.
+fn lookup_perfect_hash(bytes: &[u8], at: u64, key: u64) -> Option<u64> {
+    let seed = read_u64(bytes, at)?;
+    let buckets = read_u64(bytes, at.checked_add(8)?)?;
+    let positions = read_u64(bytes, at.checked_add(16)?)?;
+    let slots_len = read_u64(bytes, at.checked_add(24)?)?;
+    if buckets == 0 || slots_len == 0 || positions < slots_len {
+        return None;
+    }
+    let pilots = at.checked_add(32)?;
+    let remap = pilots.checked_add(buckets.checked_add(buckets % 2)?.checked_mul(4)?)?;
+    let slots = remap.checked_add((positions - slots_len).checked_mul(8)?)?;
+    let hash = mix(key ^ seed);
+    let pilot = read_u32(bytes, pilots.checked_add(reduce(hash, buckets) * 4)?)? as u64;
+    let mut position = reduce(mix(hash ^ mix(pilot)), positions);
+    if position >= slots_len {
+        position = read_u64(bytes, remap.checked_add((position - slots_len) * 8)?)?;
+        if position >= slots_len {
+            return None;
+        }
+    }
+    let slot = slots.checked_add(position.checked_mul(16)?)?;
+    if read_u64(bytes, slot)? != key {
+        return None;
+    }
+    read_u64(bytes, slot.checked_add(8)?)
+}
+
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    x ^ x >> 33
+}
+
+fn reduce(x: u64, n: u64) -> u64 {
+    ((x as u128 * n as u128) >> 64) as u64
+}
+
+/// Returns the keys which map to `value` in the map held by `bytes`,
+/// according to its reverse index, in ascending order.  Returns no keys if
+/// the map has no reverse index, or if `bytes` is malformed.
+pub fn keys_for(bytes: &[u8], value: &str) -> Vec<u64> {
+    read_keys(bytes, value).unwrap_or_default()
+}
+
+fn read_keys(bytes: &[u8], value: &str) -> Option<Vec<u64>> {
+    if read_u32(bytes, 0)? != 1 {
+        return None;
+    }
+    let string_format = *bytes.get(4)?;
+    let extension_words = *bytes.get(5)?;
+    let strings = read_u64(bytes, 16)?;
+    if extension_words == 0 {
+        return None;
+    }
+    let reverse = read_u64(bytes, 56)?;
+    if reverse == 0 {
+        return None;
+    }
+    // The values are searched one after the other, which is simpler than a
+    // binary search.
+    let n = read_u64(bytes, reverse)?;
+    let positions = reverse.checked_add(n.checked_add(1)?.checked_mul(8)?)?;
+    let keys = positions.checked_add(n.checked_add(1)?.checked_mul(8)?)?;
+    for i in 0..n {
+        let index = read_u64(bytes, reverse + 8 + i * 8)?;
+        if read_value(bytes, string_format, strings, index).as_deref() != Some(value) {
+            continue;
+        }
+        let start = read_u64(bytes, positions + i * 8)?;
+        let end = read_u64(bytes, positions + i * 8 + 8)?;
+        return (start..end)
+            .map(|position| read_u64(bytes, keys.checked_add(position.checked_mul(8)?)?))
+            .collect();
+    }
+    None
+}
+
+// Reads the value at `index` of the string section at `strings`.
+fn read_value(bytes: &[u8], string_format: u8, strings: u64, index: u64) -> Option<String> {
+    match string_format {
+        0 => read_string(bytes, strings.checked_add(index)?),
+        1 => read_symbols_string(bytes.get(offset(strings, 0)?..)?, index),
+        2 => read_blocks_string(bytes.get(offset(strings, 0)?..)?, index),
+        _ => None,
+    }
+}
+
+fn offset(base: u64, delta: u64) -> Option<usize> {
+    base.checked_add(delta)?.try_into().ok()
+}
+
+fn read_u32(bytes: &[u8], at: u64) -> Option<u32> {
+    let at = offset(at, 0)?;
+    let field = bytes.get(at..at.checked_add(4)?)?;
+    Some(u32::from_ne_bytes(field.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], at: u64) -> Option<u64> {
+    let at = offset(at, 0)?;
+    let field = bytes.get(at..at.checked_add(8)?)?;
+    Some(u64::from_ne_bytes(field.try_into().unwrap()))
+}
+
+// Reads the zero terminated string at `at`.
+fn read_string(bytes: &[u8], at: u64) -> Option<String> {
+    let rest = bytes.get(offset(at, 0)?..)?;
+    let len = rest.iter().position(|byte| *byte == 0)?;
+    String::from_utf8(rest[..len].to_vec()).ok()
+}
+
+// Decodes the value at `index` of the symbols string section `section`.
+fn read_symbols_string(section: &[u8], index: u64) -> Option<String> {
+    let n = *section.first()? as usize;
+    let lens = section.get(1..1 + n)?;
+    let mut symbols = vec![];
+    let mut start = 1 + n;
+    for len in lens {
+        let len = *len as usize;
+        if len == 0 || len > 8 {
+            return None;
+        }
+        symbols.push(section.get(start..start + len)?);
+        start += len;
+    }
+
+    let mut at = offset(index, 0)?;
+    let mut len: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *section.get(at)?;
+        at += 1;
+        len |= ((byte & 0x7f) as u64).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+    let codes = section.get(at..offset(at as u64, len)?)?;
+    let mut value = vec![];
+    let mut position = 0;
+    while position < codes.len() {
+        let code = codes[position];
+        position += 1;
+        if code == 255 {
+            value.push(*codes.get(position)?);
+            position += 1;
+        } else {
+            value.extend_from_slice(symbols.get(code as usize)?);
+        }
+    }
+    String::from_utf8(value).ok()
+}
+
+// Decodes the value at `index` of the blocks string section `section`.
+fn read_blocks_string(section: &[u8], index: u64) -> Option<String> {
+    let count = read_u32(section, 0)? as u64;
+    let bits = read_u32(section, 4)? as u64;
+    if bits == 0 || bits > 32 {
+        return None;
+    }
+    let block = index >> bits;
+    if block >= count {
+        return None;
+    }
+    let start = read_u64(section, 8 + block * 8)?;
+    let end = read_u64(section, 16 + block * 8)?;
+    let compressed = section.get(offset(start, 0)?..offset(end, 0)?)?;
+    let block = decompress(compressed)?;
+    read_string(&block, index & ((1 << bits) - 1))
+}
+
+// Decompresses a block of the blocks string format.
+fn decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut at = 0;
+    let mut next = || {
+        let byte = *input.get(at)?;
+        at += 1;
+        Some(byte)
+    };
+    let mut len: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = next()?;
+        len |= ((byte & 0x7f) as u64).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            return None;
+        }
+    }
+    let mut out = vec![];
+    while (out.len() as u64) < len {
+        let token = next()?;
+        let mut literals = (token >> 4) as u64;
+        if literals == 15 {
+            loop {
+                let byte = next()?;
+                literals += byte as u64;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        for _ in 0..literals {
+            out.push(next()?);
+        }
+        if out.len() as u64 >= len {
+            break;
+        }
+        let distance = next()? as usize | (next()? as usize) << 8;
+        let mut copy = (token & 15) as u64;
+        if copy == 15 {
+            loop {
+                let byte = next()?;
+                copy += byte as u64;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        if distance == 0 || distance > out.len() || out.len() as u64 + copy + 4 > len {
+            return None;
+        }
+        for _ in 0..copy + 4 {
+            out.push(out[out.len() - distance]);
+        }
+    }
+    if out.len() as u64 != len {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Builder, CompressedMap, Compression, IndexKind, Layout};
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn layout() -> impl Strategy<Value = Layout> {
+        prop_oneof![
+            Just(Layout::Insertion),
+            Just(Layout::DepthFirst),
+            Just(Layout::BreadthFirst),
+            Just(Layout::VanEmdeBoas),
+        ]
+    }
+
+    fn compression() -> impl Strategy<Value = Compression> {
+        prop_oneof![
+            Just(Compression::None),
+            Just(Compression::Symbols),
+            Just(Compression::Blocks),
+        ]
+    }
+
+    fn index_kind() -> impl Strategy<Value = IndexKind> {
+        prop_oneof![Just(IndexKind::Trie), Just(IndexKind::PerfectHash)]
+    }
+
+    // Keys which are either small, so that tables fill up, or anywhere in the
+    // key space, so that there are long chains of tables.
+    fn key() -> impl Strategy<Value = u64> {
+        prop_oneof![0..1024_u64, any::<u64>()]
+    }
+
+    proptest! {
+        #[test]
+        fn agrees_with_map(
+            schedule in prop::collection::vec(2..=16_usize, 1..4),
+            layout in layout(),
+            compression in compression(),
+            index_kind in index_kind(),
+            reverse_index in any::<bool>(),
+            dense_range in any::<bool>(),
+            entries in prop::collection::btree_map(key(), "[a-z]{0,3}", 0..200),
+            run in 0..150_u64,
+            removed in prop::collection::vec(key(), 0..20),
+            missing in prop::collection::vec(key(), 0..50),
+        ) {
+            // A run of consecutive keys, which may be a dense range.
+            let mut entries = entries;
+            for key in (1 << 40)..(1 << 40) + run {
+                entries.insert(key, format!("{}", key % 5));
+            }
+            let mut builder = Builder::with_schedule(&schedule);
+            builder.set_layout(layout);
+            builder.set_compression(compression);
+            builder.set_index_kind(index_kind);
+            builder.set_reverse_index(reverse_index);
+            builder.set_dense_range(dense_range);
+            let mut expected = BTreeMap::new();
+            for (key, value) in &entries {
+                builder.insert(*key, value);
+                expected.insert(*key, value.clone());
+            }
+            for key in removed.iter().chain(entries.keys().step_by(7)) {
+                builder.remove(*key);
+                expected.remove(key);
+            }
+            let bytes = builder.build();
+            let map = CompressedMap::new(&bytes);
+            for key in entries.keys().chain(&removed).chain(&missing) {
+                let found = lookup(&bytes, *key);
+                let value = map.get(*key);
+                prop_assert_eq!(value.as_deref(), found.as_deref(), "key: {}", key);
+                prop_assert_eq!(expected.get(key), found.as_ref(), "key: {}", key);
+            }
+            for value in entries.values().map(|value| value.as_str()).chain(Some("missing")) {
+                let found = keys_for(&bytes, value);
+                prop_assert_eq!(map.keys_for(value).collect::<Vec<_>>(), found.clone());
+                let keys: Vec<u64> = expected
+                    .iter()
+                    .filter(|(_, expected)| *expected == value && reverse_index)
+                    .map(|(key, _)| *key)
+                    .collect();
+                prop_assert_eq!(keys, found, "value: {}", value);
+            }
+        }
+
+        #[test]
+        fn does_not_panic_on_corrupt_maps(
+            entries in prop::collection::btree_map(key(), "[a-z]{0,3}", 1..50),
+            corruption in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+            keys in prop::collection::vec(key(), 0..20),
+            compression in compression(),
+            index_kind in index_kind(),
+        ) {
+            let mut builder = Builder::new(4);
+            builder.set_compression(compression);
+            builder.set_index_kind(index_kind);
+            builder.set_reverse_index(true);
+            for (key, value) in &entries {
+                builder.insert(*key, value);
+            }
+            let mut bytes = builder.build();
+            for (index, value) in corruption {
+                let len = bytes.len();
+                bytes[index.index(len)] = value;
+            }
+            for key in entries.keys().chain(&keys) {
+                lookup(&bytes, *key);
+            }
+            for value in entries.values() {
+                keys_for(&bytes, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::MapBuf;
+use arc_swap::ArcSwap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Identifies a version of the map file.  Files are assumed to be unchanged as
+// long as their modification time and length are.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Version {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl Version {
+    fn of(metadata: &fs::Metadata) -> io::Result<Version> {
+        Ok(Version {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+struct Current {
+    map: MapBuf,
+    version: Version,
+}
+
+/// A map which is loaded from a file, and which can be replaced with a newer
+/// version of that file while it is in use.
+///
+/// Lookups are made on the [MapBuf] returned by [ReloadableMap::current].
+/// Reloading replaces the map that later calls return, while the maps that
+/// were returned before stay valid until they are dropped, so that readers
+/// which are in the middle of their lookups finish them on the old version.
+/// The current map is swapped atomically, so readers take no lock, and are
+/// never blocked by a reload.
+///
+/// To avoid reading a partially written file, new versions of the file
+/// should be written under a different name, and then renamed to the path
+/// of the map.
+pub struct ReloadableMap {
+    path: PathBuf,
+    current: ArcSwap<Current>,
+}
+
+impl ReloadableMap {
+    /// Loads the map from the file at `path`.  Files which [MapBuf::new]
+    /// rejects, such as maps with compressed values, are an error of kind
+    /// `InvalidData`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ReloadableMap> {
+        let path = path.as_ref().to_path_buf();
+        let (map, version) = load(&path)?;
+        Ok(ReloadableMap {
+            path,
+            current: ArcSwap::from_pointee(Current { map, version }),
+        })
+    }
+
+    /// Returns the path that the map is loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the most recently loaded version of the map.
+    pub fn current(&self) -> MapBuf {
+        self.current.load().map.clone()
+    }
+
+    /// Loads the file again, whether it has changed or not.  If the file can
+    /// not be read, or does not hold a valid map, the error is returned and
+    /// the current map is kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let (map, version) = load(&self.path)?;
+        self.current.store(Arc::new(Current { map, version }));
+        Ok(())
+    }
+
+    /// Loads the file again if its modification time or length differ from
+    /// those of the version that was loaded last.  Returns whether the map was
+    /// reloaded.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let version = Version::of(&fs::metadata(&self.path)?)?;
+        if version == self.current.load().version {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Starts a thread which calls [ReloadableMap::reload_if_changed] every
+    /// `interval`, until all other references to `map` are dropped.  Failed
+    /// reloads are retried on the next check.
+    pub fn watch(map: &Arc<ReloadableMap>, interval: Duration) -> thread::JoinHandle<()> {
+        let map = Arc::downgrade(map);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match map.upgrade() {
+                Some(map) => {
+                    let _ = map.reload_if_changed();
+                }
+                None => return,
+            }
+        })
+    }
+}
+
+// Reads and validates the map at `path`.
+fn load(path: &Path) -> io::Result<(MapBuf, Version)> {
+    let file = fs::File::open(path)?;
+    let version = Version::of(&file.metadata()?)?;
+    let mut bytes = Vec::with_capacity(version.len as usize);
+    io::Read::read_to_end(&mut &file, &mut bytes)?;
+    // The version is only used to detect changes, so it does not matter that
+    // it may be older than the contents that were read.
+    let map = MapBuf::new(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok((map, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Builder;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    // A file in the temporary directory which is removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let name = format!("sequence-map-{}-{}", std::process::id(), name);
+            TempFile(std::env::temp_dir().join(name))
+        }
+
+        // Writes `bytes` to the file, with a modification time which differs
+        // from that of the previous version.
+        fn write(&self, bytes: &[u8], seconds: u64) {
+            fs::write(&self.0, bytes).unwrap();
+            let file = fs::File::options().write(true).open(&self.0).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            file.set_modified(modified).unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn build(value: &str) -> Vec<u8> {
+        let mut builder = Builder::new(4);
+        builder.insert(42, value);
+        builder.build()
+    }
+
+    #[test]
+    fn reload() {
+        let file = TempFile::new("reload");
+        file.write(&build("Hello!"), 1000);
+        let map = ReloadableMap::open(&file.0).unwrap();
+        let old = map.current();
+        assert_eq!("Hello!", old.get(42).unwrap());
+        assert!(!map.reload_if_changed().unwrap());
+
+        file.write(&build("World!"), 2000);
+        assert!(map.reload_if_changed().unwrap());
+        assert_eq!("World!", map.current().get(42).unwrap());
+        // Readers of the old version are not affected.
+        assert_eq!("Hello!", old.get(42).unwrap());
+        assert!(!map.reload_if_changed().unwrap());
+
+        // Invalid files are not loaded.
+        file.write(&[0; 100], 3000);
+        let err = map.reload_if_changed().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!("World!", map.current().get(42).unwrap());
+
+        file.write(&build("Again!"), 3000);
+        map.reload().unwrap();
+        assert_eq!("Again!", map.current().get(42).unwrap());
+    }
+
+    #[test]
+    fn reads_during_reloads() {
+        let file = TempFile::new("reads");
+        file.write(&build("Hello!"), 1000);
+        let map = Arc::new(ReloadableMap::open(&file.0).unwrap());
+        let reads = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+                let reads = reads.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        let value = map.current().get(42).unwrap().to_string();
+                        assert!(value == "Hello!" || value == "World!", "{}", value);
+                        reads.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        for version in 0..100 {
+            let value = if version % 2 == 0 { "World!" } else { "Hello!" };
+            file.write(&build(value), 2000 + version);
+            let before = reads.load(Ordering::Relaxed);
+            map.reload().unwrap();
+            // The readers go on with the new map.
+            while reads.load(Ordering::Relaxed) == before {
+                thread::yield_now();
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!("Hello!", map.current().get(42).unwrap());
+    }
+
+    #[test]
+    fn open_missing() {
+        let file = TempFile::new("missing");
+        let err = ReloadableMap::open(&file.0).err().unwrap();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn watch() {
+        let file = TempFile::new("watch");
+        file.write(&build("Hello!"), 1000);
+        let map = Arc::new(ReloadableMap::open(&file.0).unwrap());
+        let watcher = ReloadableMap::watch(&map, Duration::from_millis(1));
+
+        file.write(&build("World!"), 2000);
+        let start = SystemTime::now();
+        while map.current().get(42).unwrap() != "World!" {
+            assert!(start.elapsed().unwrap() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The thread stops once the map is gone.
+        drop(map);
+        watcher.join().unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The reverse index of a map, which finds the keys that map to a value.  See
+//! [format](crate::format) for its layout.
+
+use std::convert::TryInto;
+use zerocopy::LayoutVerified;
+
+/// Appends the reverse index of `values` to `out`.  Each value is given by
+/// its string index and the keys which map to it, and the values must be in
+/// the order of their contents.
+pub fn write(values: &[(usize, Vec<u64>)], out: &mut Vec<u8>) {
+    let mut push = |word: u64| out.extend_from_slice(&word.to_ne_bytes());
+    push(values.len() as u64);
+    for (index, _) in values {
+        push(*index as u64);
+    }
+    let mut start = 0;
+    for (_, keys) in values {
+        push(start);
+        start += keys.len() as u64;
+    }
+    push(start);
+    for (_, keys) in values {
+        for key in keys {
+            push(*key);
+        }
+    }
+}
+
+/// A reverse index, read from the bytes of a map.
+pub struct Index<'a> {
+    // The string index of each value, in the order of their contents.
+    indexes: &'a [u64],
+    // The position in `keys` of the first key of each value, and then the
+    // number of keys.
+    starts: &'a [u64],
+    keys: &'a [u64],
+}
+
+impl<'a> Index<'a> {
+    /// Reads the reverse index which starts at the start of `bytes`.  Returns
+    /// `None` if it does not fit into `bytes`, or they are not aligned.  The
+    /// contents are not checked.
+    pub fn read(bytes: &'a [u8]) -> Option<Index<'a>> {
+        let words: &[u64] = LayoutVerified::new_slice_from_prefix(bytes, bytes.len() / 8)?
+            .0
+            .into_slice();
+        let (len, words) = words.split_first()?;
+        let len: usize = (*len).try_into().ok()?;
+        if words.is_empty() || len > (words.len() - 1) / 2 {
+            return None;
+        }
+        let (indexes, words) = words.split_at(len);
+        let (starts, words) = words.split_at(len + 1);
+        let keys_len: usize = starts[len].try_into().ok()?;
+        Some(Index {
+            indexes,
+            starts,
+            keys: words.get(..keys_len)?,
+        })
+    }
+
+    /// Returns the number of values.
+    pub fn len(&self) -> usize {
+        self.indexes.len()
+    }
+
+    /// Returns the string index of the value at `position`.
+    pub fn string_index(&self, position: usize) -> usize {
+        self.indexes[position] as usize
+    }
+
+    /// Returns the keys which map to the value at `position`.  These are
+    /// empty if the index is corrupt.
+    pub fn keys(&self, position: usize) -> &'a [u64] {
+        let start = self.starts[position] as usize;
+        let end = self.starts[position + 1] as usize;
+        self.keys.get(start..end.max(start)).unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read() {
+        let values = vec![(8, vec![1, 5]), (0, vec![]), (3, vec![2])];
+        let mut bytes = vec![];
+        write(&values, &mut bytes);
+        let words: Vec<u64> = bytes
+            .chunks(8)
+            .map(|word| u64::from_ne_bytes(word.try_into().unwrap()))
+            .collect();
+        // Copied into words, so that they are aligned.
+        let bytes = zerocopy::AsBytes::as_bytes(&words[..]);
+        let index = Index::read(bytes).unwrap();
+        assert_eq!(3, index.len());
+        for (position, (string_index, keys)) in values.iter().enumerate() {
+            assert_eq!(*string_index, index.string_index(position));
+            assert_eq!(&keys[..], index.keys(position));
+        }
+        for len in 0..bytes.len() {
+            assert!(Index::read(&bytes[..len]).is_none(), "len: {}", len);
+        }
+    }
+}
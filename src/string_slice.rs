@@ -14,9 +14,10 @@
 
 #![allow(dead_code)]
 
-use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::ffi;
-use std::string;
+use std::fmt;
 
 /// Internally stores strings in a long sequence.  Same strings are deduped.
 #[derive(Debug)]
@@ -25,53 +26,304 @@ pub struct Intern {
     // byte at the end of each.  This makes it easy to produce both rust strings
     // and C strings from this same representation.
     strings: Vec<u8>,
-    // A map of seen strings and their respective offsets, from the beginning
-    // of the string.  On a repeated insert, no additional space is reserved
-    // for a string duplicate.
-    seen: BTreeMap<string::String, usize>,
+    // An open addressing hash table of the offsets of the seen strings, from
+    // the beginning of the strings, plus one.  Zero marks a free slot.  The
+    // strings are compared where they are stored, so that no copies of them
+    // are needed.  On a repeated insert, no additional space is reserved for
+    // a string duplicate.  The number of slots is a power of two, and at most
+    // half of them are used.
+    slots: Vec<usize>,
+    // The number of used slots.
+    seen: usize,
 }
 
-impl Into<Vec<u8>> for Intern {
-    fn into(self) -> Vec<u8> {
-        self.strings
+// The smallest number of slots of the hash table, when it has any.
+const MIN_SLOTS: usize = 16;
+
+impl From<Intern> for Vec<u8> {
+    fn from(intern: Intern) -> Vec<u8> {
+        intern.strings
+    }
+}
+
+impl From<Vec<u8>> for Intern {
+    /// Reconstructs an intern table from a sequence of strings previously
+    /// produced by an [Intern].  The dedup table is rebuilt from the strings
+    /// found in the sequence, the first occurrence of each string wins.
+    fn from(strings: Vec<u8>) -> Intern {
+        let mut intern = Intern {
+            strings,
+            slots: vec![],
+            seen: 0,
+        };
+        let mut index = 0;
+        while index < intern.strings.len() {
+            let len = intern.get(index).content().to_bytes_with_nul().len();
+            intern.mark(index);
+            index += len;
+        }
+        intern
     }
 }
 
+// Hashes the bytes of a string, in the style of FxHash: 8 bytes at a time,
+// each mixed in with a multiplication.  The high bits are the best mixed.
+fn hash(bytes: &[u8]) -> u64 {
+    const SEED: u64 = 0x517c_c1b7_2722_0a95;
+    let mix = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    let mut chunks = bytes.chunks_exact(8);
+    let mut hash = bytes.len() as u64;
+    for chunk in &mut chunks {
+        hash = mix(hash, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut rest = [0; 8];
+    rest[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    mix(hash, u64::from_le_bytes(rest))
+}
+
 impl Intern {
     pub fn new() -> Intern {
         Intern {
             strings: vec![],
-            seen: BTreeMap::new(),
+            slots: vec![],
+            seen: 0,
         }
     }
 
     /// Add the string `s` to the string intern table.
     pub fn add(&mut self, s: &str) -> usize {
-        let seen = self.seen.get(&s.to_string());
-        match seen {
-            Some(index) => {
-                String::over(&self.strings[*index..]);
-                *index
-            }
-            None => {
+        self.reserve_slot();
+        match self.find(s.as_bytes()) {
+            Ok(index) => index,
+            Err(slot) => {
                 let index = self.strings.len();
-                let new_index = String::required_len(s) + index;
-                self.seen.insert(s.to_string(), index);
-                self.strings.resize(new_index, 0);
-                String::init(s, &mut self.strings[index..new_index]);
+                self.strings.reserve(String::required_len(s));
+                self.strings.extend_from_slice(s.as_bytes());
+                self.strings.push(0);
+                self.slots[slot] = index + 1;
+                self.seen += 1;
                 index
             }
         }
     }
-    
-    ///
-    pub fn get(&self, index:usize) -> String<'_> {
+
+    /// Gets the string stored at `index`.
+    pub fn get(&self, index: usize) -> String<'_> {
         String::over(&self.strings[index..])
     }
 
     pub fn len(&self) -> usize {
         self.strings.len()
     }
+
+    /// Returns each distinct string, and its index, in the order of the
+    /// indexes.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+        let mut indexes: Vec<usize> = self
+            .slots
+            .iter()
+            .filter(|slot| **slot != 0)
+            .map(|slot| slot - 1)
+            .collect();
+        indexes.sort_unstable();
+        indexes
+            .into_iter()
+            .map(move |index| (index, self.get(index).to_str()))
+    }
+
+    // Looks up the string with the content `bytes`.  Returns its index, if it
+    // was seen, or else the free slot at which to record it.  There must be at
+    // least one free slot.
+    fn find(&self, bytes: &[u8]) -> Result<usize, usize> {
+        let mask = self.slots.len() - 1;
+        let mut slot = (hash(bytes) >> (64 - self.slots.len().trailing_zeros())) as usize;
+        loop {
+            match self.slots[slot] {
+                0 => return Err(slot),
+                used => {
+                    let index = used - 1;
+                    let end = index + bytes.len();
+                    if self.strings.get(index..end) == Some(bytes) && self.strings[end] == 0 {
+                        return Ok(index);
+                    }
+                }
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    // Makes sure that one more string can be recorded in the hash table,
+    // doubling its size if needed.
+    fn reserve_slot(&mut self) {
+        if (self.seen + 1) * 2 <= self.slots.len() {
+            return;
+        }
+        let len = (self.slots.len() * 2).max(MIN_SLOTS);
+        let slots = std::mem::replace(&mut self.slots, vec![0; len]);
+        for used in slots.into_iter().filter(|slot| *slot != 0) {
+            let content = self.get(used - 1).content().to_bytes();
+            let slot = self.find(content).expect_err("distinct strings");
+            self.slots[slot] = used;
+        }
+    }
+
+    /// Interns the strings of all of `parts` into a single table, with the
+    /// work spread over multiple threads.  Also returns, for each part, the
+    /// index in the merged table of each of its strings, by their index in
+    /// the part.
+    ///
+    /// The strings are split up into shards by their hash, so that equal
+    /// strings end up in the same shard, and each shard is interned on its
+    /// own.  Only joining the shards, which copies their bytes and records
+    /// the already known hashes, is done on the calling thread.
+    #[cfg(feature = "rayon")]
+    pub fn merge(parts: &[Intern]) -> (Intern, Vec<HashMap<usize, usize>>) {
+        use rayon::prelude::*;
+
+        let shards = (rayon::current_num_threads() * 4).next_power_of_two();
+        // The high bits of the hashes index the hash tables, so other bits
+        // pick the shard, which spreads the strings of each shard over the
+        // whole table.
+        let shard_of = |hash: u64| (hash >> 24) as usize & (shards - 1);
+        // The index, the content and the hash of strings, by shard.
+        type Sharded<'a> = Vec<Vec<(usize, &'a str, u64)>>;
+        let sharded: Vec<Sharded> = parts
+            .par_iter()
+            .map(|part| {
+                let mut sharded = vec![vec![]; shards];
+                for (index, content) in part.iter() {
+                    let hash = hash(content.as_bytes());
+                    sharded[shard_of(hash)].push((index, content, hash));
+                }
+                sharded
+            })
+            .collect();
+        // Each shard, the index and the hash of each of its distinct strings,
+        // and the index in the shard of the strings of each part.
+        type Shard = (Intern, Vec<(usize, u64)>, Vec<Vec<(usize, usize)>>);
+        let interned: Vec<Shard> = (0..shards)
+            .into_par_iter()
+            .map(|shard| {
+                let mut intern = Intern::new();
+                let mut distinct = vec![];
+                let mut indexes = vec![];
+                for part in &sharded {
+                    let mut part_indexes = vec![];
+                    for (index, content, hash) in &part[shard] {
+                        let len = intern.len();
+                        let shard_index = intern.add(content);
+                        if shard_index == len {
+                            distinct.push((shard_index, *hash));
+                        }
+                        part_indexes.push((*index, shard_index));
+                    }
+                    indexes.push(part_indexes);
+                }
+                (intern, distinct, indexes)
+            })
+            .collect();
+
+        let mut merged = Intern::new();
+        let seen: usize = interned.iter().map(|(_, distinct, _)| distinct.len()).sum();
+        if seen > 0 {
+            let len = ((seen + 1) * 2).next_power_of_two().max(MIN_SLOTS);
+            merged.slots = vec![0; len];
+        }
+        let mut starts = vec![];
+        for (intern, distinct, _) in &interned {
+            let start = merged.strings.len();
+            starts.push(start);
+            merged.strings.extend_from_slice(&intern.strings);
+            for (index, hash) in distinct {
+                merged.record(start + index, *hash);
+            }
+        }
+        let indexes = (0..parts.len())
+            .into_par_iter()
+            .map(|part| {
+                let shards = interned.iter().zip(&starts);
+                shards
+                    .flat_map(|((_, _, indexes), start)| {
+                        let indexes = indexes[part].iter();
+                        indexes.map(move |(index, shard_index)| (*index, start + shard_index))
+                    })
+                    .collect()
+            })
+            .collect();
+        (merged, indexes)
+    }
+
+    // Records the string at `index`, with the hash `hash`, in the hash table,
+    // which must have room for it.  The string must not be recorded yet.
+    #[cfg(feature = "rayon")]
+    fn record(&mut self, index: usize, hash: u64) {
+        let mask = self.slots.len() - 1;
+        let mut slot = (hash >> (64 - self.slots.len().trailing_zeros())) as usize;
+        while self.slots[slot] != 0 {
+            slot = (slot + 1) & mask;
+        }
+        self.slots[slot] = index + 1;
+        self.seen += 1;
+    }
+
+    /// Records that the string at `index` is stored there, for strings which
+    /// start within another string.  Returns `index`.
+    pub fn mark(&mut self, index: usize) -> usize {
+        self.reserve_slot();
+        if let Err(slot) = self.find(self.get(index).content().to_bytes()) {
+            self.slots[slot] = index + 1;
+            self.seen += 1;
+        }
+        index
+    }
+
+    /// Same as [Intern::mark], except that `None` is returned if no valid
+    /// string starts at `index`.
+    pub fn try_mark(&mut self, index: usize) -> Option<usize> {
+        let string = ffi::CStr::from_bytes_until_nul(self.strings.get(index..)?).ok()?;
+        string.to_str().ok()?;
+        Some(self.mark(index))
+    }
+
+    /// Returns a copy in which each string that is the end of another string
+    /// is stored within that other string, instead of on its own.  Also
+    /// returns the index in the copy of each string, by the string's index in
+    /// this table, and the number of strings which are stored within others.
+    pub fn share_suffixes(&self) -> (Intern, HashMap<usize, usize>, usize) {
+        let mut strings: Vec<(&str, usize)> = self
+            .iter()
+            .map(|(index, content)| (content, index))
+            .collect();
+        // Sorted by their reversed bytes, each string is followed by all the
+        // strings that end with it, if there are any.
+        strings.sort_unstable_by(|(a, _), (b, _)| a.bytes().rev().cmp(b.bytes().rev()));
+        // The position in `strings` of the string which each string is
+        // stored within, possibly itself.
+        let mut owners: Vec<usize> = (0..strings.len()).collect();
+        for position in (0..strings.len().saturating_sub(1)).rev() {
+            if strings[position + 1].0.ends_with(strings[position].0) {
+                owners[position] = owners[position + 1];
+            }
+        }
+
+        // The strings which are stored on their own keep their order.
+        let mut stored: Vec<usize> = (0..strings.len())
+            .filter(|position| owners[*position] == *position)
+            .collect();
+        stored.sort_unstable_by_key(|position| strings[*position].1);
+        let mut shared = Intern::new();
+        let mut indexes = HashMap::new();
+        for position in &stored {
+            let (content, index) = strings[*position];
+            indexes.insert(index, shared.add(content));
+        }
+        for (position, (content, index)) in strings.iter().enumerate() {
+            let (owner, owner_index) = strings[owners[position]];
+            let new_index = indexes[&owner_index] + owner.len() - content.len();
+            indexes.insert(*index, shared.mark(new_index));
+        }
+        (shared, indexes, strings.len() - stored.len())
+    }
 }
 
 /// Represents a reference to an interned string.  The reference's lifetime
@@ -94,16 +346,12 @@ impl<'a> String<'a> {
 
     /// Returns the content of the string.
     pub fn content(&self) -> &'a ffi::CStr {
-        &self.content
+        self.content
     }
 
-    /// Converts the string to 
+    /// Converts the string to a rust string slice.
     pub fn to_str(&self) -> &'a str {
-        &self.content.to_str().expect("to_str success")
-    }
-
-    pub fn to_string(&self) -> string::String {
-        self.to_str().to_string()
+        self.content.to_str().expect("to_str success")
     }
 
     // Initializes a string into the given buffer.  The buffer must have
@@ -124,17 +372,24 @@ impl<'a> String<'a> {
         String { content }
     }
 
-    /// Overlays a string on top of the supplied buffer.
+    /// Overlays a string on top of the supplied buffer.  Panics if there is
+    /// no NUL byte in the buffer to end the string.
     pub fn over(buffer: &'a [u8]) -> String<'a> {
-        let content =
-            unsafe { std::ffi::CStr::from_ptr(buffer.as_ptr() as *const std::os::raw::c_char) };
+        let content = ffi::CStr::from_bytes_until_nul(buffer).expect("NUL-terminated string");
         String { content }
     }
 }
 
+impl fmt::Display for String<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_str())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::string_slice::*;
+    use std::string;
 
     #[test]
     fn basic() {
@@ -157,6 +412,117 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn merge() {
+        let contents = [&["a", "b", "c"][..], &[], &["c", "d", "a", ""]];
+        let parts: Vec<Intern> = contents
+            .iter()
+            .map(|part| {
+                let mut intern = Intern::new();
+                for content in *part {
+                    intern.add(content);
+                }
+                intern
+            })
+            .collect();
+        let (mut merged, indexes) = Intern::merge(&parts);
+        assert_eq!(5, merged.iter().count());
+        for (part, indexes) in parts.iter().zip(&indexes) {
+            assert_eq!(part.iter().count(), indexes.len());
+            for (index, content) in part.iter() {
+                assert_eq!(content, merged.get(indexes[&index]).to_str());
+            }
+        }
+        // Equal strings are found in the merged table.
+        let len = merged.len();
+        for content in &["a", "b", "c", "d", ""] {
+            merged.add(content);
+        }
+        assert_eq!(len, merged.len());
+        assert_eq!(indexes[0][&0], indexes[2][&4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "NUL-terminated")]
+    fn over_unterminated() {
+        String::over(b"Hello!");
+    }
+
+    #[test]
+    fn reconstruct_from_bytes() {
+        let mut intern = Intern::new();
+        let hello = intern.add("Hello!");
+        let world = intern.add("World!");
+        let bytes: Vec<u8> = intern.into();
+
+        let mut intern = Intern::from(bytes.clone());
+        assert_eq!(hello, intern.add("Hello!"));
+        assert_eq!(world, intern.add("World!"));
+        assert_eq!(bytes.len(), intern.len());
+        let again = intern.add("Again!");
+        assert_eq!(bytes.len(), again);
+    }
+
+    #[test]
+    fn try_mark() {
+        let mut intern = Intern::new();
+        let hello = intern.add("Grüße");
+        assert_eq!(Some(hello + 1), intern.try_mark(hello + 1));
+        // Within the 'ü', past the end, and at the end.
+        assert_eq!(None, intern.try_mark(hello + 3));
+        assert_eq!(None, intern.try_mark(intern.len()));
+        assert_eq!(Some(intern.len() - 1), intern.try_mark(intern.len() - 1));
+    }
+
+    #[test]
+    fn many_strings() {
+        let mut intern = Intern::new();
+        let strings: Vec<string::String> = (0..10000).map(|n| format!("{}", n * 7)).collect();
+        let indexes: Vec<usize> = strings.iter().map(|s| intern.add(s)).collect();
+        for (s, index) in strings.iter().zip(&indexes) {
+            assert_eq!(*index, intern.add(s));
+            assert_eq!(s, intern.get(*index).to_str());
+        }
+        // Strings which start with other strings, or within them.
+        assert_ne!(intern.add("7"), intern.add("70"));
+        assert_eq!("1001", intern.get(indexes[143]).to_str());
+        assert_eq!(indexes[143] + 1, intern.mark(indexes[143] + 1));
+        assert_eq!(indexes[143] + 1, intern.add("001"));
+        assert_eq!(10001, intern.iter().count());
+
+        let len = intern.len();
+        let mut intern = Intern::from(Vec::from(intern));
+        for (s, index) in strings.iter().zip(&indexes) {
+            assert_eq!(*index, intern.add(s));
+        }
+        assert_eq!(len, intern.len());
+    }
+
+    #[test]
+    fn share_suffixes() {
+        let mut intern = Intern::new();
+        let hello = intern.add("Hello!");
+        let world = intern.add("World!");
+        let lo = intern.add("lo!");
+        let empty = intern.add("");
+        let oh_hello = intern.add("Oh, Hello!");
+        let (mut shared, indexes, count) = intern.share_suffixes();
+        assert_eq!(3, count);
+        // Strings which are stored on their own keep their order.
+        let bytes: Vec<u8> = shared.strings.clone();
+        assert_eq!(b"World!\0Oh, Hello!\0", &bytes[..]);
+        for index in &[hello, world, lo, empty, oh_hello] {
+            assert_eq!(intern.get(*index), shared.get(indexes[index]));
+        }
+        assert_eq!(indexes[&lo], shared.add("lo!"));
+
+        // Sharing again changes nothing.
+        let (again, _, count) = shared.share_suffixes();
+        assert_eq!(3, count);
+        assert_eq!(bytes, Vec::from(again));
+    }
+
     fn deduplicate_seen_strings() {
         let mut intern = Intern::new();
         let index = intern.add("Hello!");
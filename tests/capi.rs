@@ -0,0 +1,77 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for the C API, which run the C test in tests/capi/test.c.
+
+#![cfg(feature = "capi")]
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+
+// Makes sure that the library, which defines the C API, is linked in.
+use sequence_map as _;
+
+extern "C" {
+    fn seqmap_c_test(path: *const c_char) -> c_int;
+}
+
+#[test]
+fn c_test() {
+    let path = std::env::temp_dir().join(format!("sequence-map-capi-{}", std::process::id()));
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    let result = unsafe { seqmap_c_test(c_path.as_ptr()) };
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(0, result);
+}
+
+#[test]
+fn compressed_values() {
+    use sequence_map::capi::{seqmap_free, seqmap_from_bytes, seqmap_get, seqmap_get_copy};
+    use sequence_map::{Builder, Compression};
+    use std::ffi::CStr;
+
+    for compression in &[Compression::Symbols, Compression::Blocks] {
+        let mut builder = Builder::new(4);
+        builder.set_compression(*compression);
+        builder.insert(42, "Hello!");
+        builder.insert(84, "Hello!");
+        let bytes = builder.build();
+        unsafe {
+            let map = seqmap_from_bytes(bytes.as_ptr(), bytes.len());
+            assert!(!map.is_null());
+            let value = seqmap_get(map, 42);
+            assert_eq!("Hello!", CStr::from_ptr(value).to_str().unwrap());
+            // Decoded values are kept, and shared by the keys which map to
+            // them.
+            assert_eq!(value, seqmap_get(map, 42));
+            assert_eq!(value, seqmap_get(map, 84));
+            assert!(seqmap_get(map, 43).is_null());
+            let mut buf = [1 as c_char; 8];
+            assert_eq!(7, seqmap_get_copy(map, 84, buf.as_mut_ptr(), buf.len()));
+            assert_eq!("Hello!", CStr::from_ptr(buf.as_ptr()).to_str().unwrap());
+            seqmap_free(map);
+        }
+    }
+}
+
+#[test]
+fn header_is_up_to_date() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/sequence_map.h"));
+    let checked_in = include_str!("../include/sequence_map.h");
+    assert!(
+        generated == checked_in,
+        "include/sequence_map.h is out of date, copy it from {}",
+        env!("OUT_DIR")
+    );
+}
@@ -0,0 +1,87 @@
+/*
+ * Copyright 2020 Google LLC
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* Exercises the C API.  Called from tests/capi.rs. */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "sequence_map.h"
+
+#define CHECK(condition)                                                \
+  do {                                                                  \
+    if (!(condition)) {                                                 \
+      fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+              #condition);                                              \
+      return 1;                                                         \
+    }                                                                   \
+  } while (0)
+
+int seqmap_c_test(const char *path) {
+  SeqMapBuilder *builder = seqmap_builder_new(4);
+  CHECK(builder != NULL);
+  CHECK(seqmap_builder_insert(builder, 42, "Hello!"));
+  CHECK(seqmap_builder_insert(builder, 84, "World!"));
+  CHECK(seqmap_builder_insert(builder, 100, "Again!"));
+  CHECK(!seqmap_builder_insert(builder, 1, "\xff"));
+  CHECK(seqmap_builder_remove(builder, 100));
+  CHECK(!seqmap_builder_remove(builder, 100));
+  SeqMap *map = seqmap_builder_build(builder);
+  CHECK(map != NULL);
+  CHECK(strcmp(seqmap_get(map, 42), "Hello!") == 0);
+  CHECK(strcmp(seqmap_get(map, 84), "World!") == 0);
+  CHECK(seqmap_get(map, 100) == NULL);
+
+  /* Copy a value into a buffer, which must also hold the NUL. */
+  char buf[7];
+  CHECK(seqmap_get_copy(map, 42, NULL, 0) == 7);
+  CHECK(seqmap_get_copy(map, 42, buf, 6) == 7);
+  CHECK(seqmap_get_copy(map, 42, buf, sizeof(buf)) == 7);
+  CHECK(strcmp(buf, "Hello!") == 0);
+  CHECK(seqmap_get_copy(map, 100, buf, sizeof(buf)) == 0);
+
+  /* Write the map to a file, and read it back. */
+  size_t len = 0;
+  const uint8_t *bytes = seqmap_bytes(map, &len);
+  CHECK(len > 0);
+  FILE *file = fopen(path, "wb");
+  CHECK(file != NULL);
+  CHECK(fwrite(bytes, 1, len, file) == len);
+  CHECK(fclose(file) == 0);
+  SeqMap *opened = seqmap_open(path);
+  CHECK(opened != NULL);
+  CHECK(strcmp(seqmap_get(opened, 84), "World!") == 0);
+  seqmap_free(opened);
+
+  SeqMap *copy = seqmap_from_bytes(bytes, len);
+  CHECK(copy != NULL);
+  seqmap_free(map);
+  CHECK(strcmp(seqmap_get(copy, 42), "Hello!") == 0);
+  seqmap_free(copy);
+
+  /* Failures. */
+  CHECK(seqmap_builder_new(1) == NULL);
+  CHECK(seqmap_builder_new(17) == NULL);
+  const uint8_t not_a_map[64] = {0};
+  CHECK(seqmap_from_bytes(not_a_map, 0) == NULL);
+  CHECK(seqmap_from_bytes(not_a_map, sizeof(not_a_map)) == NULL);
+  CHECK(seqmap_open("/nonexistent/sequence-map") == NULL);
+  CHECK(seqmap_get(NULL, 42) == NULL);
+  CHECK(seqmap_get_copy(NULL, 42, NULL, 0) == 0);
+  seqmap_free(NULL);
+  seqmap_builder_free(seqmap_builder_new(2));
+  return 0;
+}